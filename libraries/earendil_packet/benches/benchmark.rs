@@ -2,7 +2,7 @@ use bytes::Bytes;
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use earendil_crypt::{AnonEndpoint, RelayFingerprint, RemoteId};
 use earendil_packet::{
-    crypt::DhSecret, ForwardInstruction, InnerPacket, Message, RawPacket, ReplyBlock,
+    crypt::DhSecret, DelayProfile, ForwardInstruction, InnerPacket, Message, RawPacket, ReplyBlock,
 };
 
 fn generate_forward_instructions(n: usize) -> Vec<(ForwardInstruction, DhSecret)> {
@@ -53,6 +53,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
                     &destination,
                     payload.clone(),
                     RemoteId::Anon(my_anon_id),
+                    DelayProfile::None,
                 ))
             });
         });
@@ -69,6 +70,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
                         &my_opk,
                         0,
                         my_anon_id,
+                        DelayProfile::None,
                     ))
                 });
             },
@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{fmt::Display, str::FromStr};
 
 use arrayref::array_ref;
 use base64::{engine::general_purpose, Engine as _};
@@ -7,6 +7,7 @@ use chacha20::{
     ChaCha20,
 };
 use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use rand::SeedableRng;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -45,6 +46,12 @@ impl DhPublic {
     }
 }
 
+impl Display for DhPublic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", general_purpose::STANDARD.encode(self.as_bytes()))
+    }
+}
+
 impl FromStr for DhPublic {
     type Err = base64::DecodeError;
 
@@ -62,7 +69,7 @@ impl FromStr for DhPublic {
 
 /// A diffie-hellman secret key, based on x25519.
 ///
-/// This is *intentionally* not serializable, and we *intentionally* never expose the underlying bytes representation. This is to ensure we only use them as in-memory ephemeral or mid-term keys.
+/// This is *intentionally* not serializable, and we *intentionally* never expose the underlying bytes representation. This is to ensure we only use them as in-memory ephemeral or mid-term keys. Mid-term keys that must survive a restart are instead re-derived from a persisted seed, using [DhSecret::from_seed].
 #[derive(Clone)]
 pub struct DhSecret(x25519_dalek::ReusableSecret);
 
@@ -74,6 +81,13 @@ impl DhSecret {
         ))
     }
 
+    /// Deterministically derives a secret key from a 32-byte random seed.
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        Self(x25519_dalek::ReusableSecret::random_from_rng(
+            rand_chacha::ChaCha20Rng::from_seed(*seed),
+        ))
+    }
+
     /// Returns the public key of this secret key.
     pub fn public(&self) -> DhPublic {
         DhPublic((&self.0).into())
@@ -181,6 +195,19 @@ mod tests {
         assert_eq!(public_key.as_bytes(), deserialized.as_bytes());
     }
 
+    #[test]
+    fn from_seed_deterministic() {
+        let seed = [7u8; 32];
+        assert_eq!(
+            DhSecret::from_seed(&seed).public(),
+            DhSecret::from_seed(&seed).public()
+        );
+        assert_ne!(
+            DhSecret::from_seed(&seed).public(),
+            DhSecret::from_seed(&[8u8; 32]).public()
+        );
+    }
+
     #[test]
     fn shared_secret() {
         let alice_secret_key = DhSecret::generate();
@@ -57,6 +57,7 @@ mod tests {
             InnerPacket::Message(msg.clone()),
             &[0; 32],
             RemoteId::Relay(my_isk.public().fingerprint()),
+            DelayProfile::None,
         )?;
 
         let mut peeled_packet = packet;
@@ -120,6 +121,43 @@ mod tests {
         }
     }
 
+    #[test]
+    fn delay_profile_sets_hop_delays() {
+        let route = generate_forward_instructions(3);
+        let instructs: Vec<ForwardInstruction> = route.iter().map(|(inst, _)| *inst).collect();
+        let hop_delays = |delay: DelayProfile| -> Vec<u16> {
+            let mut pkt = RawPacket::new_normal(
+                &instructs,
+                &DhSecret::generate().public(),
+                InnerPacket::Message(Message {
+                    relay_dock: 0u32,
+                    body: Bytes::new(),
+                }),
+                RemoteId::Anon(AnonEndpoint::random()),
+                delay,
+            )
+            .unwrap();
+            route
+                .iter()
+                .map(|(_, our_sk)| match pkt.peel(our_sk) {
+                    Ok(PeeledPacket::Relay {
+                        pkt: next,
+                        delay_ms,
+                        ..
+                    }) => {
+                        pkt = next;
+                        delay_ms
+                    }
+                    e => panic!("Expected forward packet, got {:?}", e),
+                })
+                .collect()
+        };
+
+        assert_eq!(hop_delays(DelayProfile::None), vec![0, 0, 0]);
+        let delays = hop_delays(DelayProfile::Exponential { mean_ms: 10_000 });
+        assert!(delays.iter().any(|delay| *delay > 0));
+    }
+
     #[test]
     fn reply_block_five_hops() {
         use crate::reply_block::ReplyBlock;
@@ -140,9 +178,15 @@ mod tests {
         let first_peeler = RelayFingerprint::from_bytes(&[10; 32]);
 
         // Prepare reply block
-        let (reply_block, (_, reply_degarbler)) =
-            ReplyBlock::new(&route, first_peeler, &alice_opk, 0, alice_anon_id)
-                .expect("Failed to create reply block");
+        let (reply_block, (_, reply_degarbler)) = ReplyBlock::new(
+            &route,
+            first_peeler,
+            &alice_opk,
+            0,
+            alice_anon_id,
+            DelayProfile::None,
+        )
+        .expect("Failed to create reply block");
 
         // Prepare message using header from reply block
         let body = "hello world from reply block!";
@@ -15,8 +15,8 @@ use crate::{
 };
 
 pub const RAW_BODY_SIZE: usize = 20000;
-const MAX_HOPS: usize = 10;
-const LOW_LATENCY_MS: u16 = 15;
+/// The most relays, including the destination, that a packet can be routed through.
+pub const MAX_HOPS: usize = 10;
 const METADATA_BUFFER_SIZE: usize = 35;
 const FORWARD_TO_CLIENT_FLAG: u8 = 2;
 const FORWARD_TO_RELAY_FLAG: u8 = 1;
@@ -63,20 +63,40 @@ pub enum PacketPeelError {
     InnerPacketOpenError,
 }
 
-fn sample_delay(avg: u16) -> u16 {
-    let exp = Exp::new(1.0 / avg as f64).expect("avg must be greater than zero");
-    let mut rng = rand::thread_rng();
-    rng.sample(exp) as u16
+/// How long every relay along a route holds a packet before sending it on. Longer delays mix each packet with more of the other traffic going through a relay, at the cost of latency.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DelayProfile {
+    /// Relays send the packet on right away.
+    None,
+    /// Every hop's delay is drawn independently from an exponential distribution with this mean.
+    Exponential { mean_ms: u16 },
+}
+
+impl DelayProfile {
+    /// Draws the delay for one hop, in milliseconds.
+    pub fn sample(&self) -> u16 {
+        match self {
+            DelayProfile::None => 0,
+            DelayProfile::Exponential { mean_ms: 0 } => 0,
+            DelayProfile::Exponential { mean_ms } => {
+                let exp = Exp::new(1.0 / *mean_ms as f64).expect("mean must be greater than zero");
+                // float-to-int casts saturate, so extremely long delays become u16::MAX
+                rand::thread_rng().sample(exp) as u16
+            }
+        }
+    }
 }
 
 impl RawPacket {
+    /// Creates a RawPacket for a message to a relay, where every hop delays the packet according to `delay`.
     pub fn new_normal(
         route: &[ForwardInstruction],
         dest_opk: &DhPublic,
         payload: InnerPacket,
         my_id: RemoteId,
+        delay: DelayProfile,
     ) -> Result<Self, PacketConstructError> {
-        let (raw, _) = Self::new(route, dest_opk, false, payload, &[0; 32], my_id)?;
+        let (raw, _) = Self::new(route, dest_opk, false, payload, &[0; 32], my_id, delay)?;
         Ok(raw)
     }
 
@@ -106,12 +126,13 @@ impl RawPacket {
         payload: InnerPacket,
         metadata: &[u8; 32],
         my_id: RemoteId,
+        delay_profile: DelayProfile,
     ) -> Result<(Self, Vec<[u8; 32]>), PacketConstructError> {
         if route.len() >= MAX_HOPS {
             return Err(PacketConstructError::TooManyHops);
         }
 
-        let delay = sample_delay(LOW_LATENCY_MS);
+        let delay = delay_profile.sample();
 
         // Use a recursive algorithm. Base case: the route is empty
         if route.is_empty() {
@@ -177,6 +198,7 @@ impl RawPacket {
                 payload,
                 metadata,
                 my_id,
+                delay_profile,
             )?;
 
             buffer[33..].copy_from_slice(&delay.to_be_bytes());
@@ -6,7 +6,8 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     crypt::{stream_dencrypt, DhPublic},
-    ForwardInstruction, InnerPacket, Message, PacketConstructError, RawBody, RawHeader, RawPacket,
+    DelayProfile, ForwardInstruction, InnerPacket, Message, PacketConstructError, RawBody,
+    RawHeader, RawPacket,
 };
 
 /// A reply block. Reply blocks are constructed by endpoints who wish other endpoints to talk to them via an anonymous address, and are single-use, consumed when used to construct a packet going to that anonymous address.
@@ -18,12 +19,14 @@ pub struct ReplyBlock {
 }
 
 impl ReplyBlock {
+    /// Creates a reply block, along with the degarbler for replies sent with it. The delays that every hop applies to those replies are fixed here, according to `delay`.
     pub fn new(
         route: &[ForwardInstruction],
         first_peeler: RelayFingerprint,
         dest_opk: &DhPublic,
         my_client_id: ClientId,
         my_anon_id: AnonEndpoint,
+        delay: DelayProfile,
     ) -> Result<(Self, (u64, ReplyDegarbler)), PacketConstructError> {
         let rb_id: u64 = rand::random();
         let mut metadata = [0; 32];
@@ -41,6 +44,7 @@ impl ReplyBlock {
             }),
             &metadata,
             RemoteId::Anon(my_anon_id),
+            delay,
         )?;
         let header = raw_packet.header;
         let stream_key = rand::thread_rng().gen();
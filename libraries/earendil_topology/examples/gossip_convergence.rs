@@ -0,0 +1,262 @@
+//! Simulates relay graph gossip over a topology in the format of `shadow-tests/gen-earendil-shadow/100-nodes.yaml`, reporting how many seconds every relay takes to learn the whole graph and how much gossip it takes, both with graph digests and with the random sampling that gossip used before them.
+//!
+//! Like the daemon, every relay gossips with each of its neighbors once a second, moves at most 100 adjacencies per round, and learns at most 60 new relays a minute unless told otherwise. Digest gossip backs off up to 16 seconds while a neighbor has nothing new. Network latency is ignored.
+//!
+//! ```text
+//! cargo run --release -p earendil_topology --example gossip_convergence -- shadow-tests/gen-earendil-shadow/100-nodes.yaml [NEW_RELAYS_PER_MINUTE]
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bytes::Bytes;
+use earendil_crypt::{RelayFingerprint, RelayIdentitySecret};
+use earendil_packet::crypt::DhSecret;
+use earendil_topology::{AdjacencyDescriptor, IdentityDescriptor, RelayGraph};
+use rand::seq::IteratorRandom;
+use stdcode::StdcodeSerializeExt;
+
+const QUOTA: usize = 100;
+const SAMPLE_SIZE: usize = 10;
+const MAX_BACKOFF: u64 = 16;
+const AFTERMATH: u64 = 60;
+
+struct Relay {
+    sk: RelayIdentitySecret,
+    graph: RelayGraph,
+    neighbors: Vec<usize>,
+    /// the minute we're in and how many new relays we learned in it
+    budget: (u64, usize),
+    new_relays_per_minute: usize,
+    /// when we next gossip with each neighbor, and the current backoff
+    next_gossip: Vec<(u64, u64)>,
+}
+
+impl Relay {
+    fn take_budget(&mut self, now: u64) -> bool {
+        if self.budget.0 != now / 60 {
+            self.budget = (now / 60, 0);
+        }
+        if self.budget.1 >= self.new_relays_per_minute {
+            return false;
+        }
+        self.budget.1 += 1;
+        true
+    }
+
+    fn learn_identity(&mut self, identity: IdentityDescriptor, now: u64) {
+        let is_new = self
+            .graph
+            .identity(&identity.identity_pk.fingerprint())
+            .is_none();
+        if !is_new || self.take_budget(now) {
+            let _ = self.graph.insert_identity(identity);
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or("shadow-tests/gen-earendil-shadow/100-nodes.yaml".into());
+    let links = read_topology(&std::fs::read_to_string(path)?);
+    let new_relays_per_minute = match std::env::args().nth(2) {
+        Some(arg) => arg.parse()?,
+        None => 60,
+    };
+
+    for digests in [false, true] {
+        let mode = if digests { "digests" } else { "sampling" };
+        let mut relays = build(&links, new_relays_per_minute);
+        let mut bytes = 0;
+        let mut converged: Option<(u64, usize)> = None;
+        for now in 0..3600 {
+            if converged.is_some_and(|(at, _)| now >= at + AFTERMATH) {
+                break;
+            }
+            for i in 0..relays.len() {
+                for slot in 0..relays[i].neighbors.len() {
+                    if relays[i].next_gossip[slot].0 > now {
+                        continue;
+                    }
+                    let j = relays[i].neighbors[slot];
+                    let backoff = if digests {
+                        gossip_digest(&mut relays, i, slot, now, &mut bytes)
+                    } else {
+                        gossip_sample(&mut relays, i, j, now, &mut bytes);
+                        1
+                    };
+                    relays[i].next_gossip[slot] = (now + backoff, backoff);
+                }
+            }
+            if converged.is_none()
+                && relays.iter().all(|relay| {
+                    relay.graph.all_nodes().count() == relays.len()
+                        && relay.graph.all_adjacencies().count() == links.len()
+                })
+            {
+                println!(
+                    "{mode}: all {} relays learned all {} adjacencies after {} s, gossiping {} KiB on the way",
+                    relays.len(),
+                    links.len(),
+                    now + 1,
+                    bytes / 1024
+                );
+                converged = Some((now + 1, bytes));
+            }
+        }
+        match converged {
+            Some((_, at_convergence)) => println!(
+                "{mode}: {} KiB/s of gossip across the network once converged",
+                (bytes - at_convergence) / AFTERMATH as usize / 1024
+            ),
+            None => println!("{mode}: did not converge within an hour"),
+        }
+    }
+    Ok(())
+}
+
+/// Relay `i` gossips with its neighbor in `slot` the way the daemon does now, returning how long to wait until the next round.
+fn gossip_digest(relays: &mut [Relay], i: usize, slot: usize, now: u64, bytes: &mut usize) -> u64 {
+    let j = relays[i].neighbors[slot];
+    let backoff = relays[i].next_gossip[slot].1;
+    let digest = relays[i].graph.digest();
+    *bytes += 16;
+    if digest.summary() == relays[j].graph.digest().summary() {
+        return 1;
+    }
+    let delta = relays[j].graph.delta(&digest, QUOTA);
+    *bytes += digest.stdcode().len() + delta.stdcode().len();
+    for identity in delta.identities {
+        relays[i].learn_identity(identity, now);
+    }
+    for adjacency in delta.adjacencies {
+        let _ = relays[i].graph.insert_adjacency(adjacency);
+    }
+    if relays[i].graph.digest().summary() == digest.summary() {
+        (backoff * 2).min(MAX_BACKOFF)
+    } else {
+        1
+    }
+}
+
+/// Relay `i` gossips with its neighbor `j` the way the daemon did before digests, asking for the adjacencies of random relays it knows.
+fn gossip_sample(relays: &mut [Relay], i: usize, j: usize, now: u64, bytes: &mut usize) {
+    let sample: Vec<RelayFingerprint> = relays[i]
+        .graph
+        .all_nodes()
+        .choose_multiple(&mut rand::thread_rng(), SAMPLE_SIZE);
+    *bytes += sample.stdcode().len();
+    let adjacencies: Vec<AdjacencyDescriptor> = sample
+        .iter()
+        .flat_map(|fp| relays[j].graph.adjacencies(fp).into_iter().flatten())
+        .take(QUOTA)
+        .collect();
+    *bytes += adjacencies.stdcode().len();
+    for adjacency in adjacencies {
+        // the daemon caches identities, so it only fetches the ones it doesn't know
+        for fp in [adjacency.left, adjacency.right] {
+            if relays[i].graph.identity(&fp).is_some() {
+                continue;
+            }
+            if let Some(identity) = relays[j].graph.identity(&fp) {
+                *bytes += identity.stdcode().len();
+                relays[i].learn_identity(identity, now);
+            }
+        }
+        // inserting what we already have changes nothing, so skip verifying it again
+        let known = relays[i]
+            .graph
+            .adjacencies(&adjacency.left)
+            .into_iter()
+            .flatten()
+            .any(|adj| adj == adjacency);
+        if !known {
+            let _ = relays[i].graph.insert_adjacency(adjacency);
+        }
+    }
+}
+
+fn read_topology(yaml: &str) -> BTreeSet<(String, String)> {
+    yaml.lines()
+        .filter_map(|line| {
+            let (a, b) = line
+                .trim()
+                .strip_prefix("- [")?
+                .strip_suffix(']')?
+                .split_once(',')?;
+            let (a, b) = (a.trim().to_string(), b.trim().to_string());
+            // relays don't link to themselves
+            (a != b).then(|| if a < b { (a, b) } else { (b, a) })
+        })
+        .collect()
+}
+
+/// Sets up the relays the way they are right after linking up with their neighbors, which gives them each other's identities and adjacencies.
+fn build(links: &BTreeSet<(String, String)>, new_relays_per_minute: usize) -> Vec<Relay> {
+    let names: BTreeMap<&str, usize> = links
+        .iter()
+        .flat_map(|(a, b)| [a.as_str(), b.as_str()])
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| (name, i))
+        .collect();
+    let mut relays: Vec<Relay> = names
+        .iter()
+        .map(|_| {
+            let sk = RelayIdentitySecret::generate();
+            let mut graph = RelayGraph::new();
+            graph
+                .insert_identity(IdentityDescriptor::new(
+                    &sk,
+                    &DhSecret::generate(),
+                    u64::MAX,
+                ))
+                .unwrap();
+            Relay {
+                sk,
+                graph,
+                neighbors: vec![],
+                budget: (0, 0),
+                new_relays_per_minute,
+                next_gossip: vec![],
+            }
+        })
+        .collect();
+    for (a, b) in links {
+        let (a, b) = (names[a.as_str()], names[b.as_str()]);
+        relays[a].neighbors.push(b);
+        relays[a].next_gossip.push((0, 1));
+        relays[b].neighbors.push(a);
+        relays[b].next_gossip.push((0, 1));
+    }
+    for (a, b) in links {
+        let (a, b) = (names[a.as_str()], names[b.as_str()]);
+        let (mut left, mut right) = (&relays[a].sk, &relays[b].sk);
+        if left.public().fingerprint() > right.public().fingerprint() {
+            std::mem::swap(&mut left, &mut right);
+        }
+        let mut adj = AdjacencyDescriptor {
+            left: left.public().fingerprint(),
+            right: right.public().fingerprint(),
+            left_sig: Bytes::new(),
+            right_sig: Bytes::new(),
+            unix_timestamp: relays[a]
+                .graph
+                .identity(&relays[a].sk.public().fingerprint())
+                .unwrap()
+                .unix_timestamp,
+        };
+        adj.left_sig = left.sign(adj.to_sign().as_bytes());
+        adj.right_sig = right.sign(adj.to_sign().as_bytes());
+        for (me, them) in [(a, b), (b, a)] {
+            let identity = relays[them]
+                .graph
+                .identity(&relays[them].sk.public().fingerprint())
+                .unwrap();
+            relays[me].graph.insert_identity(identity).unwrap();
+            relays[me].graph.insert_adjacency(adj.clone()).unwrap();
+        }
+    }
+    relays
+}
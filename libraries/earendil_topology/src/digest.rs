@@ -0,0 +1,155 @@
+use std::collections::{HashMap, HashSet};
+
+use earendil_crypt::RelayFingerprint;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::{AdjacencyDescriptor, IdentityDescriptor, RelayGraph};
+
+/// A compact summary of what a relay graph knows about each relay, so that a peer can send over only what's missing instead of the whole graph.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GraphDigest {
+    pub nodes: Vec<NodeDigest>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NodeDigest {
+    pub fp: RelayFingerprint,
+    /// The timestamp of the relay's identity descriptor, or 0 if there is none.
+    pub identity_version: u64,
+    /// The timestamps of the adjacencies that the relay is the left end of, by their right ends in order. Every adjacency is only listed once this way.
+    pub adjacencies: Vec<(RelayFingerprint, u64)>,
+}
+
+/// The descriptors that a peer found us to be missing, or to have older versions of.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GraphDelta {
+    pub identities: Vec<IdentityDescriptor>,
+    pub adjacencies: Vec<AdjacencyDescriptor>,
+}
+
+impl GraphDigest {
+    /// Hashes the whole digest into one number, so that peers can cheaply tell whether their graphs already agree.
+    pub fn summary(&self) -> u64 {
+        let mut hasher = blake3::Hasher::new();
+        for node in self.nodes.iter() {
+            hasher.update(node.fp.as_bytes());
+            hasher.update(&node.identity_version.to_le_bytes());
+            for (right, version) in node.adjacencies.iter() {
+                hasher.update(right.as_bytes());
+                hasher.update(&version.to_le_bytes());
+            }
+        }
+        truncate_hash(hasher.finalize())
+    }
+}
+
+impl RelayGraph {
+    /// Summarizes the graph for [RelayGraph::delta] on a peer. Nodes are sorted, so that equal graphs give equal digests.
+    pub fn digest(&self) -> GraphDigest {
+        let mut nodes = self
+            .id_to_fp
+            .iter()
+            .map(|(id, fp)| self.node_digest(*id, *fp))
+            .collect::<Vec<_>>();
+        nodes.sort_unstable_by_key(|node| node.fp);
+        GraphDigest { nodes }
+    }
+
+    /// Returns what whoever summarized their graph as `digest` is missing or has older versions of, at most `limit` adjacencies and `limit` identities. Descriptors that the peer has the same or newer versions of are never sent. They are picked in random order, so that repeated calls eventually cover all of them.
+    pub fn delta(&self, digest: &GraphDigest, limit: usize) -> GraphDelta {
+        let their_identities: HashMap<RelayFingerprint, u64> = digest
+            .nodes
+            .iter()
+            .map(|node| (node.fp, node.identity_version))
+            .collect();
+        let their_adjacencies: HashMap<(RelayFingerprint, RelayFingerprint), u64> = digest
+            .nodes
+            .iter()
+            .flat_map(|node| {
+                node.adjacencies
+                    .iter()
+                    .map(|(right, version)| ((node.fp, *right), *version))
+            })
+            .collect();
+        let they_lack = |descr: &IdentityDescriptor| {
+            their_identities
+                .get(&descr.identity_pk.fingerprint())
+                .is_none_or(|version| *version < descr.unix_timestamp)
+        };
+
+        let mut newer_adjacencies = self
+            .documents
+            .values()
+            .filter(|adj| {
+                their_adjacencies
+                    .get(&(adj.left, adj.right))
+                    .is_none_or(|version| *version < adj.unix_timestamp)
+            })
+            .collect::<Vec<_>>();
+        newer_adjacencies.shuffle(&mut rand::thread_rng());
+        let mut newer_identities = self
+            .id_to_descriptor
+            .values()
+            .filter(|descr| they_lack(descr))
+            .collect::<Vec<_>>();
+        newer_identities.shuffle(&mut rand::thread_rng());
+
+        let mut sent_identities = HashSet::new();
+        let mut delta = GraphDelta::default();
+        for adj in newer_adjacencies {
+            if delta.adjacencies.len() >= limit {
+                break;
+            }
+            // the adjacency is useless without the identities at its ends
+            let missing = [adj.left, adj.right]
+                .into_iter()
+                .filter(|fp| !sent_identities.contains(fp))
+                .filter_map(|fp| self.identity(&fp))
+                .filter(|descr| they_lack(descr))
+                .collect::<Vec<_>>();
+            if delta.identities.len() + missing.len() > limit {
+                continue;
+            }
+            delta.adjacencies.push(adj.clone());
+            for descr in missing {
+                sent_identities.insert(descr.identity_pk.fingerprint());
+                delta.identities.push(descr);
+            }
+        }
+        for descr in newer_identities {
+            if delta.identities.len() >= limit {
+                break;
+            }
+            if sent_identities.insert(descr.identity_pk.fingerprint()) {
+                delta.identities.push(descr.clone());
+            }
+        }
+        delta
+    }
+
+    fn node_digest(&self, id: u64, fp: RelayFingerprint) -> NodeDigest {
+        let identity_version = self
+            .id_to_descriptor
+            .get(&id)
+            .map_or(0, |descr| descr.unix_timestamp);
+        let mut adjacencies = self
+            .adjacency
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .filter_map(|neigh_id| self.documents.get(&(id, *neigh_id)))
+            .map(|adj| (adj.right, adj.unix_timestamp))
+            .collect::<Vec<_>>();
+        adjacencies.sort_unstable();
+        NodeDigest {
+            fp,
+            identity_version,
+            adjacencies,
+        }
+    }
+}
+
+fn truncate_hash(hash: blake3::Hash) -> u64 {
+    u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+}
@@ -1,16 +1,28 @@
+mod digest;
+mod prune;
+mod snapshot;
+
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    sync::Mutex,
     time::{SystemTime, UNIX_EPOCH},
 };
 
 use bytes::Bytes;
-use earendil_crypt::{RelayFingerprint, RelayIdentityPublic, RelayIdentitySecret, VerifyError};
+use earendil_crypt::{
+    NeighborId, RelayFingerprint, RelayIdentityPublic, RelayIdentitySecret, VerifyError,
+};
 use earendil_packet::crypt::{DhPublic, DhSecret};
 use indexmap::IndexMap;
 use rand::{seq::IteratorRandom, Rng};
 use serde::{Deserialize, Serialize};
 use stdcode::StdcodeSerializeExt;
 
+pub use digest::{GraphDelta, GraphDigest, NodeDigest};
+pub use prune::GraphLimits;
+pub use snapshot::{GraphSnapshot, SnapshotError, SnapshotImport, SNAPSHOT_VERSION};
+
 /// A full, indexed representation of the Earendil relay graph. Includes info about:
 /// - Which fingerprints are adjacent to which fingerprints
 /// - What signing keys and midterm keys do each fingerprint have
@@ -22,6 +34,38 @@ pub struct RelayGraph {
     id_to_descriptor: HashMap<u64, IdentityDescriptor>,
     adjacency: HashMap<u64, HashSet<u64>>,
     documents: IndexMap<(u64, u64), AdjacencyDescriptor>,
+
+    /// Locally measured link weights, keyed by the smaller ID first. Links missing from here weigh [DEFAULT_LINK_WEIGHT].
+    #[serde(skip)]
+    link_weights: HashMap<(u64, u64), u64>,
+    /// Cache of [RelayGraph::next_hops], thrown away whenever links or their weights change.
+    #[serde(skip)]
+    routing_table: Mutex<Option<RoutingTable>>,
+
+    #[serde(skip)]
+    limits: GraphLimits,
+    /// Relays that pruning never evicts, and that decide which other relays are worth keeping.
+    #[serde(skip)]
+    anchors: Vec<RelayFingerprint>,
+    /// The neighbor that first told us about each relay learned through gossip. This isn't persisted, so relays loaded from the state cache count against no neighbor's share.
+    #[serde(skip)]
+    learned_via: HashMap<u64, NeighborId>,
+    /// How many of the relays in `learned_via` each neighbor told us about.
+    #[serde(skip)]
+    learned_counts: HashMap<NeighborId, usize>,
+}
+
+/// The weight of a link with nothing known about it. Weights are in arbitrary units, so measurements like RTTs or prices have to be scaled relative to this.
+pub const DEFAULT_LINK_WEIGHT: u64 = 1000;
+
+fn link_key(a: u64, b: u64) -> (u64, u64) {
+    (a.min(b), a.max(b))
+}
+
+/// For every destination, the starting points on its cheapest paths.
+struct RoutingTable {
+    sources: Vec<(RelayFingerprint, u64)>,
+    next_hops: HashMap<u64, Vec<RelayFingerprint>>,
 }
 
 // Update the AdjacencyError enum with more specific cases
@@ -38,6 +82,29 @@ pub enum AdjacencyError {
 
     #[error("Invalid signature(s) in the adjacency descriptor")]
     InvalidSignatures,
+
+    #[error("Adjacency descriptor is timestamped in the future")]
+    FromTheFuture,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum IdentityError {
+    #[error("Invalid signature in the identity descriptor")]
+    InvalidSignature(#[from] VerifyError),
+
+    #[error("Identity descriptor is timestamped in the future")]
+    FromTheFuture,
+}
+
+/// How far in the future descriptors may be timestamped, in seconds, to allow for clocks that are a bit off. Descriptors from further in the future would never expire in time.
+pub const MAX_CLOCK_SKEW: u64 = 120;
+
+fn is_from_the_future(unix_timestamp: u64) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    unix_timestamp > now + MAX_CLOCK_SKEW
 }
 
 impl RelayGraph {
@@ -58,8 +125,8 @@ impl RelayGraph {
         self.id_to_descriptor.get(&id).cloned()
     }
 
-    /// Inserts an identity descriptor. Verifies its self-consistency.
-    pub fn insert_identity(&mut self, identity: IdentityDescriptor) -> Result<(), VerifyError> {
+    /// Inserts an identity descriptor. Verifies its self-consistency and its timestamp. Descriptors that have already expired are ignored.
+    pub fn insert_identity(&mut self, identity: IdentityDescriptor) -> Result<(), IdentityError> {
         tracing::trace!(
             identity = debug(identity.identity_pk.fingerprint()),
             "inserting an identity into relay graph"
@@ -68,30 +135,60 @@ impl RelayGraph {
         identity
             .identity_pk
             .verify(identity.to_sign().as_bytes(), &identity.sig)?;
+        if is_from_the_future(identity.unix_timestamp) {
+            return Err(IdentityError::FromTheFuture);
+        }
+        if self.is_expired(identity.unix_timestamp) {
+            tracing::trace!("ignoring an expired identity");
+            return Ok(());
+        }
         let id = self.alloc_id(&identity.identity_pk.fingerprint());
-        self.id_to_descriptor.insert(id, identity);
+        // never replace a descriptor with an older one, which might carry a rotated-out onion key
+        if let Some(existing) = self.id_to_descriptor.get(&id) {
+            if existing.unix_timestamp > identity.unix_timestamp {
+                return Ok(());
+            }
+        }
+        if self.id_to_descriptor.insert(id, identity).is_none()
+            && self.id_to_descriptor.len() > self.limits.max_relays
+        {
+            self.evict_relays();
+        }
         Ok(())
     }
 
-    /// Inserts an adjacency descriptor. Verifies the descriptor and returns false if it's not valid.
-    /// Returns true if the descriptor was inserted successfully.
+    /// Inserts an adjacency descriptor. Verifies the descriptor and returns an error if it's not valid. Descriptors that have already expired are ignored.
     pub fn insert_adjacency(
         &mut self,
         adjacency: AdjacencyDescriptor,
     ) -> Result<(), AdjacencyError> {
         self.verify_adjacency(&adjacency)?;
+        if self.is_expired(adjacency.unix_timestamp) {
+            tracing::trace!("ignoring an expired adjacency");
+            return Ok(());
+        }
 
         let left_fp = &adjacency.left;
         let right_fp = &adjacency.right;
         let left_id = self.alloc_id(left_fp);
         let right_id = self.alloc_id(right_fp);
 
+        // never replace a descriptor with an older one, which gossip can bring in from a peer that hasn't caught up yet
+        if let Some(existing) = self.documents.get(&(left_id, right_id)) {
+            if existing.unix_timestamp > adjacency.unix_timestamp {
+                return Ok(());
+            }
+        }
         self.documents.insert((left_id, right_id), adjacency);
 
-        self.adjacency.entry(left_id).or_default().insert(right_id);
+        let new_link = self.adjacency.entry(left_id).or_default().insert(right_id);
         self.adjacency.entry(right_id).or_default().insert(left_id);
-
-        self.cleanup();
+        if new_link {
+            self.invalidate_routes();
+            if self.documents.len() > self.limits.max_adjacencies {
+                self.evict_adjacencies();
+            }
+        }
         Ok(())
     }
 
@@ -210,60 +307,144 @@ impl RelayGraph {
         None
     }
 
-    // removes all information more than ROUTE_TIMEOUT ago
-    fn cleanup(&mut self) {
-        const ROUTE_TIMEOUT: u64 = 60 * 60; // e.g., 1 hour in seconds
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
-
-        let outdated_identities: HashSet<u64> = self
-            .id_to_descriptor
-            .iter()
-            .filter_map(|(&id, descriptor)| {
-                if now.saturating_sub(descriptor.unix_timestamp) > ROUTE_TIMEOUT {
-                    Some(id)
-                } else {
-                    None
-                }
-            })
-            .collect();
+    /// Returns how many hops away every fingerprint reachable from any of the given starting points is. Starting points that aren't in the graph are ignored.
+    pub fn distances_from(
+        &self,
+        start_fps: &[RelayFingerprint],
+    ) -> HashMap<RelayFingerprint, usize> {
+        let mut distances = HashMap::new();
+        let mut queue = VecDeque::new();
+        for id in start_fps.iter().filter_map(|fp| self.id(fp)) {
+            if distances.insert(id, 0).is_none() {
+                queue.push_back(id);
+            }
+        }
 
-        for &id in &outdated_identities {
-            self.id_to_descriptor.remove(&id);
-            if let Some(fp) = self.id_to_fp.remove(&id) {
-                self.fp_to_id.remove(&fp);
+        while let Some(current_id) = queue.pop_front() {
+            let next_distance = distances[&current_id] + 1;
+            if let Some(neighbors) = self.adjacency.get(&current_id) {
+                for neighbor_id in neighbors.iter() {
+                    if !distances.contains_key(neighbor_id) {
+                        distances.insert(*neighbor_id, next_distance);
+                        queue.push_back(*neighbor_id);
+                    }
+                }
             }
         }
 
-        let outdated_documents: Vec<(u64, u64)> = self
-            .documents
-            .iter()
-            .filter_map(|(&(left_id, right_id), descriptor)| {
-                if outdated_identities.contains(&left_id)
-                    || outdated_identities.contains(&right_id)
-                    || now.saturating_sub(descriptor.unix_timestamp) > ROUTE_TIMEOUT
-                {
-                    Some((left_id, right_id))
-                } else {
-                    None
+        distances
+            .into_iter()
+            .filter_map(|(id, distance)| Some((*self.id_to_fp.get(&id)?, distance)))
+            .collect()
+    }
+
+    /// Sets the weight of the link between two relays, for example from its measured RTT or price. Does nothing if either relay isn't in the graph.
+    pub fn set_link_weight(&mut self, a: &RelayFingerprint, b: &RelayFingerprint, weight: u64) {
+        let (Some(a_id), Some(b_id)) = (self.id(a), self.id(b)) else {
+            return;
+        };
+        if self.link_weights.insert(link_key(a_id, b_id), weight) != Some(weight) {
+            self.invalidate_routes();
+        }
+    }
+
+    /// The weight of the link between two relays, which is [DEFAULT_LINK_WEIGHT] unless it was set with [RelayGraph::set_link_weight].
+    pub fn link_weight(&self, a: &RelayFingerprint, b: &RelayFingerprint) -> u64 {
+        self.id(a)
+            .zip(self.id(b))
+            .and_then(|(a_id, b_id)| self.link_weights.get(&link_key(a_id, b_id)))
+            .copied()
+            .unwrap_or(DEFAULT_LINK_WEIGHT)
+    }
+
+    /// Returns the starting points from which `dest` is cheapest to reach, where each starting point comes with the cost of getting to it in the first place. There can be several of them when they tie, so that traffic can be spread across them. Returns an empty list if `dest` can't be reached at all.
+    ///
+    /// The answers for all destinations are computed at once and cached, until the links, their weights, or the starting points change.
+    pub fn next_hops(
+        &self,
+        sources: &[(RelayFingerprint, u64)],
+        dest: &RelayFingerprint,
+    ) -> Vec<RelayFingerprint> {
+        let Some(dest_id) = self.id(dest) else {
+            return vec![];
+        };
+        let mut sources = sources.to_vec();
+        sources.sort_unstable();
+
+        let mut routing_table = self.routing_table.lock().unwrap();
+        if routing_table
+            .as_ref()
+            .is_none_or(|table| table.sources != sources)
+        {
+            *routing_table = Some(self.build_routing_table(sources));
+        }
+        routing_table
+            .as_ref()
+            .and_then(|table| table.next_hops.get(&dest_id).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Runs Dijkstra's algorithm from all starting points at once, keeping track of which starting points every node's cheapest paths go through.
+    fn build_routing_table(&self, sources: Vec<(RelayFingerprint, u64)>) -> RoutingTable {
+        let mut costs: HashMap<u64, u64> = HashMap::new();
+        let mut next_hops: HashMap<u64, Vec<RelayFingerprint>> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        for (fp, cost) in sources.iter() {
+            let Some(id) = self.id(fp) else {
+                continue;
+            };
+            match costs.get(&id) {
+                Some(existing) if existing < cost => continue,
+                Some(existing) if existing == cost => {}
+                _ => {
+                    next_hops.remove(&id);
                 }
-            })
-            .collect();
+            }
+            costs.insert(id, *cost);
+            next_hops.entry(id).or_default().push(*fp);
+            queue.push(Reverse((*cost, id)));
+        }
 
-        for (left_id, right_id) in outdated_documents {
-            self.documents.remove(&(left_id, right_id));
-            if let Some(neighbors) = self.adjacency.get_mut(&left_id) {
-                neighbors.remove(&right_id);
+        while let Some(Reverse((cost, id))) = queue.pop() {
+            if costs[&id] < cost {
+                // stale queue entry
+                continue;
             }
-            if let Some(neighbors) = self.adjacency.get_mut(&right_id) {
-                neighbors.remove(&left_id);
+            let Some(neighbors) = self.adjacency.get(&id) else {
+                continue;
+            };
+            let hops = next_hops[&id].clone();
+            for &neigh_id in neighbors {
+                let neigh_cost = cost
+                    + self
+                        .link_weights
+                        .get(&link_key(id, neigh_id))
+                        .copied()
+                        .unwrap_or(DEFAULT_LINK_WEIGHT);
+                match costs.get(&neigh_id) {
+                    Some(&existing) if existing < neigh_cost => {}
+                    Some(&existing) if existing == neigh_cost => {
+                        let neigh_hops = next_hops.entry(neigh_id).or_default();
+                        for hop in hops.iter() {
+                            if !neigh_hops.contains(hop) {
+                                neigh_hops.push(*hop);
+                            }
+                        }
+                    }
+                    _ => {
+                        costs.insert(neigh_id, neigh_cost);
+                        next_hops.insert(neigh_id, hops.clone());
+                        queue.push(Reverse((neigh_cost, neigh_id)));
+                    }
+                }
             }
         }
 
-        // Cleanup adjacency entries for nodes that have no neighbors left
-        self.adjacency.retain(|_, neighbors| !neighbors.is_empty());
+        RoutingTable { sources, next_hops }
+    }
+
+    fn invalidate_routes(&self) {
+        *self.routing_table.lock().unwrap() = None;
     }
 
     fn alloc_id(&mut self, fp: &RelayFingerprint) -> u64 {
@@ -309,6 +490,9 @@ impl RelayGraph {
         if !left_valid || !right_valid {
             return Err(AdjacencyError::InvalidSignatures);
         }
+        if is_from_the_future(adj.unix_timestamp) {
+            return Err(AdjacencyError::FromTheFuture);
+        }
 
         Ok(())
     }
@@ -343,6 +527,8 @@ impl AdjacencyDescriptor {
 pub struct IdentityDescriptor {
     pub identity_pk: RelayIdentityPublic,
     pub onion_pk: DhPublic,
+    /// UNIX timestamp after which the relay stops accepting packets encrypted to `onion_pk`.
+    pub onion_pk_expiry: u64,
 
     pub sig: Bytes,
 
@@ -351,12 +537,17 @@ pub struct IdentityDescriptor {
 
 impl IdentityDescriptor {
     /// Creates an IdentityDescriptor from our own IdentitySecret
-    pub fn new(my_identity: &RelayIdentitySecret, my_onion: &DhSecret) -> Self {
+    pub fn new(
+        my_identity: &RelayIdentitySecret,
+        my_onion: &DhSecret,
+        onion_pk_expiry: u64,
+    ) -> Self {
         let identity_pk = my_identity.public();
         let onion_pk = my_onion.public();
         let mut descr = IdentityDescriptor {
             identity_pk,
             onion_pk,
+            onion_pk_expiry,
             sig: Bytes::new(),
             unix_timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
@@ -374,3 +565,314 @@ impl IdentityDescriptor {
         blake3::keyed_hash(b"identity_descriptor_____________", &this.stdcode())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+
+    use super::*;
+
+    fn link(graph: &mut RelayGraph, a: &RelayIdentitySecret, b: &RelayIdentitySecret) {
+        let (left, right) = if a.public().fingerprint() < b.public().fingerprint() {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        let mut adj = AdjacencyDescriptor {
+            left: left.public().fingerprint(),
+            right: right.public().fingerprint(),
+            left_sig: Bytes::new(),
+            right_sig: Bytes::new(),
+            unix_timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        };
+        adj.left_sig = left.sign(adj.to_sign().as_bytes());
+        adj.right_sig = right.sign(adj.to_sign().as_bytes());
+        graph.insert_adjacency(adj).unwrap();
+    }
+
+    /// Builds a ring of relays, returning the graph and the relays in ring order.
+    fn ring(size: usize) -> (RelayGraph, Vec<RelayIdentitySecret>) {
+        let mut graph = RelayGraph::new();
+        let sks: Vec<RelayIdentitySecret> =
+            (0..size).map(|_| RelayIdentitySecret::generate()).collect();
+        for sk in sks.iter() {
+            graph
+                .insert_identity(IdentityDescriptor::new(sk, &DhSecret::generate(), u64::MAX))
+                .unwrap();
+        }
+        for i in 0..size {
+            link(&mut graph, &sks[i], &sks[(i + 1) % size]);
+        }
+        (graph, sks)
+    }
+
+    #[test]
+    fn next_hops_split_equal_costs() {
+        // relay 0 has neighbors 1 and 5, and relay 3 is equally far through either
+        let (graph, sks) = ring(6);
+        let fps: Vec<RelayFingerprint> = sks.iter().map(|sk| sk.public().fingerprint()).collect();
+        let sources = [(fps[1], DEFAULT_LINK_WEIGHT), (fps[5], DEFAULT_LINK_WEIGHT)];
+
+        let mut hops = graph.next_hops(&sources, &fps[3]);
+        hops.sort();
+        let mut expected = vec![fps[1], fps[5]];
+        expected.sort();
+        assert_eq!(hops, expected);
+        assert_eq!(graph.next_hops(&sources, &fps[2]), vec![fps[1]]);
+        assert_eq!(graph.next_hops(&sources, &fps[4]), vec![fps[5]]);
+
+        // a pricier neighbor breaks the tie
+        let sources = [
+            (fps[1], DEFAULT_LINK_WEIGHT),
+            (fps[5], DEFAULT_LINK_WEIGHT + 1),
+        ];
+        assert_eq!(graph.next_hops(&sources, &fps[3]), vec![fps[1]]);
+    }
+
+    #[test]
+    fn next_hops_follow_graph_changes() {
+        let (mut graph, sks) = ring(6);
+        let fps: Vec<RelayFingerprint> = sks.iter().map(|sk| sk.public().fingerprint()).collect();
+        let sources = [(fps[1], DEFAULT_LINK_WEIGHT), (fps[5], DEFAULT_LINK_WEIGHT)];
+        assert_eq!(graph.next_hops(&sources, &fps[2]), vec![fps[1]]);
+
+        // a slow link makes the long way around cheaper
+        graph.set_link_weight(&fps[1], &fps[2], DEFAULT_LINK_WEIGHT * 10);
+        assert_eq!(
+            graph.link_weight(&fps[2], &fps[1]),
+            DEFAULT_LINK_WEIGHT * 10
+        );
+        assert_eq!(graph.next_hops(&sources, &fps[2]), vec![fps[5]]);
+
+        // and a new shortcut makes both ways equally cheap
+        graph.set_link_weight(&fps[1], &fps[2], DEFAULT_LINK_WEIGHT);
+        link(&mut graph, &sks[5], &sks[2]);
+        assert_eq!(graph.next_hops(&sources, &fps[2]).len(), 2);
+
+        let stranger = RelayIdentitySecret::generate().public().fingerprint();
+        assert!(graph.next_hops(&sources, &stranger).is_empty());
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let (graph, sks) = ring(5);
+        let snapshot = graph.export_snapshot(&sks[0]);
+        assert_eq!(snapshot.identities.len(), 5);
+        assert_eq!(snapshot.adjacencies.len(), 5);
+
+        let mut copy = RelayGraph::new();
+        let stats = copy.import_snapshot(snapshot.clone()).unwrap();
+        assert_eq!(stats.rejected, 0);
+        assert_eq!(
+            copy.export_snapshot(&sks[0]).adjacencies,
+            snapshot.adjacencies
+        );
+
+        // a forged descriptor is skipped, even in a properly signed snapshot
+        let mut forged = snapshot.clone();
+        forged.adjacencies[0].unix_timestamp += 1;
+        forged.signer = sks[1].public();
+        forged.sig = sks[1].sign(forged.to_sign().as_bytes());
+        let stats = RelayGraph::new().import_snapshot(forged).unwrap();
+        assert_eq!(stats.adjacencies, 4);
+        assert_eq!(stats.rejected, 1);
+
+        // and tampering with the snapshot itself fails the whole import
+        let mut tampered = snapshot;
+        tampered.identities.pop();
+        assert!(RelayGraph::new().import_snapshot(tampered).is_err());
+    }
+
+    #[test]
+    fn prune_expires_old_descriptors() {
+        let (mut graph, sks) = ring(4);
+        let old_identity = |sk: &RelayIdentitySecret| {
+            let mut descr = IdentityDescriptor::new(sk, &DhSecret::generate(), u64::MAX);
+            descr.unix_timestamp -= 1800;
+            descr.sig = sk.sign(descr.to_sign().as_bytes());
+            descr
+        };
+        let gone = RelayIdentitySecret::generate();
+        graph.insert_identity(old_identity(&gone)).unwrap();
+        // a relay that still signs adjacencies hasn't left, even if we missed its new identity
+        let linked = RelayIdentitySecret::generate();
+        graph.insert_identity(old_identity(&linked)).unwrap();
+        link(&mut graph, &linked, &sks[0]);
+        assert_eq!(graph.all_nodes().count(), 6);
+
+        graph.set_limits(GraphLimits {
+            descriptor_ttl: 600,
+            ..Default::default()
+        });
+        graph.prune();
+        assert_eq!(graph.all_nodes().count(), 5);
+        assert_eq!(graph.all_adjacencies().count(), 5);
+        assert!(graph.identity(&gone.public().fingerprint()).is_none());
+        assert!(graph.identity(&linked.public().fingerprint()).is_none());
+        assert_eq!(
+            graph
+                .neighbors(&linked.public().fingerprint())
+                .unwrap()
+                .collect::<Vec<_>>(),
+            vec![sks[0].public().fingerprint()]
+        );
+    }
+
+    #[test]
+    fn prune_evicts_farthest_relays() {
+        let (mut graph, sks) = ring(10);
+        let fps: Vec<RelayFingerprint> = sks.iter().map(|sk| sk.public().fingerprint()).collect();
+        graph.set_anchors(vec![fps[0]]);
+        graph.set_limits(GraphLimits {
+            max_relays: 5,
+            ..Default::default()
+        });
+        graph.prune();
+
+        let mut kept: Vec<RelayFingerprint> = graph.all_nodes().collect();
+        kept.sort();
+        let mut expected = vec![fps[8], fps[9], fps[0], fps[1], fps[2]];
+        expected.sort();
+        assert_eq!(kept, expected);
+        // only the links between the survivors are left
+        assert_eq!(graph.all_adjacencies().count(), 4);
+    }
+
+    #[test]
+    fn prune_evicts_relays_of_the_neighbor_with_the_most() {
+        let (mut graph, sks) = ring(4);
+        let fps: Vec<RelayFingerprint> = sks.iter().map(|sk| sk.public().fingerprint()).collect();
+        graph.set_anchors(vec![fps[0]]);
+        graph.set_limits(GraphLimits {
+            max_relays_per_neighbor: 5,
+            ..Default::default()
+        });
+
+        // our neighbor fps[1] makes up relays right next to itself, but only gets to tell us about 5 of them
+        let sybil = NeighborId::Relay(fps[1]);
+        let fakes: Vec<RelayIdentitySecret> =
+            (0..6).map(|_| RelayIdentitySecret::generate()).collect();
+        for fake in fakes.iter() {
+            graph
+                .insert_gossiped_identity(
+                    IdentityDescriptor::new(fake, &DhSecret::generate(), u64::MAX),
+                    sybil,
+                )
+                .unwrap();
+        }
+        assert!(graph.identity(&fakes[5].public().fingerprint()).is_none());
+        for fake in fakes[..5].iter() {
+            link(&mut graph, fake, &sks[1]);
+        }
+
+        // while honest relays learned through fps[3] are farther away
+        let honest = NeighborId::Relay(fps[3]);
+        let far: Vec<RelayIdentitySecret> =
+            (0..2).map(|_| RelayIdentitySecret::generate()).collect();
+        for sk in far.iter() {
+            graph
+                .insert_gossiped_identity(
+                    IdentityDescriptor::new(sk, &DhSecret::generate(), u64::MAX),
+                    honest,
+                )
+                .unwrap();
+        }
+        link(&mut graph, &far[0], &sks[2]);
+        link(&mut graph, &far[1], &far[0]);
+        assert_eq!(graph.all_nodes().count(), 11);
+
+        graph.set_limits(GraphLimits {
+            max_relays: 9,
+            ..Default::default()
+        });
+        graph.prune();
+        assert_eq!(graph.all_nodes().count(), 9);
+        for sk in sks.iter().chain(far.iter()) {
+            assert!(graph.identity(&sk.public().fingerprint()).is_some());
+        }
+    }
+
+    #[test]
+    fn rejects_future_descriptors() {
+        let (mut graph, sks) = ring(2);
+        let sk = RelayIdentitySecret::generate();
+        let mut descr = IdentityDescriptor::new(&sk, &DhSecret::generate(), u64::MAX);
+        descr.unix_timestamp += MAX_CLOCK_SKEW * 2;
+        descr.sig = sk.sign(descr.to_sign().as_bytes());
+        assert!(matches!(
+            graph.insert_identity(descr),
+            Err(IdentityError::FromTheFuture)
+        ));
+
+        let (left, right) = if sks[0].public().fingerprint() < sks[1].public().fingerprint() {
+            (&sks[0], &sks[1])
+        } else {
+            (&sks[1], &sks[0])
+        };
+        let mut adj = graph.all_adjacencies().next().unwrap();
+        adj.unix_timestamp += MAX_CLOCK_SKEW * 2;
+        adj.left_sig = left.sign(adj.to_sign().as_bytes());
+        adj.right_sig = right.sign(adj.to_sign().as_bytes());
+        assert!(matches!(
+            graph.insert_adjacency(adj),
+            Err(AdjacencyError::FromTheFuture)
+        ));
+    }
+
+    #[test]
+    fn deltas_converge_graphs() {
+        let (graph, sks) = ring(20);
+        let mut copy = RelayGraph::new();
+        let fresh = copy.delta(&graph.digest(), 100);
+        assert!(fresh.identities.is_empty() && fresh.adjacencies.is_empty());
+
+        // a small limit takes a few rounds, but every round makes progress
+        let mut rounds = 0;
+        loop {
+            let delta = graph.delta(&copy.digest(), 4);
+            if delta.identities.is_empty() && delta.adjacencies.is_empty() {
+                break;
+            }
+            assert!(delta.adjacencies.len() <= 4);
+            for identity in delta.identities {
+                copy.insert_identity(identity).unwrap();
+            }
+            for adjacency in delta.adjacencies {
+                copy.insert_adjacency(adjacency).unwrap();
+            }
+            rounds += 1;
+            assert!(rounds < 100, "graphs never converged");
+        }
+        assert_eq!(copy.digest().summary(), graph.digest().summary());
+        assert_eq!(copy.all_adjacencies().count(), 20);
+
+        // once converged, only what changed gets sent
+        let mut graph = graph;
+        link(&mut graph, &sks[0], &sks[10]);
+        let delta = graph.delta(&copy.digest(), 100);
+        assert!(delta.identities.is_empty());
+        assert_eq!(delta.adjacencies.len(), 1);
+
+        // and never what the peer has a newer copy of
+        let (left, right) = if sks[1].public().fingerprint() < sks[2].public().fingerprint() {
+            (&sks[1], &sks[2])
+        } else {
+            (&sks[2], &sks[1])
+        };
+        let mut newer = copy
+            .adjacencies(&left.public().fingerprint())
+            .unwrap()
+            .find(|adj| adj.right == right.public().fingerprint())
+            .unwrap();
+        newer.unix_timestamp += 1;
+        newer.left_sig = left.sign(newer.to_sign().as_bytes());
+        newer.right_sig = right.sign(newer.to_sign().as_bytes());
+        copy.insert_adjacency(newer.clone()).unwrap();
+        assert_eq!(graph.delta(&copy.digest(), 100).adjacencies.len(), 1);
+        assert_eq!(copy.delta(&graph.digest(), 100).adjacencies, vec![newer]);
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use earendil_crypt::{NeighborId, RelayFingerprint};
+
+use crate::{link_key, IdentityDescriptor, IdentityError, RelayGraph};
+
+/// Limits on what a [RelayGraph] remembers, so that nobody can fill up our memory with signed but useless descriptors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GraphLimits {
+    /// How long descriptors are kept after they were signed, in seconds.
+    pub descriptor_ttl: u64,
+    /// The most relays the graph holds.
+    pub max_relays: usize,
+    /// The most adjacencies the graph holds.
+    pub max_adjacencies: usize,
+    /// The most relays that any one neighbor can tell us about through gossip.
+    pub max_relays_per_neighbor: usize,
+}
+
+impl Default for GraphLimits {
+    fn default() -> Self {
+        Self {
+            descriptor_ttl: 60 * 60,
+            max_relays: 10_000,
+            max_adjacencies: 100_000,
+            max_relays_per_neighbor: 1_000,
+        }
+    }
+}
+
+impl RelayGraph {
+    pub fn set_limits(&mut self, limits: GraphLimits) {
+        self.limits = limits;
+    }
+
+    /// Sets the relays we care about the most, usually ourselves and our neighbors. These are never evicted, and relays that can't reach them are evicted before any others.
+    pub fn set_anchors(&mut self, anchors: Vec<RelayFingerprint>) {
+        self.anchors = anchors;
+    }
+
+    /// Inserts an identity descriptor that a neighbor gossiped to us. Relays new to us are ignored once the neighbor has told us about `max_relays_per_neighbor` others, so that no one neighbor can fill the graph with relays of its own making.
+    pub fn insert_gossiped_identity(
+        &mut self,
+        identity: IdentityDescriptor,
+        via: NeighborId,
+    ) -> Result<(), IdentityError> {
+        let fp = identity.identity_pk.fingerprint();
+        let is_new = self.id(&fp).is_none();
+        if is_new
+            && self.learned_counts.get(&via).copied().unwrap_or(0)
+                >= self.limits.max_relays_per_neighbor
+        {
+            tracing::debug!(
+                via = debug(via),
+                fp = debug(fp),
+                "neighbor told us about too many relays, ignoring another one"
+            );
+            return Ok(());
+        }
+        self.insert_identity(identity)?;
+        if let Some(id) = self.id(&fp).filter(|_| is_new) {
+            self.learned_via.insert(id, via);
+            *self.learned_counts.entry(via).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    /// Removes expired descriptors, and the relays left with neither an identity nor an adjacency, then evicts whatever goes over the limits. Relays whose identities expired stay as long as they have adjacencies, since they can still forward packets.
+    pub fn prune(&mut self) {
+        let expired_identities = self
+            .id_to_descriptor
+            .iter()
+            .filter(|(_, descr)| self.is_expired(descr.unix_timestamp))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired_identities {
+            self.id_to_descriptor.remove(&id);
+        }
+
+        let expired_adjacencies = self
+            .documents
+            .iter()
+            .filter(|(_, adj)| self.is_expired(adj.unix_timestamp))
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for (left_id, right_id) in expired_adjacencies {
+            self.remove_adjacency(left_id, right_id);
+        }
+
+        let forgotten_relays = self
+            .id_to_fp
+            .keys()
+            .copied()
+            .filter(|id| {
+                !self.id_to_descriptor.contains_key(id) && !self.adjacency.contains_key(id)
+            })
+            .collect::<Vec<_>>();
+        for id in forgotten_relays {
+            self.remove_relay(id);
+        }
+
+        if self.id_to_descriptor.len() > self.limits.max_relays {
+            self.evict_relays();
+        }
+        if self.documents.len() > self.limits.max_adjacencies {
+            self.evict_adjacencies();
+        }
+    }
+
+    pub(crate) fn is_expired(&self, unix_timestamp: u64) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        now.saturating_sub(unix_timestamp) > self.limits.descriptor_ttl
+    }
+
+    /// Orders the relays other than the anchors from the first to evict to the last.
+    ///
+    /// Relays that can't reach the anchors go first. Past that, hop distance alone would favor whoever makes up the most relays next to us, so relays are taken in turns from whichever neighbor told us about the most of them, and only within a neighbor's share do the farthest and oldest go first. A neighbor that makes up relays thus mostly gets its own evicted.
+    pub(crate) fn eviction_order(&self) -> Vec<u64> {
+        let distances = self.distances_from(&self.anchors);
+        let mut shares: HashMap<Option<NeighborId>, Vec<(u64, usize, u64)>> = HashMap::new();
+        for (id, fp) in self.id_to_fp.iter() {
+            if self.anchors.contains(fp) {
+                continue;
+            }
+            let distance = distances.get(fp).copied().unwrap_or(usize::MAX);
+            let timestamp = self
+                .id_to_descriptor
+                .get(id)
+                .map_or(0, |descr| descr.unix_timestamp);
+            shares
+                .entry(self.learned_via.get(id).copied())
+                .or_default()
+                .push((*id, distance, timestamp));
+        }
+
+        let mut candidates = vec![];
+        for mut share in shares.into_values() {
+            share.sort_unstable_by_key(|(_, distance, timestamp)| (Reverse(*distance), *timestamp));
+            let len = share.len();
+            // how many relays of the share are left when each one is evicted
+            candidates.extend(share.into_iter().enumerate().map(
+                |(i, (id, distance, timestamp))| {
+                    (id, distance == usize::MAX, len - i, distance, timestamp)
+                },
+            ));
+        }
+        candidates.sort_unstable_by_key(|(_, unreachable, left, distance, timestamp)| {
+            (
+                Reverse(*unreachable),
+                Reverse(*left),
+                Reverse(*distance),
+                *timestamp,
+            )
+        });
+        candidates.into_iter().map(|(id, ..)| id).collect()
+    }
+
+    /// Evicts relays down to 90% of the limit, so that every new relay past the limit doesn't trigger another eviction, in the order of [RelayGraph::eviction_order].
+    pub(crate) fn evict_relays(&mut self) {
+        let target = self.limits.max_relays - self.limits.max_relays / 10;
+        let evicted = self
+            .eviction_order()
+            .into_iter()
+            .filter(|id| self.id_to_descriptor.contains_key(id))
+            .take(self.id_to_descriptor.len().saturating_sub(target))
+            .collect::<Vec<_>>();
+        tracing::debug!(count = evicted.len(), "relay graph full, evicting relays");
+        for id in evicted {
+            self.remove_relay(id);
+        }
+    }
+
+    /// Evicts adjacencies down to 90% of the limit, starting with those whose end comes first in [RelayGraph::eviction_order], then the oldest.
+    pub(crate) fn evict_adjacencies(&mut self) {
+        let target = self.limits.max_adjacencies - self.limits.max_adjacencies / 10;
+        let order: HashMap<u64, usize> = self
+            .eviction_order()
+            .into_iter()
+            .enumerate()
+            .map(|(rank, id)| (id, rank))
+            .collect();
+        let rank = |id: &u64| order.get(id).copied().unwrap_or(usize::MAX);
+        let mut candidates = self
+            .documents
+            .iter()
+            .map(|(key, adj)| (*key, rank(&key.0).min(rank(&key.1)), adj.unix_timestamp))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable_by_key(|(_, rank, timestamp)| (*rank, *timestamp));
+        let evicted = candidates
+            .into_iter()
+            .map(|(key, _, _)| key)
+            .take(self.documents.len().saturating_sub(target))
+            .collect::<Vec<_>>();
+        tracing::debug!(
+            count = evicted.len(),
+            "relay graph full, evicting adjacencies"
+        );
+        for (left_id, right_id) in evicted {
+            self.remove_adjacency(left_id, right_id);
+        }
+    }
+
+    /// Forgets a relay along with all its adjacencies.
+    fn remove_relay(&mut self, id: u64) {
+        for neigh_id in self.adjacency.get(&id).cloned().unwrap_or_default() {
+            // we don't know which end is left, so try both
+            self.remove_adjacency(id, neigh_id);
+            self.remove_adjacency(neigh_id, id);
+        }
+        self.id_to_descriptor.remove(&id);
+        if let Some(via) = self.learned_via.remove(&id) {
+            if let Some(count) = self.learned_counts.get_mut(&via) {
+                *count -= 1;
+                if *count == 0 {
+                    self.learned_counts.remove(&via);
+                }
+            }
+        }
+        if let Some(fp) = self.id_to_fp.remove(&id) {
+            self.fp_to_id.remove(&fp);
+        }
+    }
+
+    /// Forgets an adjacency, given the IDs of its left and right ends. Does nothing if there is no such adjacency.
+    fn remove_adjacency(&mut self, left_id: u64, right_id: u64) {
+        if self.documents.remove(&(left_id, right_id)).is_none() {
+            return;
+        }
+        self.link_weights.remove(&link_key(left_id, right_id));
+        for (id, neigh_id) in [(left_id, right_id), (right_id, left_id)] {
+            if let Some(neighbors) = self.adjacency.get_mut(&id) {
+                neighbors.remove(&neigh_id);
+                if neighbors.is_empty() {
+                    self.adjacency.remove(&id);
+                }
+            }
+        }
+        self.invalidate_routes();
+    }
+}
@@ -0,0 +1,116 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use earendil_crypt::{RelayIdentityPublic, RelayIdentitySecret};
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+
+use crate::{AdjacencyDescriptor, IdentityDescriptor, RelayGraph};
+
+/// The only snapshot format version we understand.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// A signed copy of everything a relay graph knows, for seeding other relay graphs with.
+///
+/// The signature only vouches for who produced the snapshot. Every descriptor inside carries its own signatures, which are checked again on import.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GraphSnapshot {
+    pub version: u32,
+    pub unix_timestamp: u64,
+    pub identities: Vec<IdentityDescriptor>,
+    pub adjacencies: Vec<AdjacencyDescriptor>,
+
+    pub signer: RelayIdentityPublic,
+    pub sig: Bytes,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SnapshotError {
+    #[error("snapshot has format version {0}, but only version {SNAPSHOT_VERSION} is supported")]
+    UnsupportedVersion(u32),
+
+    #[error("invalid signature on the snapshot")]
+    InvalidSignature,
+}
+
+/// What importing a [GraphSnapshot] did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotImport {
+    pub identities: usize,
+    pub adjacencies: usize,
+    /// descriptors that failed verification and were left out
+    pub rejected: usize,
+}
+
+impl GraphSnapshot {
+    /// The value that the signature is supposed to be computed against.
+    pub fn to_sign(&self) -> blake3::Hash {
+        let mut this = self.clone();
+        this.sig = Bytes::new();
+        blake3::keyed_hash(b"graph_snapshot__________________", &this.stdcode())
+    }
+
+    /// Checks the format version and the signature of the snapshot, but not the descriptors inside.
+    pub fn verify(&self) -> Result<(), SnapshotError> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(self.version));
+        }
+        self.signer
+            .verify(self.to_sign().as_bytes(), &self.sig)
+            .map_err(|_| SnapshotError::InvalidSignature)
+    }
+}
+
+impl RelayGraph {
+    /// Exports every identity and adjacency descriptor in the graph as a snapshot signed by `signer`. Descriptors are sorted, so that the same graph always gives the same snapshot contents.
+    pub fn export_snapshot(&self, signer: &RelayIdentitySecret) -> GraphSnapshot {
+        let mut identities: Vec<IdentityDescriptor> =
+            self.id_to_descriptor.values().cloned().collect();
+        identities.sort_by_key(|descr| descr.identity_pk.fingerprint());
+        let mut adjacencies: Vec<AdjacencyDescriptor> = self.documents.values().cloned().collect();
+        adjacencies.sort_by_key(|adj| (adj.left, adj.right));
+
+        let mut snapshot = GraphSnapshot {
+            version: SNAPSHOT_VERSION,
+            unix_timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            identities,
+            adjacencies,
+            signer: signer.public(),
+            sig: Bytes::new(),
+        };
+        snapshot.sig = signer.sign(snapshot.to_sign().as_bytes());
+        snapshot
+    }
+
+    /// Verifies a snapshot and merges it into the graph. Each descriptor is verified just like one learned through gossip, and the ones that fail are skipped rather than failing the whole import.
+    pub fn import_snapshot(
+        &mut self,
+        snapshot: GraphSnapshot,
+    ) -> Result<SnapshotImport, SnapshotError> {
+        snapshot.verify()?;
+        let mut stats = SnapshotImport::default();
+        // identities go first, since adjacencies can only be verified against them
+        for identity in snapshot.identities {
+            match self.insert_identity(identity) {
+                Ok(()) => stats.identities += 1,
+                Err(err) => {
+                    tracing::debug!(err = debug(err), "skipping invalid identity in snapshot");
+                    stats.rejected += 1;
+                }
+            }
+        }
+        for adjacency in snapshot.adjacencies {
+            match self.insert_adjacency(adjacency) {
+                Ok(()) => stats.adjacencies += 1,
+                Err(err) => {
+                    tracing::debug!(err = debug(err), "skipping invalid adjacency in snapshot");
+                    stats.rejected += 1;
+                }
+            }
+        }
+        Ok(stats)
+    }
+}
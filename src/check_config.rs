@@ -0,0 +1,474 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    path::Path,
+};
+
+use anyhow::Context;
+use colored::Colorize;
+use earendil_crypt::{HavenIdentitySecret, RelayFingerprint, RelayIdentitySecret};
+
+use crate::config::{ConfigFile, Identity};
+
+/// Something wrong, or probably wrong, with a config file.
+#[derive(Clone, Debug)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    /// Where in the config the problem is, like `in_routes.main.listen` or `havens[0].rendezvous`. Empty if it's about the config as a whole.
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The daemon refuses to start with this config
+    Error,
+    /// The daemon starts, but probably doesn't do what was intended
+    Warning,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Cross-checks the parts of a parsed config against each other. Unlike starting the daemon, this never creates missing identity files.
+pub fn check_config(cfg: &ConfigFile) -> Vec<ConfigProblem> {
+    let mut problems = vec![];
+    let mut error = |path: &str, message: String| {
+        problems.push(ConfigProblem {
+            severity: Severity::Error,
+            path: path.to_string(),
+            message,
+        })
+    };
+
+    let my_fp = match cfg.identity.as_ref().map(peek_relay_identity) {
+        Some(Ok(id)) => id.map(|id| id.public().fingerprint()),
+        Some(Err(err)) => {
+            error(identity_path(cfg.identity.as_ref()), format!("{err:#}"));
+            None
+        }
+        None => None,
+    };
+    if cfg.is_client() && !cfg.in_routes.is_empty() {
+        error(
+            "in_routes",
+            "clients cannot accept links, since only relays have the identity that in_routes need. Add an identity_seed or identity_file to make this a relay"
+                .into(),
+        );
+    }
+    if cfg.in_routes.is_empty() && cfg.out_routes.is_empty() {
+        error(
+            "",
+            "there are no in_routes or out_routes, so the daemon could not reach anybody".into(),
+        );
+    }
+
+    // every port we listen on, by protocol
+    let mut listeners: Vec<(String, &str, SocketAddr)> =
+        vec![("control_listen".into(), "tcp", cfg.control_listen)];
+    for (name, route) in cfg.in_routes.iter() {
+        listeners.push((format!("in_routes.{name}.listen"), "tcp", route.listen));
+    }
+    if let Some(socks5) = cfg.socks5 {
+        listeners.push(("socks5.listen".into(), "tcp", socks5.listen));
+    }
+    for (i, forward) in cfg.tcp_forwards.iter().enumerate() {
+        listeners.push((format!("tcp_forwards[{i}].listen"), "tcp", forward.listen));
+    }
+    for (i, forward) in cfg.udp_forwards.iter().enumerate() {
+        listeners.push((format!("udp_forwards[{i}].listen"), "udp", forward.listen));
+    }
+    for (i, (path, protocol, addr)) in listeners.iter().enumerate() {
+        let clash = listeners[..i].iter().find(|(_, other_protocol, other)| {
+            other_protocol == protocol
+                && other.port() == addr.port()
+                && addr.port() != 0
+                && (other.ip() == addr.ip()
+                    || other.ip().is_unspecified()
+                    || addr.ip().is_unspecified())
+        });
+        if let Some((other_path, _, other)) = clash {
+            error(
+                path,
+                format!("{addr} clashes with {other} of {other_path}, since both listen on {protocol} port {}", addr.port()),
+            );
+        }
+    }
+
+    if cfg.route_policy.min_hops > cfg.route_policy.hops {
+        error(
+            "route_policy.min_hops",
+            format!(
+                "routes never have more than the {} hops that route_policy.hops asks for, so no route could have {}",
+                cfg.route_policy.hops, cfg.route_policy.min_hops
+            ),
+        );
+    }
+    if let Some(cover) = cfg.cover_traffic {
+        if !(cover.rate > 0.0 && cover.rate.is_finite()) {
+            error(
+                "cover_traffic.rate",
+                format!(
+                    "{} is not a positive number of packets per second",
+                    cover.rate
+                ),
+            );
+        }
+        if !(0.0..=1.0).contains(&cover.loop_fraction) {
+            error(
+                "cover_traffic.loop_fraction",
+                format!("{} is not a fraction between 0 and 1", cover.loop_fraction),
+            );
+        }
+    }
+
+    let mut known_relays: HashSet<RelayFingerprint> = my_fp.into_iter().collect();
+    for (name, route) in cfg.out_routes.iter() {
+        known_relays.insert(route.fingerprint);
+        if Some(route.fingerprint) == my_fp {
+            problems.push(ConfigProblem {
+                severity: Severity::Warning,
+                path: format!("out_routes.{name}.fingerprint"),
+                message: "this is our own fingerprint. Relays already link to themselves, so this route is redundant".into(),
+            });
+        }
+    }
+    for (i, snapshot_cfg) in cfg.bootstrap_snapshots.iter().enumerate() {
+        match snapshot_cfg.read() {
+            Ok(snapshot) => known_relays.extend(
+                snapshot
+                    .identities
+                    .iter()
+                    .map(|id| id.identity_pk.fingerprint()),
+            ),
+            Err(err) => problems.push(ConfigProblem {
+                severity: Severity::Error,
+                path: format!("bootstrap_snapshots[{i}]"),
+                message: format!("{err:#}"),
+            }),
+        }
+    }
+
+    let mut havens = BTreeMap::new();
+    for (i, haven) in cfg.havens.iter().enumerate() {
+        match peek_haven_identity(&haven.identity) {
+            Ok(Some(id)) => {
+                let endpoint = format!("{}:{}", id.public().fingerprint(), haven.listen_port);
+                if let Some(other) = havens.insert(endpoint.clone(), i) {
+                    problems.push(ConfigProblem {
+                        severity: Severity::Error,
+                        path: format!("havens[{i}]"),
+                        message: format!("havens[{other}] already serves {endpoint}"),
+                    });
+                }
+            }
+            Ok(None) => {}
+            Err(err) => problems.push(ConfigProblem {
+                severity: Severity::Error,
+                path: format!("havens[{i}].{}", identity_path(Some(&haven.identity))),
+                message: format!("{err:#}"),
+            }),
+        }
+        if !known_relays.contains(&haven.rendezvous) {
+            problems.push(ConfigProblem {
+                severity: Severity::Warning,
+                path: format!("havens[{i}].rendezvous"),
+                message: format!(
+                    "{} is neither us, nor an out_route, nor in a bootstrap snapshot, so it may not be a relay",
+                    haven.rendezvous
+                ),
+            });
+        }
+    }
+
+    problems
+}
+
+fn identity_path(identity: Option<&Identity>) -> &'static str {
+    match identity {
+        Some(Identity::IdentityFile(_)) => "identity_file",
+        _ => "identity_seed",
+    }
+}
+
+/// Reads a relay identity without creating its file if it doesn't exist yet, in which case there is nothing to check.
+fn peek_relay_identity(identity: &Identity) -> anyhow::Result<Option<RelayIdentitySecret>> {
+    Ok(match identity {
+        Identity::IdentitySeed(seed) => Some(RelayIdentitySecret::from_seed(seed)),
+        Identity::IdentityFile(path) => {
+            read_identity_file(path)?.map(|bts| RelayIdentitySecret::from_bytes(&bts))
+        }
+    })
+}
+
+fn peek_haven_identity(identity: &Identity) -> anyhow::Result<Option<HavenIdentitySecret>> {
+    Ok(match identity {
+        Identity::IdentitySeed(seed) => Some(HavenIdentitySecret::from_seed(seed)),
+        Identity::IdentityFile(path) => {
+            read_identity_file(path)?.map(|bts| HavenIdentitySecret::from_bytes(&bts))
+        }
+    })
+}
+
+fn read_identity_file(path: &Path) -> anyhow::Result<Option<[u8; 32]>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bts = std::fs::read(path)
+        .with_context(|| format!("cannot read identity file {}", path.display()))?;
+    let bts: [u8; 32] = bts
+        .try_into()
+        .ok()
+        .with_context(|| format!("identity file {} is not 32 bytes long", path.display()))?;
+    Ok(Some(bts))
+}
+
+/// Checks a config file, printing every problem found along with its line number. Fails if the daemon would refuse the config.
+pub fn main_check_config(path: &Path) -> anyhow::Result<()> {
+    let yaml = std::fs::read_to_string(path).context("cannot read config file")?;
+    let location = |line: Option<usize>| match line {
+        Some(line) => format!("{}:{line}", path.display()),
+        None => path.display().to_string(),
+    };
+    let cfg = match ConfigFile::load(path) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            // parsing the YAML directly, rather than through JSON like the daemon does, tells us where the problem is
+            let line = serde_yaml::from_str::<ConfigFile>(&yaml)
+                .err()
+                .and_then(|err| err.location())
+                .map(|loc| loc.line());
+            println!("{} {}: {err:#}", "error".red().bold(), location(line));
+            anyhow::bail!("{} cannot be parsed", path.display());
+        }
+    };
+
+    let problems = check_config(&cfg);
+    for problem in problems.iter() {
+        let severity = match problem.severity {
+            Severity::Error => "error".red().bold(),
+            Severity::Warning => "warning".yellow().bold(),
+        };
+        println!(
+            "{severity} {}: {problem}",
+            location(yaml_line(&yaml, &problem.path))
+        );
+    }
+    if problems
+        .iter()
+        .any(|problem| problem.severity == Severity::Error)
+    {
+        anyhow::bail!("{} is not a valid config", path.display());
+    }
+    println!("{} is valid", path.display());
+    Ok(())
+}
+
+/// Generates a JSON Schema of the config file format, which editors can use for completion and validation.
+pub fn config_schema() -> String {
+    let schema = schemars::schema_for!(ConfigFile);
+    serde_json::to_string_pretty(&schema).expect("schemas always serialize")
+}
+
+/// Finds the line, counting from 1, of the value at a path like `havens[0].rendezvous` in a block-style YAML document. If part of the path is missing, the line of the deepest part that was found is returned.
+fn yaml_line(yaml: &str, path: &str) -> Option<usize> {
+    struct Line<'a> {
+        /// the indentation of the `- ` that starts a list item on this line
+        item_indent: Option<usize>,
+        /// the indentation of the key or value on this line, after any `- `
+        indent: usize,
+        text: &'a str,
+    }
+    let lines: Vec<Option<Line>> = yaml
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                return None;
+            }
+            let indent = line.len() - trimmed.len();
+            Some(match trimmed.strip_prefix("- ") {
+                Some(rest) => Line {
+                    item_indent: Some(indent),
+                    indent: indent + 2 + (rest.len() - rest.trim_start().len()),
+                    text: rest.trim_start(),
+                },
+                None => Line {
+                    item_indent: None,
+                    indent,
+                    text: trimmed,
+                },
+            })
+        })
+        .collect();
+
+    let mut segments = vec![];
+    for part in path.split('.').filter(|part| !part.is_empty()) {
+        let (key, indices) = part.split_once('[').unwrap_or((part, ""));
+        if !key.is_empty() {
+            segments.push(Err(key));
+        }
+        for index in indices.split('[') {
+            if let Ok(index) = index.trim_end_matches(']').parse::<usize>() {
+                segments.push(Ok(index));
+            }
+        }
+    }
+
+    // the lines that the part of the document we are looking into spans
+    let (mut start, mut end) = (0, lines.len());
+    let mut found = None;
+    for segment in segments {
+        let region = || (start..end).filter_map(|i| lines[i].as_ref().map(|line| (i, line)));
+        let hit = match segment {
+            Err(key) => {
+                let level = region().map(|(_, line)| line.indent).min();
+                region()
+                    .find(|(_, line)| {
+                        Some(line.indent) == level
+                            && [key.to_string(), format!("\"{key}\""), format!("'{key}'")]
+                                .iter()
+                                .any(|quoted| {
+                                    line.text
+                                        .strip_prefix(quoted.as_str())
+                                        .is_some_and(|rest| rest.trim_start().starts_with(':'))
+                                })
+                    })
+                    .map(|(i, line)| {
+                        let end = (i + 1..end)
+                            .find(|&j| {
+                                lines[j]
+                                    .as_ref()
+                                    .is_some_and(|other| other.indent <= line.indent)
+                            })
+                            .unwrap_or(end);
+                        (i + 1, end, i)
+                    })
+            }
+            Ok(index) => {
+                let level = region().filter_map(|(_, line)| line.item_indent).min();
+                region()
+                    .filter(|(_, line)| line.item_indent.is_some() && line.item_indent == level)
+                    .nth(index)
+                    .map(|(i, line)| {
+                        let item_indent = line.item_indent.unwrap_or_default();
+                        let end = (i + 1..end)
+                            .find(|&j| {
+                                lines[j].as_ref().is_some_and(|other| {
+                                    other.indent <= item_indent
+                                        || other.item_indent == Some(item_indent)
+                                })
+                            })
+                            .unwrap_or(end);
+                        // the item's first key is on the same line as its `- `
+                        (i, end, i)
+                    })
+            }
+        };
+        match hit {
+            Some((new_start, new_end, line)) => {
+                (start, end) = (new_start, new_end);
+                found = Some(line + 1);
+            }
+            None => break,
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = r#"
+identity_seed: alice
+# a comment
+in_routes:
+  main:
+    obfs: none
+    listen: 0.0.0.0:19999
+havens:
+  - identity_seed: one
+    listen_port: 80
+    rendezvous: abcd
+  -   identity_seed: two
+      rendezvous: efgh
+udp_forwards:
+- listen: 127.0.0.1:19999
+  remote: abcd:10000
+"#;
+
+    #[test]
+    fn yaml_lines_are_found() {
+        assert_eq!(yaml_line(YAML, "identity_seed"), Some(2));
+        assert_eq!(yaml_line(YAML, "in_routes.main.listen"), Some(7));
+        assert_eq!(yaml_line(YAML, "havens[0]"), Some(9));
+        assert_eq!(yaml_line(YAML, "havens[0].rendezvous"), Some(11));
+        assert_eq!(yaml_line(YAML, "havens[1].rendezvous"), Some(13));
+        assert_eq!(yaml_line(YAML, "udp_forwards[0].listen"), Some(15));
+        // missing parts point at their closest parent
+        assert_eq!(yaml_line(YAML, "havens[1].listen_port"), Some(12));
+        assert_eq!(yaml_line(YAML, ""), None);
+    }
+
+    #[test]
+    fn problems_are_found() {
+        let cfg: ConfigFile = serde_yaml::from_str(
+            r#"
+in_routes:
+  main:
+    obfs: none
+    listen: 0.0.0.0:18964
+"#,
+        )
+        .unwrap();
+        let problems: Vec<String> = check_config(&cfg)
+            .iter()
+            .map(|problem| problem.path.clone())
+            .collect();
+        // a client with in_routes, listening on the default control port
+        assert_eq!(problems, vec!["in_routes", "in_routes.main.listen"]);
+    }
+
+    #[test]
+    fn cover_traffic_is_validated() {
+        let cfg: ConfigFile = serde_yaml::from_str(
+            r#"
+out_routes:
+  relay:
+    connect: 1.2.3.4:19999
+    fingerprint: 33733f5086b8a7ebe32f3eb5f974085361475138ae06fd12105dd293fb3123f5
+    obfs: none
+cover_traffic:
+  rate: 0
+  loop_fraction: 1.5
+"#,
+        )
+        .unwrap();
+        let problems: Vec<String> = check_config(&cfg)
+            .iter()
+            .map(|problem| problem.path.clone())
+            .collect();
+        assert_eq!(
+            problems,
+            vec!["cover_traffic.rate", "cover_traffic.loop_fraction"]
+        );
+    }
+
+    #[test]
+    fn schema_is_up_to_date() {
+        let committed = include_str!("../cfg_example/config.schema.json");
+        assert!(
+            committed.trim() == config_schema().trim(),
+            "run `earendil config-schema > cfg_example/config.schema.json` to update the schema"
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use clap::{arg, Subcommand};
 use earendil_crypt::{HavenFingerprint, RelayFingerprint};
 
@@ -6,6 +8,9 @@ pub enum ControlCommand {
     /// Prints the information of all hosted havens
     HavensInfo,
 
+    /// Prints the status of all configured port forwards
+    ForwardsInfo,
+
     /// Send a GlobalRpc request to a destination.
     GlobalRpc {
         #[arg(long)]
@@ -36,14 +41,84 @@ pub enum ControlCommand {
     /// Dumps the relay graph in graphviz format.
     RelayGraphviz,
 
+    /// Dumps the relays and links in the relay graph, optionally only those near one relay.
+    RelayGraph {
+        /// Only show relays near this one. Defaults to us if only --hops is given
+        #[arg(long)]
+        around: Option<RelayFingerprint>,
+        /// How many hops away from the --around relay to show. Defaults to 1 if only --around is given
+        #[arg(long)]
+        hops: Option<usize>,
+        /// Print JSON instead of human-readable text
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Finds a path with the fewest hops between two relays.
+    RelayPath {
+        #[arg(long)]
+        from: RelayFingerprint,
+        #[arg(long)]
+        to: RelayFingerprint,
+        /// Print JSON instead of human-readable text
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Exports a signed snapshot of the relay graph, which other nodes can bootstrap from.
+    ExportGraph {
+        /// The file to write the snapshot to
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+
     /// Dumps my own routes.
     MyRoutes,
 
+    /// Prints the status and traffic of every live link to a neighbor.
+    LinkStatus {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Makes the daemon reread its config file, restarting only the routes, havens, forwards and proxies that changed.
+    ReloadConfig,
+
+    /// Prints daemon events, such as neighbors coming and going or chat messages arriving, as they happen.
+    Events {
+        /// Print one JSON object per event
+        #[arg(long)]
+        json: bool,
+    },
+
     /// Interactive chat for talking to immediate neighbors
     Chat {
         #[command(subcommand)]
         chat_command: ChatCommand,
     },
+
+    /// Prints statistics about the filter that drops replayed packets
+    ReplayFilterInfo,
+
+    /// Prints statistics about the queue that holds packets for their mix delays
+    MixQueueInfo,
+
+    /// Prints how many cover traffic loop packets routed through each relay came back
+    RelayReliability,
+
+    /// Prints what each neighbor owes us and what we owe each neighbor
+    Debts {
+        /// Only show neighbors whose fingerprint or client id starts with this, along with their settlement history
+        #[arg(short, long)]
+        neighbor: Option<String>,
+    },
+
+    /// Manual debt settlement with immediate neighbors
+    Settle {
+        #[command(subcommand)]
+        settle_command: SettleCommand,
+    },
 }
 
 #[derive(Subcommand)]
@@ -72,3 +147,33 @@ pub enum ChatCommand {
         msg: String,
     },
 }
+
+#[derive(Subcommand)]
+pub enum SettleCommand {
+    /// Asks a neighbor to accept a manual settlement of what we owe it, and waits for its answer
+    Start {
+        /// The fingerprint or client id (or a prefix of either) of the neighbor to settle with.
+        neighbor: String,
+        /// The amount settled, in micromel
+        #[arg(short, long)]
+        amount: u64,
+    },
+
+    /// Lists the incoming settlement requests waiting for our answer
+    List,
+
+    /// Accepts a neighbor's pending settlement request
+    Accept {
+        /// The fingerprint or client id (or a prefix of either) of the neighbor.
+        neighbor: String,
+    },
+
+    /// Rejects a neighbor's pending settlement request
+    Reject {
+        /// The fingerprint or client id (or a prefix of either) of the neighbor.
+        neighbor: String,
+    },
+
+    /// Prints every completed settlement, in both directions
+    History,
+}
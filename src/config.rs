@@ -1,8 +1,16 @@
-use std::{collections::BTreeMap, io::Write, net::SocketAddr, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 use earendil_crypt::{HavenIdentitySecret, RelayFingerprint, RelayIdentitySecret};
+use earendil_packet::DelayProfile;
+use earendil_topology::{GraphLimits, GraphSnapshot};
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use std::fs::OpenOptions;
@@ -11,7 +19,7 @@ use tracing::instrument;
 use crate::haven::HavenEndpoint;
 
 /// A YAML-serializable configuration file
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct ConfigFile {
     /// Seed of the long-term identity. Must be long and difficult to guess!
@@ -27,6 +35,13 @@ pub struct ConfigFile {
     #[serde(default = "default_control_listen")]
     pub control_listen: SocketAddr,
 
+    /// A Unix domain socket to also serve the local control protocol on. Only users who can open the socket file can reach it.
+    pub control_unix: Option<PathBuf>,
+
+    /// Who may use the local control protocol. By default, only clients holding the cookie written at startup get in.
+    #[serde(default)]
+    pub control_auth: ControlAuthConfig,
+
     /// List of all listeners for incoming connections
     #[serde(default)]
     pub in_routes: BTreeMap<String, InRouteConfig>,
@@ -34,6 +49,33 @@ pub struct ConfigFile {
     #[serde(default)]
     pub out_routes: BTreeMap<String, OutRouteConfig>,
 
+    /// How often, in seconds, a relay rotates its onion key. The previous key stays valid for another period after each rotation.
+    #[serde(default = "default_onion_key_rotation")]
+    pub onion_key_rotation: u64,
+
+    /// How anonymous routes are picked, unless a socket overrides it
+    #[serde(default)]
+    pub route_policy: RoutePolicy,
+
+    /// The longest a relay holds a packet for mixing, in milliseconds. Longer delays asked for by packets are cut down to this.
+    #[serde(default = "default_max_mix_delay_ms")]
+    pub max_mix_delay_ms: u64,
+
+    /// Dummy traffic that hides when we are actually active. Off unless configured.
+    pub cover_traffic: Option<CoverTrafficConfig>,
+
+    /// How long relay graph descriptors are kept, and how big the relay graph may grow
+    #[serde(default)]
+    pub relay_graph: RelayGraphConfig,
+
+    /// Limits on what neighbors can teach us about the relay graph
+    #[serde(default)]
+    pub gossip: GossipConfig,
+
+    /// Relay graph snapshots, as exported by `earendil control export-graph`, to seed the relay graph with at startup
+    #[serde(default)]
+    pub bootstrap_snapshots: Vec<BootstrapSnapshotConfig>,
+
     /// Contains the automatic settlement difficulty if accepted
     pub auto_settle: Option<AutoSettle>,
 
@@ -51,40 +93,104 @@ pub struct ConfigFile {
 }
 
 impl ConfigFile {
+    /// Reads a YAML config file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let json: serde_json::Value =
+            serde_yaml::from_slice(&std::fs::read(path).context("cannot read config file")?)
+                .context("syntax error in config file")?;
+        Ok(serde_json::from_value(json)?)
+    }
+
     pub fn is_client(&self) -> bool {
         self.identity.is_none()
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ControlAuthConfig {
+    /// Serve the control protocol without any authentication, giving full access to anybody who can reach `control_listen` or `control_unix`.
+    #[serde(default)]
+    pub unauthenticated: bool,
+    /// Where to write a fresh full-access token every time the daemon starts. Defaults to `~/.earendil/control_cookie`, which is where `earendil control` looks for one.
+    pub cookie_file: Option<PathBuf>,
+    /// Long-lived tokens, each with a scope
+    #[serde(default)]
+    pub tokens: Vec<ControlTokenConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ControlTokenConfig {
+    pub token: String,
+    pub scope: ControlScope,
+}
+
+/// What a control protocol token allows.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlScope {
+    /// Only verbs that look at the daemon's state, like listing neighbors or reading chats
+    ReadOnly,
+    /// Every verb, including ones that send chats, settle debts or publish to the DHT
+    Full,
+}
+
+fn default_onion_key_rotation() -> u64 {
+    86400
+}
+
+fn default_max_mix_delay_ms() -> u64 {
+    5000
+}
+
 fn default_control_listen() -> SocketAddr {
     "127.0.0.1:18964".parse().unwrap()
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct InRouteConfig {
     pub listen: SocketAddr,
     pub obfs: ObfsConfig,
+    #[serde(default)]
+    pub link_price: LinkPrice,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ObfsConfig {
     None,
     Sosistab3(String),
 }
 
+impl ObfsConfig {
+    /// The name of the obfuscation protocol, without any secrets.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObfsConfig::None => "none",
+            ObfsConfig::Sosistab3(_) => "sosistab3",
+        }
+    }
+}
+
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct OutRouteConfig {
     pub connect: String,
     #[serde_as(as = "serde_with::DisplayFromStr")]
     pub fingerprint: RelayFingerprint,
     pub obfs: ObfsConfig,
+    #[serde(default)]
+    pub link_price: LinkPrice,
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(rename_all = "snake_case")]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct UdpForwardConfig {
     pub listen: SocketAddr,
     #[serde_as(as = "serde_with::DisplayFromStr")]
@@ -92,8 +198,8 @@ pub struct UdpForwardConfig {
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(rename_all = "snake_case")]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct TcpForwardConfig {
     pub listen: SocketAddr,
     #[serde_as(as = "serde_with::DisplayFromStr")]
@@ -101,16 +207,16 @@ pub struct TcpForwardConfig {
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-#[serde(rename_all = "snake_case")]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub struct Socks5Config {
     pub listen: SocketAddr,
     pub fallback: Socks5Fallback,
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
-#[serde(rename_all = "snake_case")]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub enum Socks5Fallback {
     Block,
     PassThrough,
@@ -121,7 +227,8 @@ pub enum Socks5Fallback {
 }
 
 #[serde_as]
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct HavenConfig {
     #[serde(flatten)]
     pub identity: Identity,
@@ -132,14 +239,15 @@ pub struct HavenConfig {
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
 pub enum HavenHandler {
     TcpService { upstream: SocketAddr },
+    UdpService { upstream: SocketAddr },
     SimpleProxy,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 /// A configuration for an identity, specified either as a human-readable seed that will be passed through a KDF, or a file that stores the raw binary bytes of the identity secret.
 #[serde(rename_all = "snake_case")]
 pub enum Identity {
@@ -221,7 +329,8 @@ impl Identity {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct LinkPrice {
     /// in micromel
     pub max_outgoing_price: u64,
@@ -229,8 +338,224 @@ pub struct LinkPrice {
     pub incoming_debt_limit: u64,
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
+/// How the routes of anonymous packets, and of the reply blocks for them, are formed: which relays they go through, and how long each relay holds them.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RoutePolicy {
+    /// How many relays a packet goes through before reaching its destination. Must be less than `MAX_HOPS`. Networks with fewer usable relays get shorter routes, down to `min_hops`.
+    #[serde(default = "default_route_hops")]
+    pub hops: usize,
+    /// The fewest relays a packet may go through. Packets are refused rather than sent over routes that would have to be shorter. Setting this to 0 allows routes that go straight to the destination, with no relay in between to hide the sender
+    #[serde(default = "default_min_route_hops")]
+    pub min_hops: usize,
+    /// Relays never to route through
+    #[serde(default)]
+    #[serde_as(as = "Vec<serde_with::DisplayFromStr>")]
+    pub exclude: Vec<RelayFingerprint>,
+    /// Prefer relays that are fewer links apart, trading some anonymity for lower latency
+    #[serde(default)]
+    pub latency_weighted: bool,
+    /// How long every relay on the route holds packets for mixing
+    #[serde(default)]
+    pub anonymity: AnonymityLevel,
+}
+
+impl Default for RoutePolicy {
+    fn default() -> Self {
+        Self {
+            hops: default_route_hops(),
+            min_hops: default_min_route_hops(),
+            exclude: vec![],
+            latency_weighted: false,
+            anonymity: AnonymityLevel::default(),
+        }
+    }
+}
+
+/// Named mixing strengths, trading latency for resistance against matching up the packets going in and out of relays.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AnonymityLevel {
+    /// No mixing delays at all, for interactive traffic
+    Interactive,
+    /// Short mixing delays, averaging 15 ms per hop
+    #[default]
+    Standard,
+    /// Long mixing delays, averaging half a second per hop, for latency-insensitive traffic
+    Strong,
+}
+
+impl AnonymityLevel {
+    pub fn delay_profile(&self) -> DelayProfile {
+        match self {
+            AnonymityLevel::Interactive => DelayProfile::None,
+            AnonymityLevel::Standard => DelayProfile::Exponential { mean_ms: 15 },
+            AnonymityLevel::Strong => DelayProfile::Exponential { mean_ms: 500 },
+        }
+    }
+}
+
+fn default_min_route_hops() -> usize {
+    1
+}
+
+fn default_route_hops() -> usize {
+    2
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CoverTrafficConfig {
+    /// Average number of cover packets sent per second, which must be positive. Packets go out at Poisson-distributed times, like bursty real traffic.
+    pub rate: f64,
+    /// The fraction of cover packets, between 0 and 1, that loop back to us rather than get dropped at a random relay. Loop packets also measure how reliable relays are.
+    #[serde(default = "default_loop_fraction")]
+    pub loop_fraction: f64,
+}
+
+fn default_loop_fraction() -> f64 {
+    0.5
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RelayGraphConfig {
+    /// How long identity and adjacency descriptors are kept after they were signed, in seconds. Relays re-sign theirs every quarter of this, so only departed relays expire.
+    #[serde(default = "default_descriptor_ttl")]
+    pub descriptor_ttl: u64,
+    /// The most relays the graph holds. Past this, relays that can't reach us are evicted first, then the relays of whichever neighbor told us about the most, farthest first.
+    #[serde(default = "default_max_relays")]
+    pub max_relays: usize,
+    /// The most adjacencies the graph holds, evicted the same way.
+    #[serde(default = "default_max_adjacencies")]
+    pub max_adjacencies: usize,
+    /// The most relays that any one neighbor can tell us about through gossip, so that a neighbor that makes up relays can't crowd out the real ones.
+    #[serde(default = "default_max_relays_per_neighbor")]
+    pub max_relays_per_neighbor: usize,
+    /// How often, in seconds, expired descriptors are pruned.
+    #[serde(default = "default_prune_interval")]
+    pub prune_interval: u64,
+}
+
+impl Default for RelayGraphConfig {
+    fn default() -> Self {
+        Self {
+            descriptor_ttl: default_descriptor_ttl(),
+            max_relays: default_max_relays(),
+            max_adjacencies: default_max_adjacencies(),
+            max_relays_per_neighbor: default_max_relays_per_neighbor(),
+            prune_interval: default_prune_interval(),
+        }
+    }
+}
+
+impl RelayGraphConfig {
+    pub fn limits(&self) -> GraphLimits {
+        GraphLimits {
+            descriptor_ttl: self.descriptor_ttl,
+            max_relays: self.max_relays,
+            max_adjacencies: self.max_adjacencies,
+            max_relays_per_neighbor: self.max_relays_per_neighbor,
+        }
+    }
+
+    /// How old, in seconds, our own descriptors may get before we re-sign them. Re-signing only this often keeps the graph digests that neighbors compare during gossip from changing every second.
+    pub fn resign_interval(&self) -> u64 {
+        self.descriptor_ttl / 4
+    }
+}
+
+fn default_descriptor_ttl() -> u64 {
+    GraphLimits::default().descriptor_ttl
+}
+
+fn default_max_relays() -> usize {
+    GraphLimits::default().max_relays
+}
+
+fn default_max_adjacencies() -> usize {
+    GraphLimits::default().max_adjacencies
+}
+
+fn default_max_relays_per_neighbor() -> usize {
+    GraphLimits::default().max_relays_per_neighbor
+}
+
+fn default_prune_interval() -> u64 {
+    60
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct GossipConfig {
+    /// The most adjacencies we take from one neighbor in one gossip round. The rest are ignored.
+    #[serde(default = "default_max_adjacencies_per_round")]
+    pub max_adjacencies_per_round: usize,
+    /// The most relays we have never heard of that gossip may teach us per minute, across all neighbors.
+    #[serde(default = "default_max_new_relays_per_minute")]
+    pub max_new_relays_per_minute: usize,
+    /// How many invalid descriptors a neighbor may gossip to us before we disconnect it. It is refused until it has gone ten minutes without a new strike.
+    #[serde(default = "default_max_strikes")]
+    pub max_strikes: u32,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            max_adjacencies_per_round: default_max_adjacencies_per_round(),
+            max_new_relays_per_minute: default_max_new_relays_per_minute(),
+            max_strikes: default_max_strikes(),
+        }
+    }
+}
+
+fn default_max_adjacencies_per_round() -> usize {
+    100
+}
+
+fn default_max_new_relays_per_minute() -> usize {
+    60
+}
+
+fn default_max_strikes() -> u32 {
+    3
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct AutoSettle {
     /// number of seconds in between settlements
     pub interval: u64,
 }
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BootstrapSnapshotConfig {
+    pub path: PathBuf,
+    /// If set, the snapshot is only accepted if this relay signed it
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub signer: Option<RelayFingerprint>,
+}
+
+impl BootstrapSnapshotConfig {
+    /// Reads the snapshot, checking who signed it if asked to.
+    pub fn read(&self) -> anyhow::Result<GraphSnapshot> {
+        let bts = std::fs::read(&self.path)
+            .with_context(|| format!("cannot read snapshot {}", self.path.display()))?;
+        let snapshot: GraphSnapshot = stdcode::deserialize(&bts)
+            .with_context(|| format!("snapshot {} is malformed", self.path.display()))?;
+        if let Some(signer) = self.signer {
+            anyhow::ensure!(
+                snapshot.signer.fingerprint() == signer,
+                "snapshot {} was signed by {}, not {}",
+                self.path.display(),
+                snapshot.signer.fingerprint(),
+                signer
+            );
+        }
+        Ok(snapshot)
+    }
+}
@@ -1,5 +1,4 @@
 use earendil_crypt::{ClientId, RelayIdentitySecret};
-use earendil_packet::crypt::DhSecret;
 use earendil_topology::RelayGraph;
 
 use parking_lot::RwLock;
@@ -9,6 +8,8 @@ use crate::{
     config::ConfigFile,
     db::{db_read, db_write},
     debts::Debts,
+    network::{persist_replay_guard, PKT_REPLAY_GUARD},
+    onion_keys::OnionKeys,
 };
 
 pub type DaemonContext = anyctx::AnyCtx<ConfigFile>;
@@ -22,24 +23,47 @@ pub static MY_RELAY_IDENTITY: CtxField<Option<RelayIdentitySecret>> = |ctx| {
     })
 };
 
-pub static MY_RELAY_ONION_SK: CtxField<DhSecret> = |_| DhSecret::generate();
-pub static RELAY_GRAPH: CtxField<RwLock<RelayGraph>> = |ctx| {
-    let ctx = ctx.clone();
-    smol::future::block_on(async move {
-        match db_read(&ctx, "relay_graph")
+pub static MY_RELAY_ONION_KEYS: CtxField<RwLock<OnionKeys>> = |ctx| {
+    smol::future::block_on(async {
+        let lifetime = ctx.init().onion_key_rotation;
+        // without the replay filter that guarded them, packets seen before the restart could be replayed, so we need fresh keys
+        let guard_persisted = ctx.get(PKT_REPLAY_GUARD).1;
+        match db_read(ctx, "onion_keys")
             .await
             .ok()
             .flatten()
-            .and_then(|s| stdcode::deserialize(&s).ok())
+            .filter(|_| guard_persisted)
+            .and_then(|bts| OnionKeys::from_bytes(&bts, lifetime).ok())
         {
-            Some(g) => RwLock::new(g),
+            Some(keys) => {
+                tracing::debug!("retrieved persisted onion keys");
+                RwLock::new(keys)
+            }
             None => {
-                tracing::debug!("**** INIT RELAY GRAPH****");
-                RwLock::new(RelayGraph::new())
+                let keys = OnionKeys::new(lifetime);
+                if let Err(e) = persist_replay_guard(ctx, true).await {
+                    tracing::warn!("error saving replay filter: {e}");
+                }
+                if let Err(e) = db_write(ctx, "onion_keys", keys.to_bytes()).await {
+                    tracing::warn!("error saving onion keys: {e}");
+                }
+                RwLock::new(keys)
             }
         }
     })
 };
+pub static RELAY_GRAPH: CtxField<RwLock<RelayGraph>> = |ctx| {
+    let mut graph = smol::future::block_on(db_read(ctx, "relay_graph"))
+        .ok()
+        .flatten()
+        .and_then(|s| stdcode::deserialize(&s).ok())
+        .unwrap_or_else(|| {
+            tracing::debug!("**** INIT RELAY GRAPH****");
+            RelayGraph::new()
+        });
+    graph.set_limits(ctx.init().relay_graph.limits());
+    RwLock::new(graph)
+};
 
 pub static DEBTS: CtxField<Debts> = |ctx| {
     smol::future::block_on(async move {
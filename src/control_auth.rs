@@ -0,0 +1,443 @@
+//! Authentication for the local control protocol.
+//!
+//! The HTTP transport has no notion of headers, so the token travels inside every JSON-RPC request instead, as a `{"control_token": ...}` object prepended to the parameters. [AuthRpcTransport] adds it on the client side, and [ControlAuthService] checks and strips it on the daemon side.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use nanorpc::{JrpcError, JrpcRequest, JrpcResponse, RpcService, RpcTransport, ServerError};
+use serde_json::json;
+
+use crate::config::ControlScope;
+
+const TOKEN_KEY: &str = "control_token";
+
+/// The JSON-RPC error code of calls refused for lack of a good enough token.
+const AUTH_ERROR_CODE: i64 = -32001;
+
+/// Verbs that only look at the daemon's state. Everything else needs a full-access token.
+const READ_ONLY_VERBS: &[&str] = &[
+    "havens_info",
+    "forwards_info",
+    "relay_graphviz",
+    "my_routes",
+    "get_rendezvous",
+    "list_neighbors",
+    "link_status",
+    "list_chats",
+    "get_chat",
+    "list_settlements",
+    "settlement_history",
+    "list_debts",
+    "replay_filter_info",
+    "mix_queue_info",
+    "relay_reliability",
+    "relay_graph",
+    "relay_path",
+    "relay_graph_snapshot",
+    "next_events",
+];
+
+/// Where the daemon writes its cookie unless configured otherwise, and where clients look for one.
+pub fn default_cookie_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".earendil/control_cookie"))
+}
+
+/// Reads a token from a cookie file. Without an explicit path, the default one is tried, and it not existing just means there is no token.
+pub fn read_cookie(path: Option<&Path>) -> anyhow::Result<Option<String>> {
+    let (path, explicit) = match path {
+        Some(path) => (path.to_owned(), true),
+        None => match default_cookie_path() {
+            Some(path) => (path, false),
+            None => return Ok(None),
+        },
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(token) => Ok(Some(token.trim().to_string())),
+        Err(err) if !explicit && err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("cannot read cookie {}", path.display())),
+    }
+}
+
+/// Writes a token to a cookie file that only the current user can read.
+pub fn write_cookie(path: &Path, token: &str) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("cannot create directory {}", dir.display()))?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("cannot write cookie {}", path.display()))?;
+    // the mode above only applies to files we create, so tighten an existing cookie's permissions too
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))
+        .with_context(|| format!("cannot restrict permissions of cookie {}", path.display()))?;
+    std::io::Write::write_all(&mut file, token.as_bytes())?;
+    Ok(())
+}
+
+/// The tokens that the daemon accepts, and what each of them allows.
+pub struct ControlAuth {
+    // hashes compare in constant time, unlike the tokens themselves
+    tokens: Vec<(blake3::Hash, ControlScope)>,
+}
+
+impl ControlAuth {
+    pub fn new(tokens: impl IntoIterator<Item = (String, ControlScope)>) -> Self {
+        Self {
+            tokens: tokens
+                .into_iter()
+                .map(|(token, scope)| (blake3::hash(token.as_bytes()), scope))
+                .collect(),
+        }
+    }
+
+    /// Returns what the token allows, or None if it is not a token we know.
+    pub fn scope(&self, token: &str) -> Option<ControlScope> {
+        let hash = blake3::hash(token.as_bytes());
+        self.tokens
+            .iter()
+            .filter(|(known, _)| *known == hash)
+            .map(|(_, scope)| *scope)
+            .max()
+    }
+}
+
+/// Wraps a control protocol service, refusing calls that don't carry a token with enough scope. Without a [ControlAuth], every call is allowed.
+pub struct ControlAuthService<S> {
+    inner: Arc<S>,
+    auth: Option<Arc<ControlAuth>>,
+}
+
+impl<S> Clone for ControlAuthService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            auth: self.auth.clone(),
+        }
+    }
+}
+
+impl<S: RpcService> ControlAuthService<S> {
+    pub fn new(inner: S, auth: Option<ControlAuth>) -> Self {
+        Self {
+            inner: inner.into(),
+            auth: auth.map(Arc::new),
+        }
+    }
+
+    /// Strips the token from the parameters, then checks that it allows calling the method. The token is stripped even when unneeded, so that clients can always send one.
+    fn authorize(&self, method: &str, params: &mut Vec<serde_json::Value>) -> Result<(), String> {
+        let token = match params.first().and_then(|first| first.get(TOKEN_KEY)) {
+            Some(token) => {
+                let token = token.as_str().unwrap_or_default().to_string();
+                params.remove(0);
+                Some(token)
+            }
+            None => None,
+        };
+        let Some(auth) = self.auth.as_ref() else {
+            return Ok(());
+        };
+        match token.and_then(|token| auth.scope(&token)) {
+            Some(ControlScope::Full) => Ok(()),
+            Some(ControlScope::ReadOnly) if READ_ONLY_VERBS.contains(&method) => Ok(()),
+            Some(ControlScope::ReadOnly) => {
+                Err(format!("{method} needs a full-access control token"))
+            }
+            None => Err("missing or unknown control token".into()),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: RpcService> RpcService for ControlAuthService<S> {
+    async fn respond(
+        &self,
+        method: &str,
+        mut params: Vec<serde_json::Value>,
+    ) -> Option<Result<serde_json::Value, ServerError>> {
+        if let Err(message) = self.authorize(method, &mut params) {
+            return Some(Err(ServerError {
+                code: AUTH_ERROR_CODE as u32,
+                message,
+                details: serde_json::Value::Null,
+            }));
+        }
+        self.inner.respond(method, params).await
+    }
+
+    // overridden to give refusals their own error code, which clients turn into readable errors
+    async fn respond_raw(&self, mut req: JrpcRequest) -> JrpcResponse {
+        match self.authorize(&req.method, &mut req.params) {
+            Ok(()) => self.inner.respond_raw(req).await,
+            Err(message) => JrpcResponse {
+                jsonrpc: "2.0".into(),
+                result: None,
+                error: Some(JrpcError {
+                    code: AUTH_ERROR_CODE,
+                    message,
+                    data: serde_json::Value::Null,
+                }),
+                id: req.id,
+            },
+        }
+    }
+}
+
+/// Wraps a transport to the control protocol, adding the token to every call.
+pub struct AuthRpcTransport<T> {
+    inner: T,
+    token: Option<String>,
+}
+
+impl<T: RpcTransport> AuthRpcTransport<T> {
+    pub fn new(inner: T, token: Option<String>) -> Self {
+        Self { inner, token }
+    }
+}
+
+#[async_trait]
+impl<T: RpcTransport> RpcTransport for AuthRpcTransport<T>
+where
+    T::Error: Into<anyhow::Error>,
+{
+    type Error = anyhow::Error;
+
+    async fn call_raw(&self, mut req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+        if let Some(token) = self.token.as_ref() {
+            req.params.insert(0, json!({ TOKEN_KEY: token }));
+        }
+        let resp = self.inner.call_raw(req).await.map_err(Into::into)?;
+        if let Some(err) = resp
+            .error
+            .as_ref()
+            .filter(|err| err.code == AUTH_ERROR_CODE)
+        {
+            anyhow::bail!("the daemon refused the call: {}", err.message);
+        }
+        Ok(resp)
+    }
+}
+
+/// A transport to the control protocol over a Unix domain socket, with one JSON-RPC request or response per line.
+pub struct UnixRpcTransport {
+    path: PathBuf,
+}
+
+impl UnixRpcTransport {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait]
+impl RpcTransport for UnixRpcTransport {
+    type Error = anyhow::Error;
+
+    #[cfg(unix)]
+    async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+        use smol::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let mut conn = smol::net::unix::UnixStream::connect(&self.path)
+            .await
+            .with_context(|| format!("cannot connect to {}", self.path.display()))?;
+        conn.write_all(format!("{}\n", serde_json::to_string(&req)?).as_bytes())
+            .await?;
+        let mut line = String::new();
+        BufReader::new(conn).read_line(&mut line).await?;
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    #[cfg(not(unix))]
+    async fn call_raw(&self, _req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+        anyhow::bail!("Unix domain sockets are not supported on this platform")
+    }
+}
+
+/// Serves a service over a Unix domain socket at the given path, replacing whatever socket was left there before. The socket is only accessible to the current user.
+#[cfg(unix)]
+pub async fn serve_unix(path: &Path, service: impl RpcService + Clone) -> anyhow::Result<()> {
+    use smol::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            anyhow::bail!(
+                "refusing to replace {}, which is not a socket",
+                path.display()
+            )
+        }
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    // bind inside a directory only we can enter, so nobody can connect before the socket is locked down, then move it into place
+    let parent = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let staging = parent.join(format!(".earendil-control-{:016x}", rand::random::<u64>()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_context(|| format!("cannot create {}", staging.display()))?;
+    let staged = staging.join("control.sock");
+    let bound = smol::net::unix::UnixListener::bind(&staged)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&staged, path)?;
+            Ok(listener)
+        });
+    let _ = std::fs::remove_dir_all(&staging);
+    let listener = bound.with_context(|| format!("cannot bind to {}", path.display()))?;
+    loop {
+        let (conn, _) = listener.accept().await?;
+        let service = service.clone();
+        smolscale::spawn(async move {
+            let mut read = BufReader::new(conn.clone());
+            let mut write = conn;
+            loop {
+                let mut line = String::new();
+                if read.read_line(&mut line).await? == 0 {
+                    return anyhow::Ok(());
+                }
+                let req: JrpcRequest = serde_json::from_str(&line)?;
+                let resp = service.respond_raw(req).await;
+                write
+                    .write_all(format!("{}\n", serde_json::to_string(&resp)?).as_bytes())
+                    .await?;
+            }
+        })
+        .detach();
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn serve_unix(_path: &Path, _service: impl RpcService + Clone) -> anyhow::Result<()> {
+    anyhow::bail!("Unix domain sockets are not supported on this platform")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct Echo;
+
+    #[async_trait]
+    impl RpcService for Echo {
+        async fn respond(
+            &self,
+            method: &str,
+            params: Vec<serde_json::Value>,
+        ) -> Option<Result<serde_json::Value, ServerError>> {
+            Some(Ok(json!([method, params])))
+        }
+    }
+
+    fn call(
+        service: &ControlAuthService<Echo>,
+        token: Option<&str>,
+        method: &str,
+    ) -> Result<serde_json::Value, ServerError> {
+        let mut params = vec![json!(1)];
+        if let Some(token) = token {
+            params.insert(0, json!({ TOKEN_KEY: token }));
+        }
+        smol::future::block_on(service.respond(method, params)).unwrap()
+    }
+
+    #[test]
+    fn tokens_are_scoped() {
+        let auth = ControlAuth::new([
+            ("reader".to_string(), ControlScope::ReadOnly),
+            ("admin".to_string(), ControlScope::Full),
+        ]);
+        let service = ControlAuthService::new(Echo, Some(auth));
+
+        assert!(call(&service, None, "list_chats").is_err());
+        assert!(call(&service, Some("wrong"), "list_chats").is_err());
+        // the token never reaches the inner service
+        assert_eq!(
+            call(&service, Some("reader"), "list_chats").unwrap(),
+            json!(["list_chats", [1]])
+        );
+        assert!(call(&service, Some("reader"), "send_chat").is_err());
+        assert!(call(&service, Some("admin"), "send_chat").is_ok());
+
+        // refusals get their own error code on the wire
+        let req = JrpcRequest {
+            jsonrpc: "2.0".into(),
+            method: "send_chat".into(),
+            params: vec![json!({ TOKEN_KEY: "reader" })],
+            id: nanorpc::JrpcId::Number(1),
+        };
+        let resp = smol::future::block_on(service.respond_raw(req));
+        assert_eq!(resp.error.unwrap().code, AUTH_ERROR_CODE);
+
+        // without auth, everything goes, with or without a token
+        let open = ControlAuthService::new(Echo, None);
+        assert!(call(&open, None, "send_chat").is_ok());
+        assert_eq!(
+            call(&open, Some("whatever"), "send_chat").unwrap(),
+            json!(["send_chat", [1]])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_is_private_and_replaces_only_sockets() {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let path =
+            std::env::temp_dir().join(format!("earendil-test-{:016x}.sock", rand::random::<u64>()));
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(smol::future::block_on(serve_unix(&path, Echo)).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+
+        for _ in 0..2 {
+            let server = smolscale::spawn({
+                let path = path.clone();
+                async move { serve_unix(&path, Echo).await }
+            });
+            let meta = loop {
+                match std::fs::symlink_metadata(&path) {
+                    Ok(meta) if meta.file_type().is_socket() => break meta,
+                    _ => std::thread::sleep(std::time::Duration::from_millis(10)),
+                }
+            };
+            assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+            smol::future::block_on(server.cancel());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cookie_is_private_even_if_it_existed() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!(
+            "earendil-test-{:016x}.cookie",
+            rand::random::<u64>()
+        ));
+        std::fs::write(&path, "old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_cookie(&path, "new").unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert_eq!(read_cookie(Some(&path)).unwrap().as_deref(), Some("new"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,7 +1,11 @@
 use crate::{
-    commands::{ChatCommand, ControlCommand},
+    commands::{ChatCommand, ControlCommand, SettleCommand},
+    control_auth::{AuthRpcTransport, UnixRpcTransport},
     daemon::ChatEntry,
+    debts::PriceInfo,
     haven::HavenLocator,
+    settlement::SettlementRecord,
+    HavenEndpoint,
 };
 use anyhow::Context;
 use async_trait::async_trait;
@@ -12,24 +16,52 @@ use earendil_crypt::{
     AnonEndpoint, ClientId, HavenFingerprint, HavenIdentitySecret, RelayFingerprint,
 };
 use earendil_packet::{crypt::DhPublic, PacketConstructError};
+use earendil_topology::GraphSnapshot;
 use either::Either;
+use itertools::Itertools;
 use nanorpc::nanorpc_derive;
 use nanorpc_http::client::HttpRpcTransport;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use smol::Timer;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use std::{io::Write, marker::Send};
 use std::{net::SocketAddr, str::FromStr};
+use stdcode::StdcodeSerializeExt;
 use thiserror::Error;
 
+/// Where and how to reach the control protocol of a running daemon.
+pub struct ControlConnection {
+    /// Connects over HTTP unless `unix` is given
+    pub connect: SocketAddr,
+    pub unix: Option<PathBuf>,
+    /// Sent with every call, for daemons that require one
+    pub token: Option<String>,
+}
+
+impl ControlConnection {
+    pub fn client(self) -> ControlClient {
+        match self.unix {
+            Some(path) => ControlClient::from(AuthRpcTransport::new(
+                UnixRpcTransport::new(path),
+                self.token,
+            )),
+            None => ControlClient::from(AuthRpcTransport::new(
+                HttpRpcTransport::new(self.connect),
+                self.token,
+            )),
+        }
+    }
+}
+
 pub async fn main_control(
     control_command: ControlCommand,
-    connect: SocketAddr,
+    connection: ControlConnection,
 ) -> anyhow::Result<()> {
-    let control = ControlClient::from(HttpRpcTransport::new(connect));
+    let control = connection.client();
     match control_command {
         ControlCommand::GlobalRpc {
             id,
@@ -74,6 +106,105 @@ pub async fn main_control(
             let res = control.relay_graphviz().await?;
             println!("{res}");
         }
+        ControlCommand::RelayGraph { around, hops, json } => {
+            let info = control.relay_graph(around, hops).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                println!("relays:");
+                for node in info.nodes.iter().sorted_by_key(|node| node.fingerprint) {
+                    println!(
+                        "  {} {}{}",
+                        node.fingerprint,
+                        pretty_time(unix_time(node.unix_timestamp)),
+                        if node.is_neighbor { " (neighbor)" } else { "" }
+                    );
+                    println!("      onion key: {}", node.onion_pk);
+                }
+                println!("links:");
+                for edge in info.edges.iter() {
+                    println!(
+                        "  {} -- {} {}",
+                        edge.left,
+                        edge.right,
+                        pretty_time(unix_time(edge.unix_timestamp))
+                    );
+                }
+            }
+        }
+        ControlCommand::RelayPath { from, to, json } => {
+            let path = control.relay_path(from, to).await?;
+            if json {
+                let path: Option<Vec<String>> =
+                    path.map(|path| path.iter().map(|fp| fp.to_string()).collect());
+                println!("{}", serde_json::to_string_pretty(&path)?);
+            } else {
+                match path {
+                    Some(path) => {
+                        for fp in path {
+                            println!("{fp}");
+                        }
+                    }
+                    None => println!("no path from {from} to {to}"),
+                }
+            }
+        }
+        ControlCommand::ExportGraph { out } => {
+            let snapshot = control.relay_graph_snapshot().await?;
+            std::fs::write(&out, snapshot.stdcode())
+                .with_context(|| format!("cannot write snapshot to {}", out.display()))?;
+            println!(
+                "wrote {} relays and {} adjacencies, signed by {}, to {}",
+                snapshot.identities.len(),
+                snapshot.adjacencies.len(),
+                snapshot.signer.fingerprint(),
+                out.display()
+            );
+        }
+        ControlCommand::Events { json } => {
+            let mut from = None;
+            loop {
+                let batch = control.next_events(from, 20).await?;
+                if batch.missed > 0 {
+                    eprintln!("missed {} events", batch.missed);
+                }
+                for record in batch.events {
+                    if json {
+                        println!("{}", serde_json::to_string(&record)?);
+                    } else {
+                        println!(
+                            "{} {}",
+                            create_timestamp(unix_time(record.unix_timestamp)),
+                            describe_event(&record.event)
+                        );
+                    }
+                }
+                from = Some(batch.next);
+            }
+        }
+        ControlCommand::ReloadConfig => {
+            let report = control.reload_config().await??;
+            for (verb, tasks) in [
+                ("started", &report.started),
+                ("stopped", &report.stopped),
+                ("restarted", &report.restarted),
+                ("failed to start", &report.failed),
+            ] {
+                for task in tasks {
+                    println!("{verb} {task}");
+                }
+            }
+            if report.started.is_empty() && report.stopped.is_empty() && report.restarted.is_empty()
+            {
+                println!("nothing to start or stop");
+            }
+            if !report.needs_restart.is_empty() {
+                println!(
+                    "changes to {} only take effect after a restart",
+                    report.needs_restart.join(", ")
+                );
+            }
+        }
         ControlCommand::MyRoutes => {
             let routes = control.my_routes().await?;
             println!("{}", serde_yaml::to_string(&routes)?);
@@ -83,6 +214,48 @@ pub async fn main_control(
                 println!("{} - {}", info.0, info.1);
             }
         }
+        ControlCommand::ForwardsInfo => {
+            for info in control.forwards_info().await? {
+                println!(
+                    "{} {} -> {} (active: {}, total: {})",
+                    info.protocol, info.listen, info.remote, info.active_conns, info.total_conns
+                );
+                if let Some(err) = info.last_error {
+                    println!("    last error: {err}");
+                }
+            }
+        }
+        ControlCommand::LinkStatus { json } => {
+            let links = control.link_status().await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&links)?);
+            } else {
+                let divider = "+-------------+-----------------------+-----------+-------------------------------------+----------+----------+----------+---------------------+---------------------+-------+";
+                println!("{divider}");
+                println!("| Route       | Remote address        | Obfs      | Neighbor                            | Uptime   | Version  | RTT      | In (packets/bytes)  | Out (packets/bytes) | Queue |");
+                println!("{divider}");
+                let truncate = |s: &str, len: usize| s.chars().take(len).collect::<String>();
+                for info in links {
+                    let kind = if info.is_relay { "relay" } else { "client" };
+                    let neigh = format!("{kind} {}", truncate(&info.neighbor, 28));
+                    println!(
+                        "| {:<11} | {:<21} | {:<9} | {:<35} | {:<8} | {:<8} | {:<8} | {:<19} | {:<19} | {:<5} |",
+                        truncate(&info.route, 11),
+                        info.remote_addr.as_deref().unwrap_or("-"),
+                        info.obfs,
+                        neigh,
+                        pretty_duration(info.uptime_secs),
+                        truncate(info.version.as_deref().unwrap_or("-"), 8),
+                        info.rtt_ms
+                            .map_or("-".to_owned(), |rtt| format!("{rtt:.1}ms")),
+                        format!("{}/{}", info.packets_in, info.bytes_in),
+                        format!("{}/{}", info.packets_out, info.bytes_out),
+                        info.queue_depth
+                    );
+                }
+                println!("{divider}");
+            }
+        }
         ControlCommand::Chat { chat_command } => match chat_command {
             ChatCommand::List => {
                 let divider = "+-------------------------------------+---------------+-----------------------------------+";
@@ -113,31 +286,36 @@ pub async fn main_control(
                 }
             }
             ChatCommand::Start { neighbor } => {
-                let mut displayed: HashSet<(bool, String, SystemTime)> = HashSet::new();
+                // subscribe before printing the history, so that nothing arriving in between is lost
+                let mut from = control.next_events(None, 0).await?.next;
+                for (is_mine, text, time) in control.get_chat(neighbor.clone()).await?? {
+                    println!("{}", pretty_entry(is_mine, text, time));
+                }
                 let control = Arc::new(control);
                 let control_clone = control.clone();
                 let neighbor_clone = neighbor.clone();
 
                 let _listen_loop = smolscale::spawn(async move {
                     loop {
-                        let msgs = match control.get_chat(neighbor.clone()).await {
-                            Ok(Ok(msgs)) => msgs,
-                            Ok(Err(e)) => {
-                                println!("error fetching messages: {:?}", e);
-                                Timer::after(Duration::from_secs(1)).await;
-                                continue;
-                            }
+                        let batch = match control.next_events(Some(from), 20).await {
+                            Ok(batch) => batch,
                             Err(control_err) => {
                                 println!("control protocol error: {:?}", control_err);
                                 Timer::after(Duration::from_secs(1)).await;
                                 continue;
                             }
                         };
-                        for (is_mine, text, time) in msgs {
-                            let msg = (is_mine, text.clone(), time);
-                            if !displayed.contains(&msg) {
-                                println!("{}", pretty_entry(is_mine, text, time));
-                                displayed.insert(msg);
+                        from = batch.next;
+                        for record in batch.events {
+                            if let DaemonEvent::ChatReceived {
+                                neighbor: src,
+                                text,
+                            } = record.event
+                            {
+                                if src.starts_with(&neighbor) {
+                                    let time = unix_time(record.unix_timestamp);
+                                    println!("{}", pretty_entry(false, text, time));
+                                }
                             }
                         }
                     }
@@ -157,8 +335,11 @@ pub async fn main_control(
 
                     if !message.is_empty() {
                         let msg = message.to_string();
-                        match control_clone.send_chat(neighbor_clone.clone(), msg).await? {
-                            Ok(_) => continue,
+                        match control_clone
+                            .send_chat(neighbor_clone.clone(), msg.clone())
+                            .await?
+                        {
+                            Ok(_) => println!("{}", pretty_entry(true, msg, SystemTime::now())),
                             Err(e) => println!("ERROR: {e}"),
                         }
                     }
@@ -174,6 +355,144 @@ pub async fn main_control(
                 control.send_chat(dest, msg).await??;
             }
         },
+        ControlCommand::ReplayFilterInfo => {
+            let info = control.replay_filter_info().await?;
+            println!("remembered packets: {}", info.remembered);
+            println!("rejected replays: {}", info.rejected);
+            println!(
+                "onion keys rotated early because the filter filled up: {}",
+                info.early_rotations
+            );
+            println!("false positive rate: {:e}", info.false_positive_rate);
+            println!("memory: {} KiB", info.memory_bytes / 1024);
+        }
+        ControlCommand::MixQueueInfo => {
+            let info = control.mix_queue_info().await?;
+            println!("queued packets: {}/{}", info.depth, info.max_depth);
+            println!("emitted: {}", info.emitted);
+            println!("dropped on overflow: {}", info.overflowed);
+            println!("dropped after delay: {}", info.dropped);
+        }
+        ControlCommand::RelayReliability => {
+            let divider = "+------------------------------------------------------------------+----------+----------+----------+";
+            println!("{divider}");
+            println!("| Relay                                                            | Sent     | Returned | Lost     |");
+            println!("{divider}");
+            for info in control
+                .relay_reliability()
+                .await?
+                .into_iter()
+                .sorted_by_key(|info| std::cmp::Reverse(info.loops_lost))
+            {
+                println!(
+                    "| {:<64} | {:<8} | {:<8} | {:<8} |",
+                    info.relay, info.loops_sent, info.loops_returned, info.loops_lost
+                );
+            }
+            println!("{divider}");
+        }
+        ControlCommand::Debts { neighbor } => {
+            let divider = "+-------------------------------------+----------+-----------+--------------+--------------+--------------+";
+            println!("{divider}");
+            println!("| Neighbor                            | In price | Out price | Owed to us   | Owed by us   | Net debt     |");
+            println!("{divider}");
+            let prefix = neighbor.unwrap_or_default();
+            let debts = control
+                .list_debts()
+                .await?
+                .into_iter()
+                .filter(|info| info.neighbor.to_string().starts_with(&prefix))
+                .sorted_by_key(|info| info.neighbor.to_string());
+            for info in debts {
+                let neigh = info.neighbor.to_string();
+                let neigh = if neigh.len() > 32 {
+                    neigh[..32].to_owned() + "..."
+                } else {
+                    neigh
+                };
+                let fmt_price = |price: &Option<PriceInfo>| {
+                    price
+                        .as_ref()
+                        .map_or("-".to_owned(), |price| price.price.to_string())
+                };
+                let net_debt = match &info.incoming_price {
+                    Some(price) if info.net_debt > price.debt_limit as i128 => {
+                        format!("{} (over limit {})", info.net_debt, price.debt_limit)
+                    }
+                    _ => info.net_debt.to_string(),
+                };
+                println!(
+                    "| {:<35} | {:<8} | {:<9} | {:<12} | {:<12} | {:<12} |",
+                    neigh,
+                    fmt_price(&info.incoming_price),
+                    fmt_price(&info.outgoing_price),
+                    info.incoming_balance,
+                    info.outgoing_balance,
+                    net_debt
+                );
+                println!("{divider}");
+            }
+            if !prefix.is_empty() {
+                for record in control
+                    .settlement_history()
+                    .await?
+                    .into_iter()
+                    .filter(|record| record.neighbor.to_string().starts_with(&prefix))
+                {
+                    println!(
+                        "{} settled {} micromel (net debt after: {}) {}",
+                        if record.is_incoming {
+                            left_arrow()
+                        } else {
+                            right_arrow()
+                        },
+                        record.response.request.decrease(),
+                        record.response.current_debt,
+                        pretty_time(record.time)
+                    );
+                }
+            }
+        }
+        ControlCommand::Settle { settle_command } => match settle_command {
+            SettleCommand::Start { neighbor, amount } => {
+                println!("waiting for {neighbor} to answer...");
+                let record = control.start_settlement(neighbor, amount).await??;
+                println!(
+                    "settled {} micromel with {}; they now report a net debt of {} micromel",
+                    record.response.request.decrease(),
+                    record.neighbor,
+                    record.response.current_debt
+                );
+            }
+            SettleCommand::List => {
+                for (neighbor, amount) in control.list_settlements().await? {
+                    println!("{neighbor} wants to settle {amount} micromel");
+                }
+            }
+            SettleCommand::Accept { neighbor } => {
+                control.accept_settlement(neighbor).await??;
+            }
+            SettleCommand::Reject { neighbor } => {
+                control.reject_settlement(neighbor).await??;
+            }
+            SettleCommand::History => {
+                for record in control.settlement_history().await? {
+                    let arrow = if record.is_incoming {
+                        left_arrow()
+                    } else {
+                        right_arrow()
+                    };
+                    println!(
+                        "{} {} {} micromel (net debt after: {}) {}",
+                        arrow,
+                        record.neighbor,
+                        record.response.request.decrease(),
+                        record.response.current_debt,
+                        pretty_time(record.time)
+                    );
+                }
+            }
+        },
     }
     Ok(())
 }
@@ -202,6 +521,55 @@ fn pretty_entry(is_mine: bool, text: String, time: SystemTime) -> String {
     format!("{} {} {}", arrow, text, pretty_time(time))
 }
 
+fn describe_event(event: &DaemonEvent) -> String {
+    let kind = |is_relay: bool| if is_relay { "relay" } else { "client" };
+    match event {
+        DaemonEvent::NeighborUp {
+            neighbor,
+            is_relay,
+            route,
+        } => format!("{} {neighbor} linked up via {route}", kind(*is_relay)),
+        DaemonEvent::NeighborDown {
+            neighbor,
+            is_relay,
+            route,
+        } => format!("{} {neighbor} on {route} went down", kind(*is_relay)),
+        DaemonEvent::ChatReceived { neighbor, text } => format!("chat from {neighbor}: {text}"),
+        DaemonEvent::HavenConnectionAccepted { haven, port } => {
+            format!("haven {haven}:{port} accepted a connection")
+        }
+        DaemonEvent::DhtPublish {
+            haven, error: None, ..
+        } => format!("published haven {haven} to the DHT"),
+        DaemonEvent::DhtPublish {
+            haven,
+            error: Some(err),
+            ..
+        } => format!("failed to publish haven {haven} to the DHT: {err}"),
+        DaemonEvent::SettlementRequested { neighbor, amount } => {
+            format!("{neighbor} asks us to accept a settlement of {amount} micromel")
+        }
+        DaemonEvent::RelayGraphChanged {
+            relays,
+            adjacencies,
+        } => format!("relay graph changed: {relays} relays, {adjacencies} adjacencies"),
+    }
+}
+
+fn pretty_duration(secs: u64) -> String {
+    if secs < 60 {
+        format!("{secs}s")
+    } else if secs < 60 * 60 {
+        format!("{}m{}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{}m", secs / (60 * 60), secs / 60 % 60)
+    }
+}
+
+fn unix_time(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
 fn pretty_time(time: SystemTime) -> ColoredString {
     let datetime: DateTime<Utc> = time.into();
 
@@ -213,6 +581,8 @@ fn pretty_time(time: SystemTime) -> ColoredString {
 pub trait ControlProtocol {
     async fn havens_info(&self) -> Result<Vec<(String, String)>, ConfigError>;
 
+    async fn forwards_info(&self) -> Vec<ForwardInfo>;
+
     async fn send_global_rpc(
         &self,
         args: GlobalRpcArgs,
@@ -231,12 +601,67 @@ pub trait ControlProtocol {
 
     async fn list_neighbors(&self) -> Vec<Either<ClientId, RelayFingerprint>>;
 
+    /// Returns the status and traffic statistics of every live link to a neighbor.
+    async fn link_status(&self) -> Vec<LinkInfo>;
+
     async fn list_chats(&self) -> HashMap<String, (Option<ChatEntry>, u32)>;
 
     // true = outgoing, false = incoming
     async fn get_chat(&self, src: String) -> Result<Vec<(bool, String, SystemTime)>, ChatError>;
 
     async fn send_chat(&self, dest: String, msg: String) -> Result<(), ChatError>;
+
+    /// Asks a neighbor to accept a manual settlement of the given amount, in micromel, of what we owe it.
+    async fn start_settlement(
+        &self,
+        neighbor: String,
+        amount: u64,
+    ) -> Result<SettlementRecord, SettlementError>;
+
+    /// Lists the incoming settlement requests waiting for our answer, as (neighbor, amount) pairs.
+    async fn list_settlements(&self) -> Vec<(String, u64)>;
+
+    async fn accept_settlement(&self, neighbor: String) -> Result<(), SettlementError>;
+
+    async fn reject_settlement(&self, neighbor: String) -> Result<(), SettlementError>;
+
+    /// Returns every completed settlement, in both directions.
+    async fn settlement_history(&self) -> Vec<SettlementRecord>;
+
+    /// Returns the prices and balances of every neighbor we have ever priced or metered.
+    async fn list_debts(&self) -> Vec<DebtInfo>;
+
+    /// Returns statistics about the filter that drops replayed packets.
+    async fn replay_filter_info(&self) -> ReplayFilterInfo;
+
+    /// Returns statistics about the queue that holds packets for their mix delays.
+    async fn mix_queue_info(&self) -> MixQueueInfo;
+
+    /// Returns what became of the cover traffic loop packets routed through each relay.
+    async fn relay_reliability(&self) -> Vec<RelayReliability>;
+
+    /// Returns the relay graph as structured data. If either `around` or `hops` is given, only relays within `hops` hops (1 by default) of `around` (us by default) are included.
+    async fn relay_graph(
+        &self,
+        around: Option<RelayFingerprint>,
+        hops: Option<usize>,
+    ) -> RelayGraphInfo;
+
+    /// Returns a path with the fewest hops between two relays, including both ends, if there is one.
+    async fn relay_path(
+        &self,
+        from: RelayFingerprint,
+        to: RelayFingerprint,
+    ) -> Option<Vec<RelayFingerprint>>;
+
+    /// Returns a signed snapshot of the relay graph, for bootstrapping other nodes with. Relays sign it with their identity, while clients, which have none, sign it with a throwaway one.
+    async fn relay_graph_snapshot(&self) -> GraphSnapshot;
+
+    /// Returns the events numbered `from` onwards, waiting up to `timeout_secs` (at most 20) for one to happen if there are none yet. Without `from`, only events that happen after the call are returned. Pass the `next` of each batch as the `from` of the following call to subscribe to every event.
+    async fn next_events(&self, from: Option<u64>, timeout_secs: u64) -> EventBatch;
+
+    /// Rereads the config file and starts, stops or restarts only the routes, havens, forwards and socks5 proxy that changed. Changing the identity is refused.
+    async fn reload_config(&self) -> Result<ReloadReport, ConfigError>;
 }
 
 #[derive(Error, Serialize, Deserialize, Debug)]
@@ -291,6 +716,206 @@ pub enum ChatError {
     Send(String),
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DebtInfo {
+    pub neighbor: Either<ClientId, RelayFingerprint>,
+    /// What we charge the neighbor per message, and how much debt we tolerate before we stop forwarding its traffic
+    pub incoming_price: Option<PriceInfo>,
+    /// What the neighbor charges us per message, and how much debt it tolerates
+    pub outgoing_price: Option<PriceInfo>,
+    /// in micromel, accrued by the neighbor sending us messages
+    pub incoming_balance: u64,
+    /// in micromel, accrued by us sending the neighbor messages
+    pub outgoing_balance: u64,
+    /// in micromel; positive if the neighbor owes us, negative if we owe the neighbor
+    pub net_debt: i128,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplayFilterInfo {
+    /// packets currently remembered by the filter
+    pub remembered: u64,
+    /// packets rejected as replays since the daemon started
+    pub rejected: u64,
+    /// how often the onion key was rotated early since the daemon started, because its part of the filter filled up
+    pub early_rotations: u64,
+    /// estimated probability that a fresh packet gets wrongly rejected
+    pub false_positive_rate: f64,
+    pub memory_bytes: u64,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayReliability {
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub relay: RelayFingerprint,
+    pub loops_sent: u64,
+    pub loops_returned: u64,
+    /// loops that did not come back in time
+    pub loops_lost: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayGraphInfo {
+    pub nodes: Vec<RelayNodeInfo>,
+    pub edges: Vec<RelayEdgeInfo>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayNodeInfo {
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub fingerprint: RelayFingerprint,
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub onion_pk: DhPublic,
+    pub onion_pk_expiry: u64,
+    /// when the relay last signed its identity descriptor
+    pub unix_timestamp: u64,
+    /// whether we are directly linked to the relay
+    pub is_neighbor: bool,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelayEdgeInfo {
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub left: RelayFingerprint,
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub right: RelayFingerprint,
+    /// when both ends last signed the adjacency descriptor
+    pub unix_timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MixQueueInfo {
+    /// packets currently waiting for their mix delays
+    pub depth: u64,
+    pub max_depth: u64,
+    /// packets sent onwards since the daemon started
+    pub emitted: u64,
+    /// packets dropped because the queue was full
+    pub overflowed: u64,
+    /// packets dropped because they could not be sent onwards after their delays
+    pub dropped: u64,
+}
+
+#[derive(Error, Serialize, Deserialize, Debug)]
+pub enum SettlementError {
+    #[error("bad neighbor: {0}")]
+    Neighbor(String),
+    #[error("no pending settlement request from {0}")]
+    NoPending(String),
+    #[error("neighbor rejected the settlement")]
+    Rejected,
+    #[error("settlement failed: {0}")]
+    Failed(String),
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForwardInfo {
+    pub protocol: String,
+    pub listen: SocketAddr,
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub remote: HavenEndpoint,
+    pub active_conns: u64,
+    pub total_conns: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkInfo {
+    /// the in-route or out-route the link belongs to
+    pub route: String,
+    pub remote_addr: Option<String>,
+    pub obfs: String,
+    /// the relay fingerprint of the neighbor, or its client ID if it isn't a relay
+    pub neighbor: String,
+    pub is_relay: bool,
+    pub uptime_secs: u64,
+    /// the version the neighbor reports running, once it has answered us
+    pub version: Option<String>,
+    /// round-trip time of the latest link RPC call to the neighbor
+    pub rtt_ms: Option<f64>,
+    /// counted over the packets sent through the link, excluding link RPC traffic
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    /// messages waiting to be sent to the neighbor
+    pub queue_depth: usize,
+}
+
+/// Something that happened in the daemon, for control clients that subscribe to events.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DaemonEvent {
+    /// a link to a neighbor came up; `neighbor` is a relay fingerprint or client ID
+    NeighborUp {
+        neighbor: String,
+        is_relay: bool,
+        route: String,
+    },
+    NeighborDown {
+        neighbor: String,
+        is_relay: bool,
+        route: String,
+    },
+    ChatReceived {
+        neighbor: String,
+        text: String,
+    },
+    /// a visitor connected to one of our havens
+    HavenConnectionAccepted {
+        haven: String,
+        port: u16,
+    },
+    /// we published the locator of one of our havens to the DHT
+    DhtPublish {
+        haven: String,
+        success: bool,
+        error: Option<String>,
+    },
+    /// a neighbor asked us to accept a manual settlement, in micromel
+    SettlementRequested {
+        neighbor: String,
+        amount: u64,
+    },
+    /// the relay graph gained or lost relays or adjacencies
+    RelayGraphChanged {
+        relays: usize,
+        adjacencies: usize,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EventRecord {
+    pub seq: u64,
+    pub unix_timestamp: u64,
+    pub event: DaemonEvent,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EventBatch {
+    pub events: Vec<EventRecord>,
+    /// where the next call should continue from
+    pub next: u64,
+    /// events that were forgotten before they could be returned, because the caller fell too far behind
+    pub missed: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReloadReport {
+    pub started: Vec<String>,
+    pub stopped: Vec<String>,
+    pub restarted: Vec<String>,
+    /// tasks started by this or an earlier reload that have failed, with why. They are retried on the next reload
+    #[serde(default)]
+    pub failed: Vec<String>,
+    /// settings that changed, but only take effect once the daemon restarts
+    pub needs_restart: Vec<String>,
+}
+
 #[derive(Error, Serialize, Deserialize, Debug)]
 pub enum ConfigError {
     #[error("{0}")]
@@ -1,9 +1,14 @@
 mod control_protocol_impl;
 
+mod forward_status;
 mod inout_route;
 mod link;
+mod link_status;
+mod reload;
 mod serve_haven;
 mod socks5;
+mod tcp_forward;
+mod udp_forward;
 use async_trait::async_trait;
 use bytes::Bytes;
 use clone_macro::clone;
@@ -13,40 +18,47 @@ use earendil_packet::ForwardInstruction;
 use earendil_topology::{IdentityDescriptor, RelayGraph};
 use futures::future::Shared;
 use futures::task::noop_waker;
-use futures_util::{stream::FuturesUnordered, FutureExt, StreamExt, TryFutureExt};
+use futures_util::{FutureExt, TryFutureExt};
 use nanorpc::{JrpcRequest, JrpcResponse, RpcService, RpcTransport};
 use nanorpc_http::server::HttpRpcServer;
 
 use nursery_macro::nursery;
-use rand::distributions::Alphanumeric;
-use rand::Rng;
 use smolscale::immortal::{Immortal, RespawnStrategy};
 mod chat;
 use stdcode::StdcodeSerializeExt;
 use tracing::instrument;
 
+use anyhow::Context as _;
 use std::convert::Infallible;
 use std::task::Context;
-use std::{sync::Arc, time::Duration};
+use std::{collections::BTreeSet, path::PathBuf, sync::Arc, time::Duration};
 
 use crate::daemon::chat::CHATS;
 use crate::{
     context::MY_CLIENT_ID,
-    daemon::inout_route::{dial_out_route, listen_in_route},
     haven::rendezvous_forward_loop,
     n2r_socket::{n2r_socket_shuttle, N2rClientSocket},
 };
 use crate::{context::MY_RELAY_IDENTITY, n2r_socket::N2rRelaySocket};
 
-use crate::control_protocol::ControlClient;
+use crate::check_config::{check_config, Severity};
+use crate::control_protocol::{ControlClient, DaemonEvent};
 use crate::db::db_write;
-
+use crate::events::EVENTS;
+use crate::n2r::cover_traffic_loop;
+use crate::network::{all_relay_neighs, mix_queue_loop};
+use crate::onion_keys::onion_key_rotation_loop;
+use crate::settlement::SETTLEMENTS;
+
+use crate::control_auth::{
+    default_cookie_path, serve_unix, write_cookie, ControlAuth, ControlAuthService,
+};
 use crate::control_protocol::ControlService;
-use crate::{log_error, OutRouteConfig};
+use crate::{log_error, ControlScope, OutRouteConfig};
 
 use crate::{
     config::ConfigFile,
-    context::{MY_RELAY_ONION_SK, RELAY_GRAPH},
+    context::{CtxField, DEBTS, MY_RELAY_ONION_KEYS, RELAY_GRAPH},
     global_rpc::GLOBAL_RPC_DOCK,
 };
 use crate::{context::DaemonContext, global_rpc::server::GlobalRpcImpl};
@@ -54,6 +66,7 @@ use crate::{control_protocol::SendMessageError, global_rpc::GlobalRpcService};
 
 pub use self::chat::ChatEntry;
 use self::control_protocol_impl::ControlProtocolImpl;
+use self::reload::{run_config_tasks, CONFIG_PATH, LIVE_CONFIG};
 
 pub struct Daemon {
     pub(crate) ctx: DaemonContext,
@@ -63,26 +76,8 @@ pub struct Daemon {
 impl Daemon {
     /// Initializes the daemon and starts all background loops
     pub fn init(mut config: ConfigFile) -> anyhow::Result<Daemon> {
-        // If we are a relay, add ourselves into out_routes
-        if let Some((_k, v)) = config.in_routes.first_key_value() {
-            let my_relay_fp = config
-                .identity
-                .clone()
-                .unwrap()
-                .actualize_relay()
-                .expect("failed to initialize global identity")
-                .public()
-                .fingerprint();
-            let mut rng = rand::thread_rng();
-            let key: String = (0..20).map(|_| rng.sample(Alphanumeric) as char).collect();
-            let self_outroute_cfg = OutRouteConfig {
-                connect: v.listen.to_string(),
-                fingerprint: my_relay_fp,
-                obfs: v.obfs.clone(),
-            };
-            config.out_routes.insert(key, self_outroute_cfg);
-        }
-
+        ensure_config_valid(&config)?;
+        add_self_out_route(&mut config)?;
         let ctx = DaemonContext::new(config);
 
         tracing::info!("starting background task for main_daemon");
@@ -93,8 +88,13 @@ impl Daemon {
         })
     }
 
+    /// Remembers the file that the config was loaded from, so that the `reload_config` verb can reread it.
+    pub fn set_config_path(&self, path: PathBuf) {
+        *self.ctx.get(CONFIG_PATH).write() = Some(path);
+    }
+
     pub fn is_client(&self) -> bool {
-        self.ctx.init().in_routes.is_empty()
+        self.ctx.get(LIVE_CONFIG).read().in_routes.is_empty()
     }
 
     pub fn identity(&self) -> Option<RelayIdentitySecret> {
@@ -151,6 +151,8 @@ pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
 
     scopeguard::defer!(tracing::info!(is_client, "daemon is now DROPPED!"));
 
+    load_bootstrap_snapshots(&ctx)?;
+
     // Run the loops
     let _relay_loops = if !is_client {
         tracing::info!(
@@ -164,14 +166,30 @@ pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
         let identity_refresh_loop = Immortal::respawn(
             RespawnStrategy::Immediate,
             clone!([ctx], move || clone!([ctx], async move {
-                tracing::trace!("WE ARE INSERTING OURSELVES");
-                // first insert ourselves
-                let us = IdentityDescriptor::new(
-                    &ctx.get(MY_RELAY_IDENTITY)
-                        .expect("only relays have global identities"),
-                    ctx.get(MY_RELAY_ONION_SK),
-                );
-                ctx.get(RELAY_GRAPH).write().insert_identity(us)?;
+                // first insert ourselves, unless the descriptor we already have is still good
+                let us = {
+                    let onion_keys = ctx.get(MY_RELAY_ONION_KEYS).read();
+                    IdentityDescriptor::new(
+                        &ctx.get(MY_RELAY_IDENTITY)
+                            .expect("only relays have global identities"),
+                        onion_keys.current(),
+                        onion_keys.current_expiry(),
+                    )
+                };
+                let stale = ctx
+                    .get(RELAY_GRAPH)
+                    .read()
+                    .identity(&us.identity_pk.fingerprint())
+                    .is_none_or(|existing| {
+                        existing.onion_pk != us.onion_pk
+                            || existing.onion_pk_expiry != us.onion_pk_expiry
+                            || us.unix_timestamp.saturating_sub(existing.unix_timestamp)
+                                >= ctx.init().relay_graph.resign_interval()
+                    });
+                if stale {
+                    tracing::trace!("WE ARE INSERTING OURSELVES");
+                    ctx.get(RELAY_GRAPH).write().insert_identity(us)?;
+                }
                 smol::Timer::after(Duration::from_secs(1)).await;
                 anyhow::Ok(())
             })),
@@ -189,15 +207,53 @@ pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
                 .map_err(log_error("rendezvous_forward_loop"))),
         );
 
+        let onion_key_rotation_loop = Immortal::respawn(
+            RespawnStrategy::Immediate,
+            clone!([ctx], move || onion_key_rotation_loop(ctx.clone())
+                .map_err(log_error("onion_key_rotation_loop"))),
+        );
+
+        let mix_queue_loop = Immortal::respawn(
+            RespawnStrategy::Immediate,
+            clone!([ctx], move || mix_queue_loop(ctx.clone())
+                .map_err(log_error("mix_queue_loop"))),
+        );
+
         Some((
             identity_refresh_loop,
             global_rpc_loop,
             rendezvous_forward_loop,
+            onion_key_rotation_loop,
+            mix_queue_loop,
         ))
     } else {
         None
     };
 
+    let _cover_traffic_loop = ctx
+        .init()
+        .cover_traffic
+        .filter(|cfg| cfg.rate > 0.0)
+        .map(|cfg| {
+            Immortal::respawn(
+                RespawnStrategy::Immediate,
+                clone!([ctx], move || cover_traffic_loop(ctx.clone(), cfg)
+                    .map_err(log_error("cover_traffic_loop"))),
+            )
+        });
+
+    let _graph_prune_loop = Immortal::respawn(
+        RespawnStrategy::Immediate,
+        clone!([ctx], move || graph_prune_loop(ctx.clone())
+            .map_err(log_error("graph_prune_loop"))),
+    );
+
+    let _graph_watch_loop = Immortal::respawn(
+        RespawnStrategy::Immediate,
+        clone!([ctx], move || graph_watch_loop(ctx.clone())
+            .map_err(log_error("graph_watch_loop"))),
+    );
+
     let _state_cache_sync_loop = ctx.init().state_cache.clone().map(|_| {
         Immortal::respawn(
             RespawnStrategy::Immediate,
@@ -222,34 +278,115 @@ pub async fn main_daemon(ctx: DaemonContext) -> anyhow::Result<()> {
         anyhow::bail!("must have routes to start daemon")
     }
 
-    nursery!({
-        let mut fallible_tasks = FuturesUnordered::new();
+    // Routes, havens and the other parts of the config that can be reloaded run as separately stoppable tasks. If any of the ones we start with fails, that's fatal!
+    run_config_tasks(&ctx).await
+}
 
-        // For every in_routes block, spawn a task to handle incoming stuff
-        for (_in_route_name, config) in ctx.init().in_routes.iter() {
-            fallible_tasks.push(spawn!(listen_in_route(&ctx, config)));
-        }
+/// The name of the out-route that relays add to their config to link to themselves.
+const SELF_OUT_ROUTE: &str = "(self)";
 
-        // For every out_routes block, spawn a task to handle outgoing stuff
-        for (_out_route_name, config) in ctx.init().out_routes.iter() {
-            fallible_tasks.push(spawn!(dial_out_route(&ctx, config)));
+/// Logs the warnings about a config, failing if there are any errors.
+fn ensure_config_valid(config: &ConfigFile) -> anyhow::Result<()> {
+    let mut errors = vec![];
+    for problem in check_config(config) {
+        match problem.severity {
+            Severity::Error => errors.push(problem.to_string()),
+            Severity::Warning => tracing::warn!("config: {problem}"),
         }
+    }
+    anyhow::ensure!(errors.is_empty(), "invalid config: {}", errors.join("; "));
+    Ok(())
+}
 
-        // For every haven, serve the haven
-        for config in ctx.init().havens.iter() {
-            fallible_tasks.push(spawn!(serve_haven::serve_haven(&ctx, config)));
-        }
+/// If we are a relay, add ourselves into out_routes
+fn add_self_out_route(config: &mut ConfigFile) -> anyhow::Result<()> {
+    if let Some((_k, v)) = config.in_routes.first_key_value() {
+        let my_relay_fp = config
+            .identity
+            .as_ref()
+            .context("relays with in_routes must have an identity")?
+            .actualize_relay()
+            .context("failed to initialize global identity")?
+            .public()
+            .fingerprint();
+        let self_outroute_cfg = OutRouteConfig {
+            connect: v.listen.to_string(),
+            fingerprint: my_relay_fp,
+            obfs: v.obfs.clone(),
+            link_price: v.link_price,
+        };
+        config
+            .out_routes
+            .insert(SELF_OUT_ROUTE.to_string(), self_outroute_cfg);
+    }
+    Ok(())
+}
 
-        if let Some(socks5_cfg) = ctx.init().socks5 {
-            fallible_tasks.push(spawn!(socks5::socks5_loop(&ctx, socks5_cfg)));
-        }
+/// Seeds the relay graph with the configured bootstrap snapshots. A snapshot that can't be read or verified is fatal, since the config explicitly asked for it.
+fn load_bootstrap_snapshots(ctx: &DaemonContext) -> anyhow::Result<()> {
+    for cfg in ctx.init().bootstrap_snapshots.iter() {
+        let snapshot = cfg.read()?;
+        let stats = ctx
+            .get(RELAY_GRAPH)
+            .write()
+            .import_snapshot(snapshot)
+            .with_context(|| format!("cannot import snapshot {}", cfg.path.display()))?;
+        tracing::info!(
+            path = debug(&cfg.path),
+            identities = stats.identities,
+            adjacencies = stats.adjacencies,
+            rejected = stats.rejected,
+            "loaded bootstrap snapshot"
+        );
+    }
+    Ok(())
+}
+
+/// Loop that periodically prunes the relay graph, so that stale descriptors go away even when nothing new comes in.
+async fn graph_prune_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    loop {
+        smol::Timer::after(Duration::from_secs(ctx.init().relay_graph.prune_interval)).await;
+        let mut anchors = all_relay_neighs(&ctx);
+        anchors.extend(
+            ctx.get(MY_RELAY_IDENTITY)
+                .map(|id| id.public().fingerprint()),
+        );
+        let mut graph = ctx.get(RELAY_GRAPH).write();
+        graph.set_anchors(anchors);
+        graph.prune();
+    }
+}
+
+const GRAPH_WATCH_INTERVAL: Duration = Duration::from_secs(5);
 
-        // Join all the tasks. If any of the tasks terminate with an error, that's fatal!
-        while let Some(next) = fallible_tasks.next().await {
-            next?;
+/// Loop that emits an event whenever the relay graph gains or loses relays or adjacencies. It compares snapshots of both rather than hooking every insertion, so a burst of gossip only produces one event, and re-signed adjacencies produce none.
+async fn graph_watch_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    let mut last = None;
+    loop {
+        let (relays, adjacencies) = {
+            let graph = ctx.get(RELAY_GRAPH).read();
+            (
+                graph.all_nodes().collect::<BTreeSet<_>>(),
+                graph
+                    .all_adjacencies()
+                    .map(|adj| (adj.left, adj.right))
+                    .collect::<BTreeSet<_>>(),
+            )
+        };
+        if last
+            .as_ref()
+            .is_some_and(|(last_relays, last_adjacencies)| {
+                *last_relays != relays || *last_adjacencies != adjacencies
+            })
+        {
+            ctx.get(EVENTS).emit(DaemonEvent::RelayGraphChanged {
+                relays: relays.len(),
+                adjacencies: adjacencies.len(),
+            });
         }
-        anyhow::Ok(())
-    })
+        last = Some((relays, adjacencies));
+        smol::Timer::after(GRAPH_WATCH_INTERVAL).await;
+    }
 }
 
 #[instrument(skip(ctx))]
@@ -260,22 +397,65 @@ async fn db_sync_loop(ctx: DaemonContext) -> anyhow::Result<()> {
         let global_id = ctx.get(MY_RELAY_IDENTITY).stdcode();
         let graph = ctx.clone().get(RELAY_GRAPH).read().stdcode();
         let chats = ctx.get(CHATS).stdcode();
+        let debts = ctx.get(DEBTS).as_bytes()?;
+        let settlement_history = ctx.get(SETTLEMENTS).history().stdcode();
 
         db_write(&ctx, "global_identity", global_id).await?;
         db_write(&ctx, "relay_graph", graph).await?;
         db_write(&ctx, "chats", chats).await?;
+        db_write(&ctx, "debts", debts).await?;
+        db_write(&ctx, "settlement_history", settlement_history).await?;
 
         smol::Timer::after(Duration::from_secs(10)).await;
     }
 }
 
+/// A full-access control protocol token, written to the cookie file for local clients to pick up.
+static CONTROL_COOKIE: CtxField<String> = |_| hex::encode(rand::random::<[u8; 32]>());
+
 #[instrument(skip(ctx))]
 /// Loop that handles the control protocol
 async fn control_protocol_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    let cfg = &ctx.init().control_auth;
+    let auth = match cfg.unauthenticated {
+        false => {
+            let cookie = ctx.get(CONTROL_COOKIE).clone();
+            match cfg.cookie_file.clone().or_else(default_cookie_path) {
+                Some(path) => {
+                    write_cookie(&path, &cookie)?;
+                    tracing::info!(path = debug(path), "wrote control cookie");
+                }
+                None => tracing::warn!(
+                    "nowhere to write the control cookie, so only configured tokens will work"
+                ),
+            }
+            let tokens = cfg
+                .tokens
+                .iter()
+                .map(|token| (token.token.clone(), token.scope))
+                .chain([(cookie, ControlScope::Full)]);
+            Some(ControlAuth::new(tokens))
+        }
+        true => {
+            tracing::warn!("serving the control protocol without authentication");
+            None
+        }
+    };
+    let service =
+        ControlAuthService::new(ControlService(ControlProtocolImpl::new(ctx.clone())), auth);
+
     let http = HttpRpcServer::bind(ctx.init().control_listen).await?;
-    let service = ControlService(ControlProtocolImpl::new(ctx));
-    http.run(service).await?;
-    Ok(())
+    let serve_http = async {
+        http.run(service.clone()).await?;
+        anyhow::Ok(())
+    };
+    let serve_unix = async {
+        match ctx.init().control_unix.as_ref() {
+            Some(path) => serve_unix(path, service.clone()).await,
+            None => smol::future::pending().await,
+        }
+    };
+    smol::future::race(serve_http, serve_unix).await
 }
 
 #[instrument(skip(ctx))]
@@ -1,11 +1,15 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::atomic::Ordering,
     time::{Duration, SystemTime},
 };
 
 use async_trait::async_trait;
 
-use earendil_crypt::{AnonEndpoint, ClientId, HavenFingerprint, RelayFingerprint};
+use earendil_crypt::{
+    AnonEndpoint, ClientId, HavenFingerprint, RelayFingerprint, RelayIdentitySecret,
+};
+use earendil_topology::GraphSnapshot;
 use either::Either;
 use itertools::Itertools;
 
@@ -15,12 +19,21 @@ use serde_json::json;
 use smol_timeout::TimeoutExt;
 
 use crate::{
-    context::{MY_CLIENT_ID, MY_RELAY_IDENTITY, RELAY_GRAPH},
-    control_protocol::ConfigError,
+    context::{DEBTS, MY_CLIENT_ID, MY_RELAY_IDENTITY, RELAY_GRAPH},
+    control_protocol::{
+        ConfigError, DebtInfo, EventBatch, ForwardInfo, LinkInfo, MixQueueInfo, RelayEdgeInfo,
+        RelayGraphInfo, RelayNodeInfo, RelayReliability, ReloadReport, ReplayFilterInfo,
+        SettlementError,
+    },
     dht::{dht_get, dht_insert},
+    events::EVENTS,
     haven::HavenLocator,
+    n2r::RELAY_RELIABILITY,
     n2r_socket::N2rClientSocket,
-    network::{all_client_neighs, all_relay_neighs},
+    network::{all_client_neighs, all_relay_neighs, MIX_DROPPED, MIX_QUEUE, PKT_REPLAY_GUARD},
+    settlement::{
+        deduct_payment, SettlementProof, SettlementRecord, SettlementRequest, SETTLEMENTS,
+    },
     InRouteConfig,
 };
 use crate::{
@@ -29,7 +42,14 @@ use crate::{
     global_rpc::transport::GlobalRpcTransport,
 };
 
-use super::chat::{ChatEntry, CHATS};
+use super::{
+    chat::{ChatEntry, CHATS},
+    forward_status::FORWARD_STATUS,
+    link_status::LINK_STATUS,
+    reload::{self, LIVE_CONFIG},
+};
+
+const MAX_EVENT_WAIT_SECS: u64 = 20;
 
 pub struct ControlProtocolImpl {
     ctx: DaemonContext,
@@ -56,6 +76,10 @@ impl ControlProtocol for ControlProtocolImpl {
                             "TcpService".to_string(),
                             fp.to_string() + ":" + &haven_cfg.listen_port.to_string(),
                         )),
+                        crate::config::HavenHandler::UdpService { upstream: _ } => Ok((
+                            "UdpService".to_string(),
+                            fp.to_string() + ":" + &haven_cfg.listen_port.to_string(),
+                        )),
                         crate::config::HavenHandler::SimpleProxy => Ok((
                             "SimpleProxy".to_string(),
                             fp.to_string() + ":" + &haven_cfg.listen_port.to_string(),
@@ -67,14 +91,24 @@ impl ControlProtocol for ControlProtocolImpl {
             .collect()
     }
 
+    async fn forwards_info(&self) -> Vec<ForwardInfo> {
+        self.ctx
+            .get(FORWARD_STATUS)
+            .iter()
+            .map(|entry| entry.value().clone())
+            .sorted_by_key(|info| info.listen)
+            .collect()
+    }
+
     async fn my_routes(&self) -> serde_json::Value {
         if let Some(my_relay_id) = self.ctx.get(MY_RELAY_IDENTITY) {
             let lala: BTreeMap<String, serde_json::Value> = self
                 .ctx
-                .init()
+                .get(LIVE_CONFIG)
+                .read()
                 .in_routes
                 .iter()
-                .map(|(k, InRouteConfig { listen, obfs })| {
+                .map(|(k, InRouteConfig { listen, obfs, .. })| {
                     (
                         k.clone(),
                         json!({
@@ -97,7 +131,7 @@ impl ControlProtocol for ControlProtocolImpl {
             .get(MY_RELAY_IDENTITY)
             .map(|id| get_node_label(&id.public().fingerprint()) + "\n[relay]")
             .unwrap_or(self.ctx.get(MY_CLIENT_ID).to_string() + "\n[client]");
-        let my_shape = if self.ctx.init().in_routes.is_empty() {
+        let my_shape = if self.ctx.get(LIVE_CONFIG).read().in_routes.is_empty() {
             "rect"
         } else {
             "oval"
@@ -178,8 +212,7 @@ impl ControlProtocol for ControlProtocolImpl {
     async fn insert_rendezvous(&self, locator: HavenLocator) -> Result<(), DhtError> {
         let n2r_skt = N2rClientSocket::bind(self.ctx.clone(), AnonEndpoint::random())
             .expect("failed to bind n2r client socket");
-        dht_insert(&self.ctx, locator, &n2r_skt).await;
-        Ok(())
+        dht_insert(&self.ctx, locator, &n2r_skt).await
     }
 
     async fn get_rendezvous(
@@ -210,6 +243,15 @@ impl ControlProtocol for ControlProtocolImpl {
         neighbors
     }
 
+    async fn link_status(&self) -> Vec<LinkInfo> {
+        self.ctx
+            .get(LINK_STATUS)
+            .iter()
+            .sorted_by_key(|entry| *entry.key())
+            .map(|entry| entry.value().info(&self.ctx))
+            .collect()
+    }
+
     /// returns not only all active chats but also all potential chat destinations
     async fn list_chats(&self) -> HashMap<String, (Option<ChatEntry>, u32)> {
         let mut chat_info: HashMap<String, (Option<ChatEntry>, u32)> = self
@@ -254,6 +296,209 @@ impl ControlProtocol for ControlProtocolImpl {
         self.ctx.get(CHATS).record(neighbor, entry);
         Ok(())
     }
+
+    async fn start_settlement(
+        &self,
+        neighbor_prefix: String,
+        amount: u64,
+    ) -> Result<SettlementRecord, SettlementError> {
+        let neighbor = neigh_by_prefix(&self.ctx, &neighbor_prefix)
+            .map_err(|e| SettlementError::Neighbor(format!("{e}")))?;
+        let request = SettlementRequest::new(
+            *self.ctx.get(MY_RELAY_IDENTITY),
+            amount,
+            SettlementProof::Manual,
+        );
+        // the neighbor's operator has 300 seconds to answer before the neighbor gives up
+        let response = self
+            .ctx
+            .get(SETTLEMENTS)
+            .send_outgoing(neighbor, request)
+            .timeout(Duration::from_secs(310))
+            .await
+            .ok_or_else(|| SettlementError::Failed("timed out".to_owned()))?
+            .map_err(|e| SettlementError::Failed(format!("{e}")))?
+            .ok_or(SettlementError::Rejected)?;
+        deduct_payment(&self.ctx, neighbor, amount);
+        self.ctx
+            .get(SETTLEMENTS)
+            .record(neighbor, false, response.clone());
+        Ok(SettlementRecord {
+            neighbor,
+            is_incoming: false,
+            time: SystemTime::now(),
+            response,
+        })
+    }
+
+    async fn list_settlements(&self) -> Vec<(String, u64)> {
+        self.ctx
+            .get(SETTLEMENTS)
+            .list()
+            .into_iter()
+            .map(|(neigh, request)| (neigh.to_string(), request.decrease()))
+            .collect()
+    }
+
+    async fn accept_settlement(&self, neighbor_prefix: String) -> Result<(), SettlementError> {
+        let neighbor = neigh_by_prefix(&self.ctx, &neighbor_prefix)
+            .map_err(|e| SettlementError::Neighbor(format!("{e}")))?;
+        let accepted = self
+            .ctx
+            .get(SETTLEMENTS)
+            .accept_response(&self.ctx, neighbor)
+            .await
+            .map_err(|e| SettlementError::Failed(format!("{e}")))?;
+        if !accepted {
+            return Err(SettlementError::NoPending(neighbor.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn reject_settlement(&self, neighbor_prefix: String) -> Result<(), SettlementError> {
+        let neighbor = neigh_by_prefix(&self.ctx, &neighbor_prefix)
+            .map_err(|e| SettlementError::Neighbor(format!("{e}")))?;
+        let rejected = self
+            .ctx
+            .get(SETTLEMENTS)
+            .reject_response(&neighbor)
+            .await
+            .map_err(|e| SettlementError::Failed(format!("{e}")))?;
+        if !rejected {
+            return Err(SettlementError::NoPending(neighbor.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn settlement_history(&self) -> Vec<SettlementRecord> {
+        self.ctx.get(SETTLEMENTS).history()
+    }
+
+    async fn list_debts(&self) -> Vec<DebtInfo> {
+        self.ctx.get(DEBTS).summary()
+    }
+
+    async fn replay_filter_info(&self) -> ReplayFilterInfo {
+        let guard = self.ctx.get(PKT_REPLAY_GUARD).0.lock();
+        ReplayFilterInfo {
+            remembered: guard.remembered(),
+            rejected: guard.rejected(),
+            early_rotations: guard.filled(),
+            false_positive_rate: guard.false_positive_rate(),
+            memory_bytes: guard.memory_bytes() as u64,
+        }
+    }
+
+    async fn mix_queue_info(&self) -> MixQueueInfo {
+        let queue = self.ctx.get(MIX_QUEUE);
+        let stats = queue.stats();
+        MixQueueInfo {
+            depth: queue.depth() as u64,
+            max_depth: queue.max_depth() as u64,
+            emitted: stats.emitted,
+            overflowed: stats.overflowed,
+            dropped: self.ctx.get(MIX_DROPPED).load(Ordering::Relaxed),
+        }
+    }
+
+    async fn relay_reliability(&self) -> Vec<RelayReliability> {
+        self.ctx
+            .get(RELAY_RELIABILITY)
+            .iter()
+            .map(|entry| RelayReliability {
+                relay: *entry.key(),
+                loops_sent: entry.sent,
+                loops_returned: entry.returned,
+                loops_lost: entry.lost,
+            })
+            .collect()
+    }
+
+    async fn relay_graph(
+        &self,
+        around: Option<RelayFingerprint>,
+        hops: Option<usize>,
+    ) -> RelayGraphInfo {
+        let graph = self.ctx.get(RELAY_GRAPH).read();
+        let shown: Option<HashSet<RelayFingerprint>> = if around.is_some() || hops.is_some() {
+            // clients aren't in the relay graph, so their surroundings start at their neighbors
+            let centers = match (around, self.ctx.get(MY_RELAY_IDENTITY)) {
+                (Some(around), _) => vec![around],
+                (None, Some(my_id)) => vec![my_id.public().fingerprint()],
+                (None, None) => all_relay_neighs(&self.ctx),
+            };
+            let hops = hops.unwrap_or(1);
+            Some(
+                graph
+                    .distances_from(&centers)
+                    .into_iter()
+                    .filter(|(_, distance)| *distance <= hops)
+                    .map(|(fp, _)| fp)
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        let is_shown = |fp: &RelayFingerprint| shown.as_ref().is_none_or(|s| s.contains(fp));
+
+        let neighs: HashSet<RelayFingerprint> = all_relay_neighs(&self.ctx).into_iter().collect();
+        let nodes = graph
+            .all_nodes()
+            .filter(is_shown)
+            .filter_map(|fp| {
+                let descr = graph.identity(&fp)?;
+                Some(RelayNodeInfo {
+                    fingerprint: fp,
+                    onion_pk: descr.onion_pk,
+                    onion_pk_expiry: descr.onion_pk_expiry,
+                    unix_timestamp: descr.unix_timestamp,
+                    is_neighbor: neighs.contains(&fp),
+                })
+            })
+            .collect();
+        let edges = graph
+            .all_adjacencies()
+            .filter(|adj| is_shown(&adj.left) && is_shown(&adj.right))
+            .sorted_by_key(|adj| (adj.left, adj.right))
+            .map(|adj| RelayEdgeInfo {
+                left: adj.left,
+                right: adj.right,
+                unix_timestamp: adj.unix_timestamp,
+            })
+            .collect();
+        RelayGraphInfo { nodes, edges }
+    }
+
+    async fn relay_path(
+        &self,
+        from: RelayFingerprint,
+        to: RelayFingerprint,
+    ) -> Option<Vec<RelayFingerprint>> {
+        self.ctx
+            .get(RELAY_GRAPH)
+            .read()
+            .find_shortest_path(&from, &to)
+    }
+
+    async fn relay_graph_snapshot(&self) -> GraphSnapshot {
+        let signer = self
+            .ctx
+            .get(MY_RELAY_IDENTITY)
+            .unwrap_or_else(RelayIdentitySecret::generate);
+        self.ctx.get(RELAY_GRAPH).read().export_snapshot(&signer)
+    }
+
+    async fn next_events(&self, from: Option<u64>, timeout_secs: u64) -> EventBatch {
+        // stays well below the timeout of HTTP control clients
+        let timeout = Duration::from_secs(timeout_secs.min(MAX_EVENT_WAIT_SECS));
+        self.ctx.get(EVENTS).wait_from(from, timeout).await
+    }
+
+    async fn reload_config(&self) -> Result<ReloadReport, ConfigError> {
+        reload::reload_config(&self.ctx)
+            .await
+            .map_err(|e| ConfigError::Error(format!("{e:#}")))
+    }
 }
 
 fn get_node_label(fp: &RelayFingerprint) -> String {
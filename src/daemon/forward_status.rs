@@ -0,0 +1,77 @@
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use dashmap::DashMap;
+
+use crate::{context::CtxField, control_protocol::ForwardInfo, HavenEndpoint};
+
+/// The status of every port forward that this daemon runs, keyed by the local listening address.
+pub static FORWARD_STATUS: CtxField<DashMap<SocketAddr, ForwardInfo>> = |_| DashMap::new();
+
+/// How long a forward waits before trying again to bind its local address.
+const BIND_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Registers a port forward, resetting any status previously recorded for it.
+pub fn register_forward(
+    status: &DashMap<SocketAddr, ForwardInfo>,
+    protocol: &str,
+    listen: SocketAddr,
+    remote: HavenEndpoint,
+) {
+    status.insert(
+        listen,
+        ForwardInfo {
+            protocol: protocol.to_string(),
+            listen,
+            remote,
+            active_conns: 0,
+            total_conns: 0,
+            last_error: None,
+        },
+    );
+}
+
+/// Records a new connection (or session) through the given forward. The returned guard marks it finished when dropped.
+pub fn forward_conn_started(
+    status: &DashMap<SocketAddr, ForwardInfo>,
+    listen: SocketAddr,
+) -> impl Drop + '_ {
+    if let Some(mut info) = status.get_mut(&listen) {
+        info.active_conns += 1;
+        info.total_conns += 1;
+    }
+    scopeguard::guard((), move |_| {
+        if let Some(mut info) = status.get_mut(&listen) {
+            info.active_conns = info.active_conns.saturating_sub(1);
+        }
+    })
+}
+
+/// Records an error that happened within the given forward.
+pub fn forward_error(
+    status: &DashMap<SocketAddr, ForwardInfo>,
+    listen: SocketAddr,
+    err: &anyhow::Error,
+) {
+    if let Some(mut info) = status.get_mut(&listen) {
+        info.last_error = Some(format!("{:?}", err));
+    }
+}
+
+/// Binds the local address of a forward, retrying until that works. Failures go into the forward's status rather than being returned, so that a taken port only breaks this forward and not the whole daemon.
+pub async fn bind_forward<T, F: Future<Output = std::io::Result<T>>>(
+    status: &DashMap<SocketAddr, ForwardInfo>,
+    listen: SocketAddr,
+    bind: impl Fn() -> F,
+) -> T {
+    loop {
+        match bind().await {
+            Ok(bound) => return bound,
+            Err(err) => {
+                let err = anyhow::Error::from(err).context(format!("cannot bind to {listen}"));
+                tracing::warn!(err = debug(&err), "could not start forward, retrying");
+                forward_error(status, listen, &err);
+                smol::Timer::after(BIND_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
@@ -1,17 +1,27 @@
 use std::{
     net::{SocketAddr, ToSocketAddrs},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use self::{gossip::gossip_once, link_protocol::LinkService};
+use self::{
+    gossip::{gossip_once, is_gossip_banned},
+    link_protocol::LinkService,
+    settle::auto_settle_once,
+};
 
 use super::link::LinkMessage;
 use crate::{
-    config::InRouteConfig,
-    context::{DaemonContext, MY_RELAY_IDENTITY, MY_RELAY_ONION_SK, RELAY_GRAPH},
-    daemon::{chat::CHATS, inout_route::link_protocol::LinkClient, link::Link},
+    config::{InRouteConfig, LinkPrice},
+    context::{DaemonContext, DEBTS, MY_RELAY_IDENTITY, MY_RELAY_ONION_KEYS, RELAY_GRAPH},
+    daemon::{
+        chat::CHATS,
+        inout_route::link_protocol::LinkClient,
+        link::Link,
+        link_status::{register_link, LinkOrigin, LinkStatus},
+    },
     n2r, network,
     pascal::{read_pascal, write_pascal},
+    settlement::SETTLEMENTS,
 };
 use crate::{
     config::{ObfsConfig, OutRouteConfig},
@@ -19,9 +29,10 @@ use crate::{
 };
 use anyhow::Context;
 use bytes::Bytes;
-use earendil_crypt::ClientId;
+use earendil_crypt::{ClientId, RelayFingerprint};
 use earendil_packet::{RawBody, RawPacket};
 use earendil_topology::IdentityDescriptor;
+use either::Either;
 use futures::AsyncReadExt as _;
 use nursery_macro::nursery;
 use picomux::PicoMux;
@@ -38,6 +49,7 @@ use stdcode::StdcodeSerializeExt as _;
 mod gossip;
 mod link_protocol;
 mod link_protocol_impl;
+mod settle;
 
 /*
 Links aren't inherently client-relay or relay-relay.
@@ -52,12 +64,29 @@ Relay and client messages are then put on the same link.
 */
 
 #[tracing::instrument(skip_all, fields(listen=debug(cfg.listen)))]
-pub async fn listen_in_route(ctx: &DaemonContext, cfg: &InRouteConfig) -> anyhow::Result<()> {
-    async fn manage_pipe(ctx: &DaemonContext, pipe: impl Pipe) -> anyhow::Result<()> {
+pub async fn listen_in_route(
+    ctx: &DaemonContext,
+    name: &str,
+    cfg: &InRouteConfig,
+) -> anyhow::Result<()> {
+    let manage_pipe = |pipe: Box<dyn Pipe>| async move {
+        let origin = LinkOrigin {
+            route: name.to_string(),
+            remote_addr: pipe.remote_addr().map(|addr| addr.to_string()),
+            obfs: cfg.obfs.name(),
+        };
         let (mux, their_client_id, their_relay_descr) = pipe_to_mux(ctx, pipe).await?;
         let link = Link::new_listen(mux).await?;
-        manage_mux(ctx, link, their_client_id, their_relay_descr).await
-    }
+        manage_mux(
+            ctx,
+            link,
+            origin,
+            their_client_id,
+            their_relay_descr,
+            cfg.link_price,
+        )
+        .await
+    };
 
     let mut listener = TcpListener::bind(cfg.listen).await?;
     nursery!(match &cfg.obfs {
@@ -68,7 +97,7 @@ pub async fn listen_in_route(ctx: &DaemonContext, cfg: &InRouteConfig) -> anyhow
                     remote_addr = debug(tcp_pipe.remote_addr()),
                     "accepted a TCP connection"
                 );
-                spawn!(manage_pipe(ctx, tcp_pipe)).detach();
+                spawn!(manage_pipe(Box::new(tcp_pipe))).detach();
             }
             anyhow::Ok(())
         }
@@ -81,7 +110,7 @@ pub async fn listen_in_route(ctx: &DaemonContext, cfg: &InRouteConfig) -> anyhow
                     remote_addr = debug(sosistab_pipe.remote_addr()),
                     "accepted a SOSISTAB connection"
                 );
-                spawn!(manage_pipe(ctx, sosistab_pipe)).detach();
+                spawn!(manage_pipe(Box::new(sosistab_pipe))).detach();
             }
             anyhow::Ok(())
         }
@@ -90,14 +119,31 @@ pub async fn listen_in_route(ctx: &DaemonContext, cfg: &InRouteConfig) -> anyhow
 }
 
 #[tracing::instrument(skip_all, fields(connect=debug(&cfg.connect)))]
-pub async fn dial_out_route(ctx: &DaemonContext, cfg: &OutRouteConfig) -> anyhow::Result<()> {
-    async fn manage_out_pipe(ctx: &DaemonContext, pipe: impl Pipe) -> anyhow::Result<()> {
+pub async fn dial_out_route(
+    ctx: &DaemonContext,
+    name: &str,
+    cfg: &OutRouteConfig,
+) -> anyhow::Result<()> {
+    let manage_out_pipe = |pipe: Box<dyn Pipe>| async move {
+        let origin = LinkOrigin {
+            route: name.to_string(),
+            remote_addr: pipe.remote_addr().map(|addr| addr.to_string()),
+            obfs: cfg.obfs.name(),
+        };
         let (mux, their_client_id, their_relay_descr) = pipe_to_mux(ctx, pipe).await?;
         let link = Link::new_dial(mux).await?;
         tracing::debug!("link connected to other side");
-        manage_mux(ctx, link, their_client_id, their_relay_descr).await?;
+        manage_mux(
+            ctx,
+            link,
+            origin,
+            their_client_id,
+            their_relay_descr,
+            cfg.link_price,
+        )
+        .await?;
         anyhow::Ok(())
-    }
+    };
 
     loop {
         let fallible = async {
@@ -121,7 +167,7 @@ pub async fn dial_out_route(ctx: &DaemonContext, cfg: &OutRouteConfig) -> anyhow
                 ObfsConfig::None => {
                     let tcp_pipe = tcp_dialer.dial().await?;
                     tracing::debug!("TCP connected to other side");
-                    manage_out_pipe(ctx, tcp_pipe).await
+                    manage_out_pipe(Box::new(tcp_pipe)).await
                 }
                 ObfsConfig::Sosistab3(cookie) => {
                     let sosistab_dialer = SosistabDialer {
@@ -130,7 +176,7 @@ pub async fn dial_out_route(ctx: &DaemonContext, cfg: &OutRouteConfig) -> anyhow
                     };
                     let sosistab_pipe = sosistab_dialer.dial().await?;
                     tracing::debug!("SOSISTAB connected to other side");
-                    manage_out_pipe(ctx, sosistab_pipe).await
+                    manage_out_pipe(Box::new(sosistab_pipe)).await
                 }
             }
         };
@@ -153,10 +199,10 @@ async fn pipe_to_mux(
 
     let send_auth = async {
         let my_client_id = *ctx.get(MY_CLIENT_ID);
-        let my_relay_descr = ctx
-            .get(MY_RELAY_IDENTITY)
-            .as_ref()
-            .map(|id| IdentityDescriptor::new(id, ctx.get(MY_RELAY_ONION_SK)));
+        let my_relay_descr = ctx.get(MY_RELAY_IDENTITY).as_ref().map(|id| {
+            let onion_keys = ctx.get(MY_RELAY_ONION_KEYS).read();
+            IdentityDescriptor::new(id, onion_keys.current(), onion_keys.current_expiry())
+        });
         let auth_msg = (my_client_id, my_relay_descr).stdcode();
         write_pascal(&auth_msg, &mut write).await?;
         anyhow::Ok(())
@@ -179,8 +225,10 @@ async fn pipe_to_mux(
 async fn manage_mux(
     ctx: &DaemonContext,
     link: Link,
+    origin: LinkOrigin,
     their_client_id: ClientId,
     their_relay_descr: Option<IdentityDescriptor>,
+    link_price: LinkPrice,
 ) -> anyhow::Result<()> {
     scopeguard::defer!(tracing::debug!("manage_mux died"));
 
@@ -189,17 +237,84 @@ async fn manage_mux(
             .write()
             .insert_identity(descr.clone())?;
     }
+    let neigh = their_relay_descr
+        .as_ref()
+        .map(|r| Either::Right(r.identity_pk.fingerprint()))
+        .unwrap_or_else(|| Either::Left(their_client_id));
+    anyhow::ensure!(
+        !is_gossip_banned(ctx, neigh),
+        "refusing {neigh:?}, which recently gossiped too many invalid descriptors"
+    );
+
+    // we charge the neighbor our own incoming price, and learn what they charge us once the link is up
+    match neigh {
+        Either::Left(id) => ctx.get(DEBTS).insert_client_incoming_price(
+            id,
+            link_price.incoming_price,
+            link_price.incoming_debt_limit,
+        ),
+        Either::Right(fp) => ctx.get(DEBTS).insert_relay_incoming_price(
+            fp,
+            link_price.incoming_price,
+            link_price.incoming_debt_limit,
+        ),
+    }
+    let price_negotiation = async {
+        let their_price = LinkClient(link.rpc_transport())
+            .link_price()
+            .await
+            .context("could not get the neighbor's link price")?;
+        if their_price.price > link_price.max_outgoing_price {
+            anyhow::bail!(
+                "neighbor charges {} micromel per message, more than our max_outgoing_price of {}",
+                their_price.price,
+                link_price.max_outgoing_price
+            );
+        }
+        tracing::debug!(
+            price = their_price.price,
+            debt_limit = their_price.debt_limit,
+            "negotiated outgoing link price"
+        );
+        match neigh {
+            Either::Left(id) => ctx.get(DEBTS).insert_client_outgoing_price(
+                id,
+                their_price.price,
+                their_price.debt_limit,
+            ),
+            Either::Right(fp) => ctx.get(DEBTS).insert_relay_outgoing_price(
+                fp,
+                their_price.price,
+                their_price.debt_limit,
+            ),
+        }
+        smol::future::pending().await
+    };
+    let status = register_link(
+        ctx,
+        origin,
+        their_client_id,
+        their_relay_descr
+            .as_ref()
+            .map(|descr| descr.identity_pk.fingerprint()),
+    );
+    tracing::debug!(
+        route = status.origin.route,
+        remote_addr = debug(&status.origin.remote_addr),
+        neigh = debug(neigh),
+        "link up"
+    );
+
     // subscribe to the right outgoing stuff and stuff them into the link
     let recv_outgoing_client = network::subscribe_outgoing_client(ctx, their_client_id);
-    println!("ADDED CLIENT_ID: {their_client_id}");
     let send_outgoing_client = async {
         loop {
             let msg = recv_outgoing_client.recv().await?;
-            link.send_msg(LinkMessage::ToClient {
-                body: Bytes::copy_from_slice(&msg.0),
-                rb_id: msg.1,
-            })
-            .await?;
+            let body = Bytes::copy_from_slice(&msg.0);
+            status.record_outgoing(body.len());
+            link.send_msg(LinkMessage::ToClient { body, rb_id: msg.1 })
+                .await?;
+            meter_outgoing(ctx, neigh);
         }
     };
 
@@ -209,11 +324,14 @@ async fn manage_mux(
                 network::subscribe_outgoing_relay(ctx, relay_descr.identity_pk.fingerprint());
             loop {
                 let (pkt, next_peeler) = recv_relay_msg.recv().await?;
+                let packet = Bytes::copy_from_slice(bytemuck::bytes_of(&pkt));
+                status.record_outgoing(packet.len());
                 link.send_msg(LinkMessage::ToRelay {
-                    packet: Bytes::copy_from_slice(bytemuck::bytes_of(&pkt)),
+                    packet,
                     next_peeler,
                 })
                 .await?;
+                meter_outgoing(ctx, neigh);
             }
         } else {
             smol::future::pending().await
@@ -223,6 +341,18 @@ async fn manage_mux(
     let recv_incoming = async {
         loop {
             let in_msg = link.recv_msg().await?;
+            status.record_incoming(match &in_msg {
+                LinkMessage::ToClient { body, .. } => body.len(),
+                LinkMessage::ToRelay { packet, .. } => packet.len(),
+            });
+            if !is_within_debt_limit(ctx, neigh) {
+                tracing::debug!(
+                    neigh = debug(neigh),
+                    "neighbor is over its debt limit, dropping its message"
+                );
+                continue;
+            }
+            meter_incoming(ctx, neigh);
             match in_msg {
                 LinkMessage::ToClient { body, rb_id } => {
                     tracing::trace!(rb_id, "incoming ToClient");
@@ -261,29 +391,34 @@ async fn manage_mux(
         ctx: ctx.clone(),
         remote_client_id: their_client_id,
         remote_relay_fp,
+        my_price: link_price,
     });
     let rpc_serve = link.rpc_serve(service);
 
-    // gossip
+    // gossip, backing off while the neighbor has nothing new for us
     let gossip_loop = async {
+        let mut interval = GOSSIP_INTERVAL;
         loop {
-            let _ = gossip_once(ctx, &link, remote_relay_fp).await;
-            smol::Timer::after(Duration::from_secs(1)).await;
+            interval = match gossip_once(ctx, &link, neigh).await {
+                Ok(true) => (interval * 2).min(MAX_GOSSIP_INTERVAL),
+                _ => GOSSIP_INTERVAL,
+            };
+            if is_gossip_banned(ctx, neigh) {
+                anyhow::bail!(
+                    "disconnecting from {neigh:?}, which gossiped too many invalid descriptors"
+                );
+            }
+            smol::Timer::after(interval).await;
         }
     };
 
+    // keepalive, which also measures the round-trip time
+    let info_loop = probe_info(ctx, &link, &status, neigh);
+
     // chat
     let chat_loop = async {
         loop {
-            let unsent = ctx
-                .get(CHATS)
-                .wait_unsent(
-                    their_relay_descr
-                        .as_ref()
-                        .map(|r| either::Either::Right(r.identity_pk.fingerprint()))
-                        .unwrap_or_else(|| either::Either::Left(their_client_id)),
-                )
-                .await;
+            let unsent = ctx.get(CHATS).wait_unsent(neigh).await;
             tracing::debug!(len = unsent.len(), "sending batch of chats");
             for unsent in unsent {
                 tracing::debug!(text = &unsent.text, "sending a chat");
@@ -294,11 +429,145 @@ async fn manage_mux(
         }
     };
 
+    // manual settlements that our operator started with the neighbor
+    let manual_settle_loop = async {
+        let recv_outgoing = ctx.get(SETTLEMENTS).subscribe_outgoing(neigh);
+        loop {
+            let (request, send_res) = recv_outgoing.recv().await?;
+            // a failed call only fails this settlement, which the operator hears about when send_res is dropped, and not the whole link
+            let response = match LinkClient(link.rpc_transport())
+                .start_settlement(request)
+                .await
+            {
+                Ok(response) => response,
+                Err(err) => {
+                    tracing::warn!(err = debug(err), "could not send manual settlement");
+                    continue;
+                }
+            };
+            let response = response.filter(|response| {
+                response
+                    .verify(their_relay_descr.as_ref().map(|descr| &descr.identity_pk))
+                    .map_err(|err| {
+                        tracing::warn!(err = debug(err), "bad settlement response signature")
+                    })
+                    .is_ok()
+            });
+            let _ = send_res.send(response).await;
+        }
+    };
+
+    // automatic settlement of what we owe the neighbor
+    let auto_settle_loop = async {
+        if let Some(auto_settle) = ctx.init().auto_settle {
+            loop {
+                smol::Timer::after(Duration::from_secs(auto_settle.interval)).await;
+                if let Err(err) = auto_settle_once(
+                    ctx,
+                    &link,
+                    neigh,
+                    their_relay_descr.as_ref().map(|descr| &descr.identity_pk),
+                )
+                .await
+                {
+                    tracing::debug!(err = debug(err), "automatic settlement failed");
+                }
+            }
+        } else {
+            smol::future::pending().await
+        }
+    };
+
     send_outgoing_client
         .race(send_outgoing_relay)
         .race(rpc_serve)
         .race(gossip_loop)
         .race(recv_incoming)
         .race(chat_loop)
+        .race(price_negotiation)
+        .race(manual_settle_loop)
+        .race(auto_settle_loop)
+        .race(info_loop)
         .await
 }
+
+/// How often we gossip with each neighbor.
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long we wait between gossip rounds at most, after rounds in which the neighbor had nothing new for us.
+const MAX_GOSSIP_INTERVAL: Duration = Duration::from_secs(16);
+
+/// How often we ask the neighbor for its info, to keep the link's version and round-trip time up to date.
+const INFO_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Link weights are in tenths of a millisecond of RTT, so that links we know nothing about count as 100 ms ones. RTTs are rounded to buckets of this size, so that similar links tie and traffic gets spread across them, and so that jitter doesn't keep changing weights and throwing away the cached routes.
+const RTT_BUCKET: Duration = Duration::from_millis(10);
+
+/// How much each new RTT sample moves the smoothed RTT.
+const RTT_SMOOTHING: f64 = 0.25;
+
+fn rtt_link_weight(rtt: Duration) -> u64 {
+    let buckets = (rtt.as_secs_f64() / RTT_BUCKET.as_secs_f64())
+        .round()
+        .max(1.0);
+    buckets as u64 * (RTT_BUCKET.as_micros() / 100) as u64
+}
+
+async fn probe_info(
+    ctx: &DaemonContext,
+    link: &Link,
+    status: &LinkStatus,
+    neigh: Either<ClientId, RelayFingerprint>,
+) -> anyhow::Result<()> {
+    let mut smoothed_rtt: Option<Duration> = None;
+    let mut weight = None;
+    loop {
+        let start = Instant::now();
+        match LinkClient(link.rpc_transport()).info().await {
+            Ok(info) => {
+                let rtt = start.elapsed();
+                status.record_info(info.version, rtt);
+                let smoothed = smoothed_rtt.map_or(rtt, |smoothed| {
+                    smoothed.mul_f64(1.0 - RTT_SMOOTHING) + rtt.mul_f64(RTT_SMOOTHING)
+                });
+                smoothed_rtt = Some(smoothed);
+                // only links between relays are in the relay graph
+                if let (Some(my_id), Either::Right(neigh_fp)) = (ctx.get(MY_RELAY_IDENTITY), neigh)
+                {
+                    let new_weight = rtt_link_weight(smoothed);
+                    if weight != Some(new_weight) {
+                        weight = Some(new_weight);
+                        ctx.get(RELAY_GRAPH).write().set_link_weight(
+                            &my_id.public().fingerprint(),
+                            &neigh_fp,
+                            new_weight,
+                        );
+                    }
+                }
+            }
+            Err(err) => tracing::debug!(err = debug(err), "could not get the neighbor's info"),
+        }
+        smol::Timer::after(INFO_INTERVAL).await;
+    }
+}
+
+fn meter_incoming(ctx: &DaemonContext, neigh: Either<ClientId, RelayFingerprint>) {
+    match neigh {
+        Either::Left(id) => ctx.get(DEBTS).incr_client_incoming(id),
+        Either::Right(fp) => ctx.get(DEBTS).incr_relay_incoming(fp),
+    }
+}
+
+fn meter_outgoing(ctx: &DaemonContext, neigh: Either<ClientId, RelayFingerprint>) {
+    match neigh {
+        Either::Left(id) => ctx.get(DEBTS).incr_client_outgoing(id),
+        Either::Right(fp) => ctx.get(DEBTS).incr_relay_outgoing(fp),
+    }
+}
+
+fn is_within_debt_limit(ctx: &DaemonContext, neigh: Either<ClientId, RelayFingerprint>) -> bool {
+    match neigh {
+        Either::Left(id) => ctx.get(DEBTS).client_is_within_debt_limit(&id),
+        Either::Right(fp) => ctx.get(DEBTS).relay_is_within_debt_limit(&fp),
+    }
+}
@@ -1,33 +1,86 @@
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
 use bytes::Bytes;
-use earendil_crypt::RelayFingerprint;
-use earendil_topology::{AdjacencyDescriptor, IdentityDescriptor};
+use earendil_crypt::{ClientId, NeighborId, RelayFingerprint};
+use earendil_topology::{AdjacencyDescriptor, AdjacencyError, IdentityDescriptor, IdentityError};
+use either::Either;
 use itertools::Itertools;
 use moka::sync::{Cache, CacheBuilder};
+use parking_lot::Mutex;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use tap::TapOptional;
 
 use crate::{
     context::{CtxField, DaemonContext, MY_RELAY_IDENTITY, RELAY_GRAPH},
     daemon::{inout_route::link_protocol::LinkClient, link::Link},
 };
 
+/// How long a neighbor's strikes for invalid gossip are remembered after its latest one.
+const STRIKE_MEMORY: Duration = Duration::from_secs(600);
+
+/// Strikes against each neighbor for gossiping invalid descriptors to us.
+static GOSSIP_STRIKES: CtxField<Cache<Either<ClientId, RelayFingerprint>, u32>> =
+    |_| CacheBuilder::default().time_to_live(STRIKE_MEMORY).build();
+
+/// When the current minute of learning new relays started, and how many we have learned in it.
+static NEW_RELAY_BUDGET: CtxField<Mutex<(Instant, usize)>> = |_| Mutex::new((Instant::now(), 0));
+
+static IDENTITY_CACHE: CtxField<Cache<RelayFingerprint, IdentityDescriptor>> = |_| {
+    CacheBuilder::default()
+        .time_to_live(Duration::from_secs(60))
+        .build()
+};
+
+/// Whether the neighbor has gossiped too many invalid descriptors for us to keep talking to it.
+pub fn is_gossip_banned(ctx: &DaemonContext, neigh: Either<ClientId, RelayFingerprint>) -> bool {
+    ctx.get(GOSSIP_STRIKES).get(&neigh).unwrap_or(0) >= ctx.init().gossip.max_strikes
+}
+
+fn strike(ctx: &DaemonContext, neigh: Either<ClientId, RelayFingerprint>, err: anyhow::Error) {
+    let strikes = ctx.get(GOSSIP_STRIKES);
+    let count = strikes.get(&neigh).unwrap_or(0) + 1;
+    strikes.insert(neigh, count);
+    tracing::warn!(
+        neigh = debug(neigh),
+        count,
+        err = debug(err),
+        "neighbor gossiped an invalid descriptor"
+    );
+}
+
+fn neighbor_id(neigh: Either<ClientId, RelayFingerprint>) -> NeighborId {
+    match neigh {
+        Either::Left(id) => NeighborId::Client(id),
+        Either::Right(fp) => NeighborId::Relay(fp),
+    }
+}
+
+/// Takes one relay out of the budget of new relays we may learn this minute, returning false if there is none left.
+fn take_new_relay_budget(ctx: &DaemonContext) -> bool {
+    let mut budget = ctx.get(NEW_RELAY_BUDGET).lock();
+    if budget.0.elapsed() >= Duration::from_secs(60) {
+        *budget = (Instant::now(), 0);
+    }
+    if budget.1 >= ctx.init().gossip.max_new_relays_per_minute {
+        return false;
+    }
+    budget.1 += 1;
+    true
+}
+
+/// Gossips with a neighbor once. Returns whether the round was unproductive, with our graphs still differing but nothing new learned from the neighbor, in which case the caller should back off before the next one.
 #[tracing::instrument(skip_all)]
 pub async fn gossip_once(
     ctx: &DaemonContext,
     link: &Link,
-    remote_fp: Option<RelayFingerprint>,
-) -> anyhow::Result<()> {
-    if let Some(remote_fp) = remote_fp {
+    neigh: Either<ClientId, RelayFingerprint>,
+) -> anyhow::Result<bool> {
+    if let Either::Right(remote_fp) = neigh {
         fetch_identity(ctx, link, remote_fp).await?;
         sign_adjacency(ctx, link, remote_fp).await?;
     }
-    gossip_graph(ctx, link).await?;
-
-    Ok(())
+    gossip_graph(ctx, link, neigh).await
 }
 
 // Step 1: Fetch the identity of the neighbor.
@@ -42,7 +95,13 @@ async fn fetch_identity(
         .identity(remote_fp)
         .await?
         .context("relay neighbors should give us their own id!!!")?;
-    ctx.get(RELAY_GRAPH).write().insert_identity(their_id)?;
+    anyhow::ensure!(
+        their_id.identity_pk.fingerprint() == remote_fp,
+        "relay neighbor gave us someone else's id"
+    );
+    if let Err(err) = ctx.get(RELAY_GRAPH).write().insert_identity(their_id) {
+        identity_rejected(ctx, Either::Right(remote_fp), err);
+    }
     Ok(())
 }
 
@@ -57,22 +116,36 @@ async fn sign_adjacency(
         tracing::trace!("signing adjacency...");
         let my_fp = my_sk.public().fingerprint();
         if my_fp < remote_fp {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let fresh = ctx
+                .get(RELAY_GRAPH)
+                .read()
+                .adjacencies(&my_fp)
+                .into_iter()
+                .flatten()
+                .any(|adj| {
+                    adj.right == remote_fp
+                        && now.saturating_sub(adj.unix_timestamp)
+                            < ctx.init().relay_graph.resign_interval()
+                });
+            if fresh {
+                tracing::trace!("adjacency with {remote_fp} is still fresh");
+                return Ok(());
+            }
             tracing::trace!("signing adjacency with {remote_fp}");
             let mut left_incomplete = AdjacencyDescriptor {
                 left: my_fp,
                 right: remote_fp,
                 left_sig: Bytes::new(),
                 right_sig: Bytes::new(),
-                unix_timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                unix_timestamp: now,
             };
             left_incomplete.left_sig = my_sk.sign(left_incomplete.to_sign().as_bytes());
             let complete = LinkClient(link.rpc_transport())
                 .sign_adjacency(left_incomplete)
                 .await?
                 .context("remote refused to sign off")?;
-            ctx.get(RELAY_GRAPH)
-                .write()
-                .insert_adjacency(complete.clone())?;
+            insert_gossiped_adjacency(ctx, Either::Right(remote_fp), complete);
         }
     } else {
         tracing::trace!("skipping signing adjacency...");
@@ -80,10 +153,77 @@ async fn sign_adjacency(
     Ok(())
 }
 
-// Step 3: Gossip the relay graph, by asking info about random nodes.
+// Step 3: Gossip the relay graph, by sending the neighbor a digest of ours and taking whatever it finds us missing. Returns whether the round was unproductive.
 #[tracing::instrument(skip_all)]
-async fn gossip_graph(ctx: &DaemonContext, link: &Link) -> anyhow::Result<()> {
+async fn gossip_graph(
+    ctx: &DaemonContext,
+    link: &Link,
+    neigh: Either<ClientId, RelayFingerprint>,
+) -> anyhow::Result<bool> {
     tracing::trace!("gossipping relay graph...");
+    let client = LinkClient(link.rpc_transport());
+    let digest = ctx.get(RELAY_GRAPH).read().digest();
+    let summary = digest.summary();
+    match client.graph_summary().await {
+        Ok(theirs) if theirs == summary => {
+            tracing::trace!("relay graph already agrees with the neighbor's");
+            return Ok(false);
+        }
+        Ok(_) => {}
+        Err(err) => {
+            // neighbors running older versions don't know about digests
+            tracing::debug!(
+                err = debug(err),
+                "could not get graph summary, falling back to sampling"
+            );
+            gossip_graph_sample(ctx, link, neigh).await?;
+            return Ok(false);
+        }
+    }
+
+    let quota = ctx.init().gossip.max_adjacencies_per_round;
+    let delta = client.graph_delta(digest, quota).await?;
+    if delta.identities.len() > quota || delta.adjacencies.len() > quota {
+        tracing::debug!(
+            neigh = debug(neigh),
+            identities = delta.identities.len(),
+            adjacencies = delta.adjacencies.len(),
+            quota,
+            "neighbor gossiped more descriptors than its quota"
+        );
+    }
+    // identities go first, since adjacencies can only be verified against them
+    for identity in delta.identities.into_iter().take(quota) {
+        let fp = identity.identity_pk.fingerprint();
+        let is_new = ctx.get(RELAY_GRAPH).read().identity(&fp).is_none();
+        if is_new && !take_new_relay_budget(ctx) {
+            tracing::debug!(
+                fp = debug(fp),
+                "learned too many new relays lately, skipping"
+            );
+            continue;
+        }
+        if let Err(err) = ctx
+            .get(RELAY_GRAPH)
+            .write()
+            .insert_gossiped_identity(identity, neighbor_id(neigh))
+        {
+            identity_rejected(ctx, neigh, err);
+        }
+    }
+    for adjacency in delta.adjacencies.into_iter().take(quota) {
+        insert_gossiped_adjacency(ctx, neigh, adjacency);
+    }
+    // the neighbor may well be the one behind, which it finds out when it gossips with us
+    Ok(ctx.get(RELAY_GRAPH).read().digest().summary() == summary)
+}
+
+/// Gossips the relay graph the old way, by asking about random nodes.
+async fn gossip_graph_sample(
+    ctx: &DaemonContext,
+    link: &Link,
+    neigh: Either<ClientId, RelayFingerprint>,
+) -> anyhow::Result<()> {
     let all_known_nodes = ctx.get(RELAY_GRAPH).read().all_nodes().collect_vec();
     let random_sample = all_known_nodes
         .choose_multiple(&mut thread_rng(), 10.min(all_known_nodes.len()))
@@ -92,53 +232,101 @@ async fn gossip_graph(ctx: &DaemonContext, link: &Link) -> anyhow::Result<()> {
     let adjacencies = LinkClient(link.rpc_transport())
         .adjacencies(random_sample)
         .await?;
-    for adjacency in adjacencies {
-        let left_fp = adjacency.left;
-        let right_fp = adjacency.right;
-
-        static IDENTITY_CACHE: CtxField<Cache<RelayFingerprint, IdentityDescriptor>> = |_| {
-            CacheBuilder::default()
-                .time_to_live(Duration::from_secs(60))
-                .build()
-        };
-        let ourselves = ctx.get(MY_RELAY_IDENTITY);
-        let left_id = if ourselves.is_some() && ourselves.unwrap().public().fingerprint() == left_fp
+    let quota = ctx.init().gossip.max_adjacencies_per_round;
+    if adjacencies.len() > quota {
+        tracing::debug!(
+            neigh = debug(neigh),
+            count = adjacencies.len(),
+            quota,
+            "neighbor gossiped more adjacencies than its quota"
+        );
+    }
+    for adjacency in adjacencies.into_iter().take(quota) {
+        if !learn_identity(ctx, link, neigh, adjacency.left).await?
+            || !learn_identity(ctx, link, neigh, adjacency.right).await?
         {
-            None
-        } else if ctx.get(IDENTITY_CACHE).get(&left_fp).is_some() {
-            None
-        } else {
-            let val = LinkClient(link.rpc_transport())
-                .identity(left_fp)
-                .await?
-                .tap_some(|id| ctx.get(IDENTITY_CACHE).insert(left_fp, id.clone()));
-            val
-        };
-
-        let right_id =
-            if ourselves.is_some() && ourselves.unwrap().public().fingerprint() == right_fp {
-                None
-            } else if ctx.get(IDENTITY_CACHE).get(&right_fp).is_some() {
-                None
-            } else {
-                let val = LinkClient(link.rpc_transport())
-                    .identity(right_fp)
-                    .await?
-                    .tap_some(|id| ctx.get(IDENTITY_CACHE).insert(right_fp, id.clone()));
-                val
-            };
+            continue;
+        }
+        insert_gossiped_adjacency(ctx, neigh, adjacency);
+    }
+    Ok(())
+}
 
-        // fetch and insert the identities. we unconditionally do this since identity descriptors may change over time
-        if let Some(left_id) = left_id {
-            ctx.get(RELAY_GRAPH).write().insert_identity(left_id)?
+fn insert_gossiped_adjacency(
+    ctx: &DaemonContext,
+    neigh: Either<ClientId, RelayFingerprint>,
+    adjacency: AdjacencyDescriptor,
+) {
+    let res = ctx.get(RELAY_GRAPH).write().insert_adjacency(adjacency);
+    match res {
+        Ok(()) => {}
+        // the identity may have been evicted or skipped in the meantime, which isn't the neighbor's fault
+        Err(AdjacencyError::LeftIdentityNotFound | AdjacencyError::RightIdentityNotFound) => {}
+        // nor is a clock that is off, whether ours or the signers'
+        Err(AdjacencyError::FromTheFuture) => {
+            tracing::debug!(neigh = debug(neigh), "ignoring a future-dated adjacency")
         }
+        Err(err) => strike(ctx, neigh, err.into()),
+    }
+}
 
-        if let Some(right_id) = right_id {
-            ctx.get(RELAY_GRAPH).write().insert_identity(right_id)?
+/// Strikes the neighbor for an identity descriptor that the relay graph rejected, unless that isn't the neighbor's fault.
+fn identity_rejected(
+    ctx: &DaemonContext,
+    neigh: Either<ClientId, RelayFingerprint>,
+    err: IdentityError,
+) {
+    match err {
+        // a clock that is off, whether ours or the signer's, isn't
+        IdentityError::FromTheFuture => {
+            tracing::debug!(neigh = debug(neigh), "ignoring a future-dated identity")
         }
+        err => strike(ctx, neigh, err.into()),
+    }
+}
 
-        // insert the adjacency
-        ctx.get(RELAY_GRAPH).write().insert_adjacency(adjacency)?
+/// Makes sure we know the identity of a relay mentioned in gossip, fetching it from the neighbor if we haven't lately. Returns whether we know it now. Relays we have never heard of count against the budget of new relays.
+async fn learn_identity(
+    ctx: &DaemonContext,
+    link: &Link,
+    neigh: Either<ClientId, RelayFingerprint>,
+    fp: RelayFingerprint,
+) -> anyhow::Result<bool> {
+    let ourselves = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|id| id.public().fingerprint());
+    if ourselves == Some(fp) || ctx.get(IDENTITY_CACHE).contains_key(&fp) {
+        return Ok(true);
     }
-    Ok(())
+    let is_new = ctx.get(RELAY_GRAPH).read().identity(&fp).is_none();
+    if is_new && !take_new_relay_budget(ctx) {
+        tracing::debug!(
+            fp = debug(fp),
+            "learned too many new relays lately, skipping"
+        );
+        return Ok(false);
+    }
+
+    // fetch and insert the identity. we do this even for known relays, since identity descriptors may change over time
+    let Some(id) = LinkClient(link.rpc_transport()).identity(fp).await? else {
+        return Ok(false);
+    };
+    if id.identity_pk.fingerprint() != fp {
+        strike(
+            ctx,
+            neigh,
+            anyhow::anyhow!("asked for the identity of {fp}, got another relay's"),
+        );
+        return Ok(false);
+    }
+    if let Err(err) = ctx
+        .get(RELAY_GRAPH)
+        .write()
+        .insert_gossiped_identity(id.clone(), neighbor_id(neigh))
+    {
+        identity_rejected(ctx, neigh, err);
+        return Ok(false);
+    }
+    ctx.get(IDENTITY_CACHE).insert(fp, id);
+    Ok(true)
 }
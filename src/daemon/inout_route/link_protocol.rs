@@ -2,11 +2,12 @@ use async_trait::async_trait;
 use bytes::Bytes;
 
 use earendil_crypt::{RelayFingerprint, RelayIdentityPublic};
-use earendil_topology::{AdjacencyDescriptor, IdentityDescriptor};
+use earendil_topology::{AdjacencyDescriptor, GraphDelta, GraphDigest, IdentityDescriptor};
 use nanorpc::nanorpc_derive;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 
+use crate::debts::PriceInfo;
 use crate::settlement::{Seed, SettlementRequest, SettlementResponse};
 
 #[nanorpc_derive]
@@ -27,12 +28,21 @@ pub trait LinkProtocol {
     /// Gets all the adjacency-descriptors adjacent to the given fingerprints. This is called repeatedly to eventually discover the entire graph.
     async fn adjacencies(&self, fps: Vec<RelayFingerprint>) -> Vec<AdjacencyDescriptor>;
 
+    /// Gets a hash of the digest of the other end's relay graph. If it matches the hash of ours, the two graphs already agree and there is nothing to gossip.
+    async fn graph_summary(&self) -> u64;
+
+    /// Gets the descriptors that a relay graph with the given digest is missing, at most `limit` of each kind.
+    async fn graph_delta(&self, digest: GraphDigest, limit: usize) -> GraphDelta;
+
     /// Sends a settlement request and waits until a response is received or the call times out.
     async fn start_settlement(&self, req: SettlementRequest) -> Option<SettlementResponse>;
 
     /// Send a chat message to the other end of the link.
     async fn push_chat(&self, msg: String);
 
+    /// Asks the other end how much it charges per message we send it, and how much debt it tolerates before it stops forwarding our traffic.
+    async fn link_price(&self) -> PriceInfo;
+
     /// Request a MelPoW seed (used to create an automatic payment proof).
     async fn request_seed(&self) -> Option<Seed>;
 }
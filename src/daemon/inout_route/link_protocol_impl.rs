@@ -1,13 +1,23 @@
+use std::time::Duration;
+
 use async_trait::async_trait;
 
 use earendil_crypt::{ClientId, RelayFingerprint};
 
-use earendil_topology::{AdjacencyDescriptor, IdentityDescriptor};
+use earendil_topology::{AdjacencyDescriptor, GraphDelta, GraphDigest, IdentityDescriptor};
 
+use either::Either;
 use itertools::Itertools;
+use smol_timeout::TimeoutExt;
 
+use crate::config::LinkPrice;
+use crate::control_protocol::DaemonEvent;
 use crate::daemon::chat::{ChatEntry, CHATS};
-use crate::settlement::{Seed, SettlementRequest, SettlementResponse};
+use crate::debts::PriceInfo;
+use crate::events::EVENTS;
+use crate::settlement::{
+    Seed, SettlementProof, SettlementRequest, SettlementResponse, SETTLEMENTS,
+};
 use crate::{
     context::{DaemonContext, MY_RELAY_IDENTITY, RELAY_GRAPH},
     network::is_relay_neigh,
@@ -17,11 +27,16 @@ use super::link_protocol::{InfoResponse, LinkProtocol};
 
 const LABEL_LINK_RPC: &str = "link-rpc";
 
+/// The most descriptors of each kind we send in one graph delta, whatever the other end asks for.
+const MAX_DELTA_LIMIT: usize = 1000;
+
 pub struct LinkProtocolImpl {
     pub ctx: DaemonContext,
 
     pub remote_client_id: ClientId,
     pub remote_relay_fp: Option<RelayFingerprint>,
+
+    pub my_price: LinkPrice,
 }
 
 #[async_trait]
@@ -79,42 +94,67 @@ impl LinkProtocol for LinkProtocolImpl {
             .collect()
     }
 
+    async fn graph_summary(&self) -> u64 {
+        self.ctx.get(RELAY_GRAPH).read().digest().summary()
+    }
+
+    #[tracing::instrument(skip(self, digest))]
+    async fn graph_delta(&self, digest: GraphDigest, limit: usize) -> GraphDelta {
+        self.ctx
+            .get(RELAY_GRAPH)
+            .read()
+            .delta(&digest, limit.min(MAX_DELTA_LIMIT))
+    }
+
     #[tracing::instrument(skip(self))]
-    async fn start_settlement(&self, _req: SettlementRequest) -> Option<SettlementResponse> {
-        todo!()
-        // let settlements = self.ctx.get(SETTLEMENTS);
-
-        // match req.payment_proof {
-        //     SettlementProof::Automatic(_) => {
-        //         tracing::debug!("handling auto_settlement req: {:?}", req);
-        //         if let Ok(res) = settlements.verify_auto_settle(&self.ctx, req) {
-        //             res
-        //         } else {
-        //             None
-        //         }
-        //     }
-        //     SettlementProof::Manual => {
-        //         tracing::debug!("handling manual settlement req: {:?}", req);
-        //         let recv_res = settlements.insert_pending(req);
-
-        //         if let Ok(recv_res) = recv_res {
-        //             match recv_res.recv().timeout(Duration::from_secs(300)).await {
-        //                 Some(Ok(res)) => res,
-        //                 Some(Err(e)) => {
-        //                     log::warn!("settlement response receive error: {e}");
-        //                     None
-        //                 }
-        //                 None => None,
-        //             }
-        //         } else {
-        //             None
-        //         }
-        //     }
-        // }
+    async fn start_settlement(&self, req: SettlementRequest) -> Option<SettlementResponse> {
+        let settlements = self.ctx.get(SETTLEMENTS);
+
+        match req.payment_proof {
+            SettlementProof::Automatic(_) => {
+                tracing::debug!("handling auto_settlement req: {:?}", req);
+                match settlements.verify_auto_settle(&self.ctx, self.remote_neigh(), req) {
+                    Ok(res) => res,
+                    Err(err) => {
+                        tracing::debug!(err = debug(err), "rejecting auto_settlement req");
+                        None
+                    }
+                }
+            }
+            SettlementProof::Manual => {
+                tracing::debug!("handling manual settlement req: {:?}", req);
+                let amount = req.decrease();
+                let pending = match settlements.insert_pending(self.remote_neigh(), req) {
+                    Ok(pending) => pending,
+                    Err(err) => {
+                        tracing::debug!(err = debug(err), "rejecting manual settlement req");
+                        return None;
+                    }
+                };
+                self.ctx.get(EVENTS).emit(DaemonEvent::SettlementRequested {
+                    neighbor: self.remote_neigh().to_string(),
+                    amount,
+                });
+
+                // dropping `pending` after a timeout forgets the request, so it can't be accepted later
+                match pending.recv().timeout(Duration::from_secs(300)).await {
+                    Some(Ok(res)) => res,
+                    Some(Err(e)) => {
+                        tracing::warn!("settlement response receive error: {e}");
+                        None
+                    }
+                    None => None,
+                }
+            }
+        }
     }
 
     #[tracing::instrument(skip(self))]
     async fn push_chat(&self, msg: String) {
+        self.ctx.get(EVENTS).emit(DaemonEvent::ChatReceived {
+            neighbor: self.remote_neigh().to_string(),
+            text: msg.clone(),
+        });
         if let Some(fingerprint) = self.remote_relay_fp {
             self.ctx
                 .get(CHATS)
@@ -127,24 +167,23 @@ impl LinkProtocol for LinkProtocolImpl {
         }
     }
 
+    async fn link_price(&self) -> PriceInfo {
+        PriceInfo {
+            price: self.my_price.incoming_price,
+            debt_limit: self.my_price.incoming_debt_limit,
+        }
+    }
+
     #[tracing::instrument(skip(self))]
     async fn request_seed(&self) -> Option<Seed> {
-        todo!()
-        // let seed = rand::thread_rng().gen();
-        // let seed_cache = &self.ctx.get(SETTLEMENTS).seed_cache;
-
-        // match seed_cache.get(&self.remote_client_id) {
-        //     Some(mut seeds) => {
-        //         seeds.insert(seed);
-        //         seed_cache.insert(self.remote_client_id, seeds);
-        //         Some(seed)
-        //     }
-        //     None => {
-        //         let mut seed_set = HashSet::new();
-        //         seed_set.insert(seed);
-        //         seed_cache.insert(self.remote_client_id, seed_set);
-        //         Some(seed)
-        //     }
-        // }
+        self.ctx.get(SETTLEMENTS).new_seed(self.remote_neigh())
+    }
+}
+
+impl LinkProtocolImpl {
+    fn remote_neigh(&self) -> Either<ClientId, RelayFingerprint> {
+        self.remote_relay_fp
+            .map(Either::Right)
+            .unwrap_or(Either::Left(self.remote_client_id))
     }
 }
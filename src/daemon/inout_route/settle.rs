@@ -0,0 +1,62 @@
+use anyhow::Context;
+use earendil_crypt::{ClientId, RelayFingerprint, RelayIdentityPublic};
+use either::Either;
+
+use crate::{
+    context::{DaemonContext, DEBTS, MY_RELAY_IDENTITY},
+    daemon::{inout_route::link_protocol::LinkClient, link::Link},
+    settlement::{
+        deduct_payment, difficulty_to_micromel, SettlementProof, SettlementRequest,
+        MAX_AUTO_SETTLE_DIFFICULTY, SETTLEMENTS,
+    },
+};
+
+/// Pays off as much as possible of what we owe the neighbor, by sending it a MelPoW proof built from a seed it gives us.
+#[tracing::instrument(skip_all, fields(neigh=debug(neigh)))]
+pub async fn auto_settle_once(
+    ctx: &DaemonContext,
+    link: &Link,
+    neigh: Either<ClientId, RelayFingerprint>,
+    their_pk: Option<&RelayIdentityPublic>,
+) -> anyhow::Result<()> {
+    let debts = ctx.get(DEBTS);
+    let net_debt = match neigh {
+        Either::Left(id) => debts.client_net_debt_est(&id),
+        Either::Right(fp) => debts.relay_net_debt_est(&fp),
+    }
+    .unwrap_or_default();
+    // a negative net debt means that we owe the neighbor
+    if net_debt >= 0 {
+        return Ok(());
+    }
+    // never pay more than we owe
+    let difficulty = (net_debt.unsigned_abs().ilog2() as usize).min(MAX_AUTO_SETTLE_DIFFICULTY);
+    if difficulty == 0 {
+        return Ok(());
+    }
+
+    let link_client = LinkClient(link.rpc_transport());
+    let seed = link_client
+        .request_seed()
+        .await?
+        .context("neighbor does not accept automatic settlements")?;
+    tracing::debug!(difficulty, "computing automatic settlement proof");
+    let proof = smol::unblock(move || SettlementProof::new_auto(seed, difficulty)).await;
+    let amount =
+        difficulty_to_micromel(difficulty).expect("capped difficulties always have an amount");
+    let request = SettlementRequest::new(*ctx.get(MY_RELAY_IDENTITY), amount, proof);
+    let response = link_client
+        .start_settlement(request)
+        .await?
+        .context("neighbor refused our automatic settlement")?;
+    response.verify(their_pk)?;
+
+    deduct_payment(ctx, neigh, amount);
+    tracing::debug!(
+        amount,
+        their_current_debt = response.current_debt,
+        "automatic settlement done"
+    );
+    ctx.get(SETTLEMENTS).record(neigh, false, response);
+    Ok(())
+}
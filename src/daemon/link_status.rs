@@ -0,0 +1,129 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use dashmap::DashMap;
+use earendil_crypt::{ClientId, RelayFingerprint};
+use parking_lot::Mutex;
+
+use crate::{
+    context::{CtxField, DaemonContext},
+    control_protocol::{DaemonEvent, LinkInfo},
+    events::EVENTS,
+    network::{client_queue_depth, relay_queue_depth},
+};
+
+/// The status of every live link, keyed by an ID that is never reused, since there can be several links to the same neighbor.
+pub static LINK_STATUS: CtxField<DashMap<u64, Arc<LinkStatus>>> = |_| DashMap::new();
+
+static NEXT_LINK_ID: CtxField<AtomicU64> = |_| AtomicU64::new(0);
+
+/// Where a link came from, before we know who is at the other end.
+#[derive(Clone, Debug)]
+pub struct LinkOrigin {
+    /// The name of the in-route or out-route that the link belongs to
+    pub route: String,
+    pub remote_addr: Option<String>,
+    pub obfs: &'static str,
+}
+
+/// Live statistics of one link.
+pub struct LinkStatus {
+    pub origin: LinkOrigin,
+    pub client_id: ClientId,
+    pub relay_fp: Option<RelayFingerprint>,
+    pub connected: SystemTime,
+    /// The version that the neighbor reported through the link RPC, once it has answered.
+    pub version: Mutex<Option<String>>,
+    /// The round-trip time of the neighbor's latest answer through the link RPC.
+    pub rtt: Mutex<Option<Duration>>,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub packets_in: AtomicU64,
+    pub packets_out: AtomicU64,
+}
+
+impl LinkStatus {
+    pub fn record_incoming(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_outgoing(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_info(&self, version: String, rtt: Duration) {
+        *self.version.lock() = Some(version);
+        *self.rtt.lock() = Some(rtt);
+    }
+
+    /// The relay fingerprint of the neighbor, or its client ID if it isn't a relay.
+    pub fn neighbor(&self) -> String {
+        self.relay_fp
+            .map_or(self.client_id.to_string(), |fp| fp.to_string())
+    }
+
+    /// Takes a snapshot of the statistics for the control protocol.
+    pub fn info(&self, ctx: &DaemonContext) -> LinkInfo {
+        // links to relays carry both relay and client messages
+        let queue_depth = client_queue_depth(ctx, self.client_id)
+            + self.relay_fp.map_or(0, |fp| relay_queue_depth(ctx, fp));
+        LinkInfo {
+            route: self.origin.route.clone(),
+            remote_addr: self.origin.remote_addr.clone(),
+            obfs: self.origin.obfs.to_string(),
+            neighbor: self.neighbor(),
+            is_relay: self.relay_fp.is_some(),
+            uptime_secs: self.connected.elapsed().unwrap_or_default().as_secs(),
+            version: self.version.lock().clone(),
+            rtt_ms: self.rtt.lock().map(|rtt| rtt.as_secs_f64() * 1000.0),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            packets_in: self.packets_in.load(Ordering::Relaxed),
+            packets_out: self.packets_out.load(Ordering::Relaxed),
+            queue_depth,
+        }
+    }
+}
+
+/// Registers a live link. The returned guard unregisters it when dropped, which happens when the link dies.
+pub fn register_link(
+    ctx: &DaemonContext,
+    origin: LinkOrigin,
+    client_id: ClientId,
+    relay_fp: Option<RelayFingerprint>,
+) -> scopeguard::ScopeGuard<Arc<LinkStatus>, impl FnOnce(Arc<LinkStatus>) + '_> {
+    let id = ctx.get(NEXT_LINK_ID).fetch_add(1, Ordering::Relaxed);
+    let status = Arc::new(LinkStatus {
+        origin,
+        client_id,
+        relay_fp,
+        connected: SystemTime::now(),
+        version: Mutex::new(None),
+        rtt: Mutex::new(None),
+        bytes_in: AtomicU64::new(0),
+        bytes_out: AtomicU64::new(0),
+        packets_in: AtomicU64::new(0),
+        packets_out: AtomicU64::new(0),
+    });
+    ctx.get(LINK_STATUS).insert(id, status.clone());
+    ctx.get(EVENTS).emit(DaemonEvent::NeighborUp {
+        neighbor: status.neighbor(),
+        is_relay: relay_fp.is_some(),
+        route: status.origin.route.clone(),
+    });
+    scopeguard::guard(status, move |status| {
+        ctx.get(LINK_STATUS).remove(&id);
+        ctx.get(EVENTS).emit(DaemonEvent::NeighborDown {
+            neighbor: status.neighbor(),
+            is_relay: status.relay_fp.is_some(),
+            route: status.origin.route.clone(),
+        });
+    })
+}
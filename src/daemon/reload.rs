@@ -0,0 +1,388 @@
+use std::{collections::BTreeMap, fmt, net::SocketAddr, path::PathBuf, time::Duration};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use smol::channel::{Receiver, Sender};
+
+use crate::{
+    config::{
+        ConfigFile, HavenConfig, InRouteConfig, OutRouteConfig, Socks5Config, TcpForwardConfig,
+        UdpForwardConfig,
+    },
+    context::{CtxField, DaemonContext, MY_RELAY_IDENTITY},
+    control_protocol::ReloadReport,
+};
+
+use super::{
+    forward_status::FORWARD_STATUS,
+    inout_route::{dial_out_route, listen_in_route},
+    serve_haven::serve_haven,
+    socks5::socks5_loop,
+    tcp_forward::tcp_forward_loop,
+    udp_forward::udp_forward_loop,
+};
+
+/// The file the daemon's config was loaded from, which is where reloads read the new config from.
+pub static CONFIG_PATH: CtxField<RwLock<Option<PathBuf>>> = |_| RwLock::new(None);
+
+/// The config that the daemon currently runs, which is the one it started with until a reload replaces it.
+pub static LIVE_CONFIG: CtxField<RwLock<ConfigFile>> = |ctx| RwLock::new(ctx.init().clone());
+
+/// The config-driven tasks that are currently running. Dropping a task stops it.
+static RUNNING_TASKS: CtxField<smol::lock::Mutex<BTreeMap<TaskKey, RunningTask>>> =
+    |_| smol::lock::Mutex::new(BTreeMap::new());
+
+/// Errors from the tasks that the daemon started with, which are fatal to the whole daemon.
+static TASK_FAILURES: CtxField<(Sender<anyhow::Error>, Receiver<anyhow::Error>)> =
+    |_| smol::channel::unbounded();
+
+/// The last error of each task started by a reload. These don't take the daemon down, since a typo in a reloaded config shouldn't kill everything that was running fine.
+static RELOADED_TASK_ERRORS: CtxField<Mutex<BTreeMap<TaskKey, String>>> =
+    |_| Mutex::new(BTreeMap::new());
+
+/// How long a reload waits for the tasks it started to fail right away, such as when their ports are taken, so that it can report them.
+const START_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Identifies one of the tasks that run a part of the config, so that reloads can tell which ones changed.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum TaskKey {
+    InRoute(String),
+    OutRoute(String),
+    Haven(String, u16),
+    Socks5,
+    TcpForward(SocketAddr),
+    UdpForward(SocketAddr),
+}
+
+impl fmt::Display for TaskKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskKey::InRoute(name) => write!(f, "in_route {name}"),
+            TaskKey::OutRoute(name) => write!(f, "out_route {name}"),
+            TaskKey::Haven(fp, port) => write!(f, "haven {fp}:{port}"),
+            TaskKey::Socks5 => write!(f, "socks5"),
+            TaskKey::TcpForward(listen) => write!(f, "tcp_forward {listen}"),
+            TaskKey::UdpForward(listen) => write!(f, "udp_forward {listen}"),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+enum TaskConfig {
+    InRoute(InRouteConfig),
+    OutRoute(OutRouteConfig),
+    Haven(HavenConfig),
+    Socks5(Socks5Config),
+    TcpForward(TcpForwardConfig),
+    UdpForward(UdpForwardConfig),
+}
+
+struct RunningTask {
+    /// what the task was started with, to compare new configs against
+    config: serde_json::Value,
+    task: smol::Task<()>,
+}
+
+/// Parts of the config that are only read when the daemon starts.
+const RESTART_ONLY_FIELDS: &[&str] = &[
+    "state_cache",
+    "control_listen",
+    "control_unix",
+    "control_auth",
+    "onion_key_rotation",
+    "route_policy",
+    "max_mix_delay_ms",
+    "cover_traffic",
+    "relay_graph",
+    "gossip",
+    "bootstrap_snapshots",
+    "auto_settle",
+];
+
+/// Starts the tasks that the daemon's initial config asks for, then waits for any of them to fail.
+pub async fn run_config_tasks(ctx: &DaemonContext) -> anyhow::Result<()> {
+    apply_config(ctx, ctx.init(), true).await?;
+    let err = ctx.get(TASK_FAILURES).1.recv().await?;
+    Err(err)
+}
+
+/// Rereads the config file and applies it to the running daemon.
+pub async fn reload_config(ctx: &DaemonContext) -> anyhow::Result<ReloadReport> {
+    let path = ctx
+        .get(CONFIG_PATH)
+        .read()
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("the daemon was not started from a config file"))?;
+    let mut new_cfg = ConfigFile::load(&path)?;
+    super::ensure_config_valid(&new_cfg)?;
+    super::add_self_out_route(&mut new_cfg)?;
+
+    let my_fp = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|id| id.public().fingerprint());
+    let new_fp = match &new_cfg.identity {
+        Some(identity) => Some(identity.actualize_relay()?.public().fingerprint()),
+        None => None,
+    };
+    anyhow::ensure!(
+        my_fp == new_fp,
+        "the identity cannot change without restarting the daemon"
+    );
+
+    let mut report = apply_config(ctx, &new_cfg, false).await?;
+    smol::Timer::after(START_GRACE_PERIOD).await;
+    {
+        let running = ctx.get(RUNNING_TASKS).lock().await;
+        let errors = ctx.get(RELOADED_TASK_ERRORS).lock();
+        report.failed = running
+            .iter()
+            .filter(|(_, task)| task.task.is_finished())
+            .filter_map(|(key, _)| Some(format!("{key}: {}", errors.get(key)?)))
+            .collect();
+    }
+    let old_json = serde_json::to_value(ctx.init())?;
+    let new_json = serde_json::to_value(&new_cfg)?;
+    report.needs_restart = RESTART_ONLY_FIELDS
+        .iter()
+        .filter(|field| old_json.get(**field) != new_json.get(**field))
+        .map(|field| field.to_string())
+        .collect();
+    *ctx.get(LIVE_CONFIG).write() = new_cfg;
+    tracing::info!(report = debug(&report), "reloaded config");
+    Ok(report)
+}
+
+/// Starts, stops and restarts tasks so that exactly those that the config asks for are running. Failures of the started tasks are fatal to the daemon only if `fatal` is set.
+async fn apply_config(
+    ctx: &DaemonContext,
+    cfg: &ConfigFile,
+    fatal: bool,
+) -> anyhow::Result<ReloadReport> {
+    let wanted = wanted_tasks(cfg)?;
+    let mut running = ctx.get(RUNNING_TASKS).lock().await;
+    let plan = plan_tasks(&running, wanted)?;
+
+    // wait for the old tasks to stop before starting new ones, since they may want the same ports
+    for key in plan
+        .to_stop
+        .iter()
+        .chain(plan.to_start.iter().map(|(key, _, _)| key))
+    {
+        if let Some(task) = running.remove(key) {
+            tracing::debug!(key = display(key), "stopping task");
+            task.task.cancel().await;
+            if let TaskKey::TcpForward(listen) | TaskKey::UdpForward(listen) = key {
+                ctx.get(FORWARD_STATUS).remove(listen);
+            }
+        }
+    }
+    for (key, task_cfg, config) in plan.to_start {
+        ctx.get(RELOADED_TASK_ERRORS).lock().remove(&key);
+        let task = spawn_task(ctx, key.clone(), task_cfg, fatal);
+        running.insert(key, RunningTask { config, task });
+    }
+    Ok(plan.report)
+}
+
+/// What a config change takes: which tasks to stop, and which to start with what config.
+struct TaskPlan {
+    report: ReloadReport,
+    to_stop: Vec<TaskKey>,
+    to_start: Vec<(TaskKey, TaskConfig, serde_json::Value)>,
+}
+
+/// Compares the running tasks with the wanted ones. Tasks whose config changed are restarted, and so are tasks that already failed.
+fn plan_tasks(
+    running: &BTreeMap<TaskKey, RunningTask>,
+    wanted: BTreeMap<TaskKey, TaskConfig>,
+) -> anyhow::Result<TaskPlan> {
+    let mut report = ReloadReport::default();
+    let to_stop: Vec<TaskKey> = running
+        .keys()
+        .filter(|key| !wanted.contains_key(key))
+        .cloned()
+        .collect();
+    report.stopped = to_stop.iter().map(|key| key.to_string()).collect();
+
+    let mut to_start = vec![];
+    for (key, task_cfg) in wanted {
+        let config = serde_json::to_value(&task_cfg)?;
+        match running.get(&key) {
+            Some(task) if task.config == config && !task.task.is_finished() => continue,
+            Some(_) => report.restarted.push(key.to_string()),
+            None => report.started.push(key.to_string()),
+        }
+        to_start.push((key, task_cfg, config));
+    }
+    Ok(TaskPlan {
+        report,
+        to_stop,
+        to_start,
+    })
+}
+
+fn wanted_tasks(cfg: &ConfigFile) -> anyhow::Result<BTreeMap<TaskKey, TaskConfig>> {
+    let mut wanted = BTreeMap::new();
+    for (name, route) in cfg.in_routes.iter() {
+        wanted.insert(
+            TaskKey::InRoute(name.clone()),
+            TaskConfig::InRoute(route.clone()),
+        );
+    }
+    for (name, route) in cfg.out_routes.iter() {
+        wanted.insert(
+            TaskKey::OutRoute(name.clone()),
+            TaskConfig::OutRoute(route.clone()),
+        );
+    }
+    for haven in cfg.havens.iter() {
+        let fp = haven.identity.actualize_haven()?.public().fingerprint();
+        wanted.insert(
+            TaskKey::Haven(fp.to_string(), haven.listen_port),
+            TaskConfig::Haven(haven.clone()),
+        );
+    }
+    if let Some(socks5) = cfg.socks5 {
+        wanted.insert(TaskKey::Socks5, TaskConfig::Socks5(socks5));
+    }
+    for forward in cfg.tcp_forwards.iter() {
+        wanted.insert(
+            TaskKey::TcpForward(forward.listen),
+            TaskConfig::TcpForward(forward.clone()),
+        );
+    }
+    for forward in cfg.udp_forwards.iter() {
+        wanted.insert(
+            TaskKey::UdpForward(forward.listen),
+            TaskConfig::UdpForward(forward.clone()),
+        );
+    }
+    Ok(wanted)
+}
+
+fn spawn_task(ctx: &DaemonContext, key: TaskKey, cfg: TaskConfig, fatal: bool) -> smol::Task<()> {
+    let ctx = ctx.clone();
+    let send_failure = ctx.get(TASK_FAILURES).0.clone();
+    smolscale::spawn(async move {
+        let ctx = &ctx;
+        let fut: BoxFuture<anyhow::Result<()>> = match (&key, cfg) {
+            (TaskKey::InRoute(name), TaskConfig::InRoute(cfg)) => {
+                async move { listen_in_route(ctx, name, &cfg).await }.boxed()
+            }
+            (TaskKey::OutRoute(name), TaskConfig::OutRoute(cfg)) => {
+                async move { dial_out_route(ctx, name, &cfg).await }.boxed()
+            }
+            (_, TaskConfig::Haven(cfg)) => async move { serve_haven(ctx, &cfg).await }.boxed(),
+            (_, TaskConfig::Socks5(cfg)) => socks5_loop(ctx, cfg).boxed(),
+            (_, TaskConfig::TcpForward(cfg)) => tcp_forward_loop(ctx, cfg).boxed(),
+            (_, TaskConfig::UdpForward(cfg)) => udp_forward_loop(ctx, cfg).boxed(),
+            _ => unreachable!("task keys always come with the matching config"),
+        };
+        if let Err(err) = fut.await {
+            if fatal {
+                let _ = send_failure
+                    .send(err.context(format!("{key} failed")))
+                    .await;
+            } else {
+                tracing::warn!(
+                    key = display(&key),
+                    err = debug(&err),
+                    "reloaded task failed"
+                );
+                ctx.get(RELOADED_TASK_ERRORS)
+                    .lock()
+                    .insert(key, format!("{err:#}"));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> ConfigFile {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn running(cfg: &ConfigFile) -> BTreeMap<TaskKey, RunningTask> {
+        wanted_tasks(cfg)
+            .unwrap()
+            .into_iter()
+            .map(|(key, task_cfg)| {
+                let task = RunningTask {
+                    config: serde_json::to_value(task_cfg).unwrap(),
+                    task: smolscale::spawn(std::future::pending()),
+                };
+                (key, task)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_changed_tasks_are_touched() {
+        let old = config(
+            r#"
+in_routes:
+  main:
+    listen: 0.0.0.0:19999
+    obfs: none
+out_routes:
+  a:
+    connect: 1.2.3.4:19999
+    fingerprint: 33733f5086b8a7ebe32f3eb5f974085361475138ae06fd12105dd293fb3123f5
+    obfs: none
+  b:
+    connect: 1.2.3.5:19999
+    fingerprint: 33733f5086b8a7ebe32f3eb5f974085361475138ae06fd12105dd293fb3123f5
+    obfs: none
+"#,
+        );
+        let new = config(
+            r#"
+in_routes:
+  main:
+    listen: 0.0.0.0:20000
+    obfs: none
+out_routes:
+  a:
+    connect: 1.2.3.4:19999
+    fingerprint: 33733f5086b8a7ebe32f3eb5f974085361475138ae06fd12105dd293fb3123f5
+    obfs: none
+  c:
+    connect: 1.2.3.6:19999
+    fingerprint: 33733f5086b8a7ebe32f3eb5f974085361475138ae06fd12105dd293fb3123f5
+    obfs: none
+"#,
+        );
+        let mut running = running(&old);
+
+        let plan = plan_tasks(&running, wanted_tasks(&new).unwrap()).unwrap();
+        assert_eq!(plan.report.started, vec!["out_route c"]);
+        assert_eq!(plan.report.stopped, vec!["out_route b"]);
+        assert_eq!(plan.report.restarted, vec!["in_route main"]);
+        assert_eq!(plan.to_stop, vec![TaskKey::OutRoute("b".into())]);
+        let to_start: Vec<TaskKey> = plan.to_start.into_iter().map(|(key, _, _)| key).collect();
+        assert_eq!(
+            to_start,
+            vec![
+                TaskKey::InRoute("main".into()),
+                TaskKey::OutRoute("c".into())
+            ]
+        );
+
+        // reapplying the same config does nothing
+        let plan = plan_tasks(&running, wanted_tasks(&old).unwrap()).unwrap();
+        assert!(plan.to_stop.is_empty() && plan.to_start.is_empty());
+
+        // unless a task has died, in which case it gets another chance
+        let dead = running.get_mut(&TaskKey::OutRoute("a".into())).unwrap();
+        dead.task = smolscale::spawn(async {});
+        while !dead.task.is_finished() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let plan = plan_tasks(&running, wanted_tasks(&old).unwrap()).unwrap();
+        assert_eq!(plan.report.restarted, vec!["out_route a"]);
+    }
+}
@@ -1,21 +1,39 @@
-use crate::HavenHandler;
-use crate::{context::DaemonContext, HavenConfig, HavenListener, PooledListener};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{context::DaemonContext, HavenConfig, HavenListener, HavenPacketConn, PooledListener};
+use crate::{control_protocol::DaemonEvent, events::EVENTS, HavenHandler};
 use anyhow::Context as _;
 use futures::AsyncReadExt;
 use nursery_macro::nursery;
-use smol::future::FutureExt;
+use parking_lot::Mutex;
+use smol::{future::FutureExt, net::UdpSocket};
+
+use super::udp_forward::UDP_SESSION_IDLE;
 
 pub async fn serve_haven(ctx: &DaemonContext, cfg: &HavenConfig) -> anyhow::Result<()> {
     let identity = cfg.identity.actualize_haven()?;
-    let listener = PooledListener::new(
-        HavenListener::bind(ctx, identity, cfg.listen_port, cfg.rendezvous).await?,
-    );
+    let listener = HavenListener::bind(ctx, identity, cfg.listen_port, cfg.rendezvous).await?;
+    let accepted = || {
+        ctx.get(EVENTS).emit(DaemonEvent::HavenConnectionAccepted {
+            haven: identity.public().fingerprint().to_string(),
+            port: cfg.listen_port,
+        })
+    };
+    if let HavenHandler::UdpService { upstream } = cfg.handler {
+        return serve_udp_service(listener, upstream, accepted).await;
+    }
+
+    let listener = PooledListener::new(listener);
     nursery!({
         loop {
             let client = listener
                 .accept()
                 .await
                 .context("could not accept another from PooledListener")?;
+            accepted();
             let handler = &cfg.handler;
             spawn!(async move {
                 match handler {
@@ -37,6 +55,9 @@ pub async fn serve_haven(ctx: &DaemonContext, cfg: &HavenConfig) -> anyhow::Resu
                             .race(smol::io::copy(upstream.clone(), write_client))
                             .await?
                     }
+                    HavenHandler::UdpService { .. } => {
+                        anyhow::bail!("UDP services do not serve streams")
+                    }
                 };
                 anyhow::Ok(())
             })
@@ -44,3 +65,63 @@ pub async fn serve_haven(ctx: &DaemonContext, cfg: &HavenConfig) -> anyhow::Resu
         }
     })
 }
+
+/// Relays every incoming [HavenPacketConn] to the upstream UDP service, using a fresh local UDP socket for each visitor session.
+async fn serve_udp_service(
+    listener: HavenListener,
+    upstream: SocketAddr,
+    accepted: impl Fn(),
+) -> anyhow::Result<()> {
+    nursery!({
+        loop {
+            let conn = listener
+                .accept()
+                .await
+                .context("could not accept another from HavenListener")?;
+            accepted();
+            tracing::debug!(upstream = debug(upstream), "serving a udp service");
+            spawn!(async move {
+                if let Err(err) = udp_service_once(conn, upstream).await {
+                    tracing::debug!(err = debug(err), "udp service session died");
+                }
+            })
+            .detach()
+        }
+    })
+}
+
+async fn udp_service_once(conn: HavenPacketConn, upstream: SocketAddr) -> anyhow::Result<()> {
+    let bind_addr = if upstream.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(upstream).await?;
+    let last_active = Mutex::new(Instant::now());
+
+    let up_loop = async {
+        loop {
+            let pkt = conn.recv_pkt().await?;
+            *last_active.lock() = Instant::now();
+            socket.send(&pkt).await?;
+        }
+    };
+    let dn_loop = async {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            *last_active.lock() = Instant::now();
+            conn.send_pkt(&buf[..n]).await?;
+        }
+    };
+    let idle_loop = async {
+        loop {
+            smol::Timer::after(Duration::from_secs(10)).await;
+            if last_active.lock().elapsed() > UDP_SESSION_IDLE {
+                anyhow::bail!("udp session idle for too long")
+            }
+        }
+    };
+    up_loop.race(dn_loop).race(idle_loop).await
+}
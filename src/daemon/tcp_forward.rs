@@ -0,0 +1,51 @@
+use futures::AsyncReadExt;
+use futures_util::TryFutureExt;
+use nursery_macro::nursery;
+use smol::{
+    future::FutureExt as _,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{context::DaemonContext, PooledVisitor, TcpForwardConfig};
+
+use super::forward_status::{
+    bind_forward, forward_conn_started, forward_error, register_forward, FORWARD_STATUS,
+};
+
+/// Listens on the local address of a TCP forward, proxying every accepted connection to the remote haven endpoint.
+#[tracing::instrument(skip_all, fields(listen=debug(cfg.listen), remote=display(cfg.remote)))]
+pub async fn tcp_forward_loop(ctx: &DaemonContext, cfg: TcpForwardConfig) -> anyhow::Result<()> {
+    let status = ctx.get(FORWARD_STATUS);
+    register_forward(status, "tcp", cfg.listen, cfg.remote);
+
+    let tcp_listener = bind_forward(status, cfg.listen, || TcpListener::bind(cfg.listen)).await;
+    let pool = PooledVisitor::new(ctx.clone());
+
+    nursery!(loop {
+        let (client_stream, remote_addr) = tcp_listener.accept().await?;
+        tracing::debug!(
+            remote_addr = debug(remote_addr),
+            "accepted a TCP forward connection"
+        );
+        spawn!(tcp_forward_once(ctx, client_stream, &cfg, &pool)
+            .inspect_err(|e| forward_error(status, cfg.listen, e))
+            .map_err(|e| tracing::debug!(err = debug(e), "tcp forward worker failed")))
+        .detach();
+    })
+}
+
+async fn tcp_forward_once(
+    ctx: &DaemonContext,
+    client_stream: TcpStream,
+    cfg: &TcpForwardConfig,
+    pool: &PooledVisitor,
+) -> anyhow::Result<()> {
+    client_stream.set_nodelay(true)?;
+    let _conn_guard = forward_conn_started(ctx.get(FORWARD_STATUS), cfg.listen);
+    let earendil_stream = pool.connect(cfg.remote, b"").await?;
+    let (read, write) = earendil_stream.split();
+    smol::io::copy(read, client_stream.clone())
+        .race(smol::io::copy(client_stream, write))
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use moka::sync::Cache;
+use smol::{channel::Sender, future::FutureExt as _, net::UdpSocket, Task};
+
+use crate::{context::DaemonContext, HavenPacketConn, UdpForwardConfig};
+
+use super::forward_status::{
+    bind_forward, forward_conn_started, forward_error, register_forward, FORWARD_STATUS,
+};
+
+/// How long a UDP session can go without any traffic from its local source before it is torn down.
+pub const UDP_SESSION_IDLE: Duration = Duration::from_secs(120);
+
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// A session between a local UDP source address and the remote haven.
+struct UdpSession {
+    send_upstream: Sender<Bytes>,
+    _task: Task<()>,
+}
+
+/// Listens on the local address of a UDP forward, relaying datagrams to and from the remote haven. Every local source address gets its own [HavenPacketConn].
+#[tracing::instrument(skip_all, fields(listen=debug(cfg.listen), remote=display(cfg.remote)))]
+pub async fn udp_forward_loop(ctx: &DaemonContext, cfg: UdpForwardConfig) -> anyhow::Result<()> {
+    let status = ctx.get(FORWARD_STATUS);
+    register_forward(status, "udp", cfg.listen, cfg.remote);
+
+    let socket = bind_forward(status, cfg.listen, || UdpSocket::bind(cfg.listen)).await;
+    let sessions: Cache<SocketAddr, Arc<UdpSession>> =
+        Cache::builder().time_to_idle(UDP_SESSION_IDLE).build();
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (n, src) = socket.recv_from(&mut buf).await?;
+        let pkt = Bytes::copy_from_slice(&buf[..n]);
+        let session = sessions.get_with(src, || {
+            tracing::debug!(src = debug(src), "new UDP forward session");
+            let (send_upstream, recv_upstream) = smol::channel::bounded(100);
+            let ctx = ctx.clone();
+            let socket = socket.clone();
+            let cfg = cfg.clone();
+            Arc::new(UdpSession {
+                send_upstream,
+                _task: smolscale::spawn(async move {
+                    let status = ctx.get(FORWARD_STATUS);
+                    let _session_guard = forward_conn_started(status, cfg.listen);
+                    let session = async {
+                        let conn = HavenPacketConn::connect(&ctx, cfg.remote).await?;
+                        let up_loop = async {
+                            loop {
+                                let pkt = recv_upstream.recv().await?;
+                                conn.send_pkt(&pkt).await?;
+                            }
+                        };
+                        let dn_loop = async {
+                            loop {
+                                let pkt = conn.recv_pkt().await?;
+                                socket.send_to(&pkt, src).await?;
+                            }
+                        };
+                        up_loop.race(dn_loop).await
+                    };
+                    let res: anyhow::Result<()> = session.await;
+                    if let Err(err) = res {
+                        tracing::debug!(err = debug(&err), src = debug(src), "UDP session died");
+                        forward_error(status, cfg.listen, &err);
+                    }
+                }),
+            })
+        });
+        if session.send_upstream.try_send(pkt).is_err() && session.send_upstream.is_closed() {
+            // the session died, so the next datagram from this source starts a fresh one
+            sessions.invalidate(&src);
+        }
+    }
+}
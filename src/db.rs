@@ -55,3 +55,19 @@ pub async fn db_read(ctx: &DaemonContext, key: &str) -> Result<Option<Vec<u8>>,
         Ok(None)
     }
 }
+
+/// Deletes every key from `start` (inclusive) to `end` (exclusive).
+pub async fn db_delete_range(
+    ctx: &DaemonContext,
+    start: &str,
+    end: &str,
+) -> Result<(), sqlx::Error> {
+    if let Some(pool) = ctx.get(DATABASE) {
+        sqlx::query("DELETE FROM misc WHERE key >= ? AND key < ?")
+            .bind(start)
+            .bind(end)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
@@ -1,9 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use dashmap::DashMap;
 use earendil_crypt::{ClientId, RelayFingerprint};
+use either::Either;
 use serde::{Deserialize, Serialize};
 
+use crate::control_protocol::DebtInfo;
+
 pub struct Debts {
     client_incoming_prices: DashMap<ClientId, PriceInfo>,
     client_outgoing_prices: DashMap<ClientId, PriceInfo>,
@@ -13,7 +16,7 @@ pub struct Debts {
     relay_balances: DashMap<RelayFingerprint, Balances>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PriceInfo {
     pub price: u64,
     pub debt_limit: u64,
@@ -73,6 +76,13 @@ impl Debts {
             .insert(neigh, PriceInfo { price, debt_limit });
     }
 
+    /// The price the given neighboring relay charges us per packet, if we know it.
+    pub fn relay_outgoing_price(&self, neigh: &RelayFingerprint) -> Option<u64> {
+        self.relay_outgoing_prices
+            .get(neigh)
+            .map(|price_info| price_info.price)
+    }
+
     pub fn incr_relay_outgoing(&self, neigh: RelayFingerprint) {
         if let Some(price_info) = self.relay_outgoing_prices.get(&neigh) {
             let to_add = price_info.price;
@@ -83,6 +93,16 @@ impl Debts {
         }
     }
 
+    pub fn incr_client_outgoing(&self, neigh: ClientId) {
+        if let Some(price_info) = self.client_outgoing_prices.get(&neigh) {
+            let to_add = price_info.price;
+            self.client_balances
+                .entry(neigh)
+                .or_default()
+                .client_outgoing_balance += to_add;
+        }
+    }
+
     pub fn incr_client_incoming(&self, neigh: ClientId) {
         if let Some(price_info) = self.client_incoming_prices.get(&neigh) {
             let to_add = price_info.price;
@@ -103,20 +123,6 @@ impl Debts {
         }
     }
 
-    fn insert_client_incoming(&self, neigh: ClientId, new_debt: u64) {
-        self.client_balances
-            .entry(neigh)
-            .or_default()
-            .client_incoming_balance = new_debt;
-    }
-
-    fn insert_relay_incoming(&self, neigh: RelayFingerprint, new_debt: u64) {
-        self.relay_balances
-            .entry(neigh)
-            .or_default()
-            .relay_incoming_balance = new_debt;
-    }
-
     pub fn client_net_debt_est(&self, neigh: &ClientId) -> Option<i128> {
         self.client_balances
             .get(neigh)
@@ -129,6 +135,26 @@ impl Debts {
             .map(|b| b.relay_incoming_balance as i128 - b.relay_outgoing_balance as i128)
     }
 
+    /// What a client's net debt would be after it settles the given amount.
+    pub fn client_net_debt_after_settlement(&self, neigh: &ClientId, amount: u64) -> Option<i128> {
+        self.client_balances.get(neigh).map(|b| {
+            b.client_incoming_balance.saturating_sub(amount) as i128
+                - b.client_outgoing_balance as i128
+        })
+    }
+
+    /// What a relay's net debt would be after it settles the given amount.
+    pub fn relay_net_debt_after_settlement(
+        &self,
+        neigh: &RelayFingerprint,
+        amount: u64,
+    ) -> Option<i128> {
+        self.relay_balances.get(neigh).map(|b| {
+            b.relay_incoming_balance.saturating_sub(amount) as i128
+                - b.relay_outgoing_balance as i128
+        })
+    }
+
     pub fn client_is_within_debt_limit(&self, neigh: &ClientId) -> bool {
         if let Some(price_info) = self.client_incoming_prices.get(neigh) {
             if let Some(net) = self.client_net_debt_est(neigh) {
@@ -151,43 +177,88 @@ impl Debts {
         true
     }
 
-    pub fn list(&self) -> Vec<String> {
-        let client_balances = self.client_balances.iter().map(|entry| {
-            let fp = entry.key();
-            if let Some(debt) = self.client_net_debt_est(fp) {
-                format!("{fp} owes me {debt} micromel")
-            } else {
-                format!("no debt found for {fp}")
-            }
-        });
-
-        let relay_balances = self.relay_balances.iter().map(|entry| {
-            let fp = entry.key();
-            if let Some(debt) = self.relay_net_debt_est(fp) {
-                format!("{fp} owes me {debt} micromel")
-            } else {
-                format!("no debt found for {fp}")
-            }
-        });
-
-        client_balances
-            .chain(relay_balances)
-            .collect::<Vec<String>>()
+    /// Summarizes prices and balances for every neighbor we have ever priced or metered.
+    pub fn summary(&self) -> Vec<DebtInfo> {
+        let clients = self
+            .client_incoming_prices
+            .iter()
+            .map(|e| *e.key())
+            .chain(self.client_outgoing_prices.iter().map(|e| *e.key()))
+            .chain(self.client_balances.iter().map(|e| *e.key()))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .map(|id| {
+                let balances = self.client_balances.get(&id).map(|b| b.clone());
+                DebtInfo {
+                    neighbor: Either::Left(id),
+                    incoming_price: self.client_incoming_prices.get(&id).map(|p| p.clone()),
+                    outgoing_price: self.client_outgoing_prices.get(&id).map(|p| p.clone()),
+                    incoming_balance: balances
+                        .as_ref()
+                        .map(|b| b.client_incoming_balance)
+                        .unwrap_or_default(),
+                    outgoing_balance: balances
+                        .as_ref()
+                        .map(|b| b.client_outgoing_balance)
+                        .unwrap_or_default(),
+                    net_debt: self.client_net_debt_est(&id).unwrap_or_default(),
+                }
+            });
+        let relays = self
+            .relay_incoming_prices
+            .iter()
+            .map(|e| *e.key())
+            .chain(self.relay_outgoing_prices.iter().map(|e| *e.key()))
+            .chain(self.relay_balances.iter().map(|e| *e.key()))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .map(|fp| {
+                let balances = self.relay_balances.get(&fp).map(|b| b.clone());
+                DebtInfo {
+                    neighbor: Either::Right(fp),
+                    incoming_price: self.relay_incoming_prices.get(&fp).map(|p| p.clone()),
+                    outgoing_price: self.relay_outgoing_prices.get(&fp).map(|p| p.clone()),
+                    incoming_balance: balances
+                        .as_ref()
+                        .map(|b| b.relay_incoming_balance)
+                        .unwrap_or_default(),
+                    outgoing_balance: balances
+                        .as_ref()
+                        .map(|b| b.relay_outgoing_balance)
+                        .unwrap_or_default(),
+                    net_debt: self.relay_net_debt_est(&fp).unwrap_or_default(),
+                }
+            });
+        clients.chain(relays).collect()
     }
 
     pub fn deduct_client_settlement(&self, neigh: ClientId, amount: u64) {
-        if let Some(current_debt) = self.client_net_debt_est(&neigh) {
-            let debt = current_debt - amount as i128;
-            let settled_debt = if debt > 0 { debt as u64 } else { 0 };
-            self.insert_client_incoming(neigh, settled_debt);
+        if let Some(mut balances) = self.client_balances.get_mut(&neigh) {
+            balances.client_incoming_balance =
+                balances.client_incoming_balance.saturating_sub(amount);
         }
     }
 
     pub fn deduct_relay_settlement(&self, neigh: RelayFingerprint, amount: u64) {
-        if let Some(current_debt) = self.relay_net_debt_est(&neigh) {
-            let debt = current_debt - amount as i128;
-            let settled_debt = if debt > 0 { debt as u64 } else { 0 };
-            self.insert_relay_incoming(neigh, settled_debt);
+        if let Some(mut balances) = self.relay_balances.get_mut(&neigh) {
+            balances.relay_incoming_balance =
+                balances.relay_incoming_balance.saturating_sub(amount);
+        }
+    }
+
+    /// Records that we paid off some of what we owe a client neighbor.
+    pub fn deduct_client_payment(&self, neigh: ClientId, amount: u64) {
+        if let Some(mut balances) = self.client_balances.get_mut(&neigh) {
+            balances.client_outgoing_balance =
+                balances.client_outgoing_balance.saturating_sub(amount);
+        }
+    }
+
+    /// Records that we paid off some of what we owe a relay neighbor.
+    pub fn deduct_relay_payment(&self, neigh: RelayFingerprint, amount: u64) {
+        if let Some(mut balances) = self.relay_balances.get_mut(&neigh) {
+            balances.relay_outgoing_balance =
+                balances.relay_outgoing_balance.saturating_sub(amount);
         }
     }
 
@@ -272,3 +343,35 @@ impl Debts {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metering_and_settlement() {
+        let debts = Debts::new();
+        let neigh: ClientId = 42;
+        debts.insert_client_incoming_price(neigh, 10, 25);
+        debts.insert_client_outgoing_price(neigh, 1, 100);
+
+        for _ in 0..3 {
+            debts.incr_client_incoming(neigh);
+        }
+        debts.incr_client_outgoing(neigh);
+        assert_eq!(debts.client_net_debt_est(&neigh), Some(29));
+        assert!(!debts.client_is_within_debt_limit(&neigh));
+
+        debts.deduct_client_settlement(neigh, 20);
+        assert_eq!(debts.client_net_debt_est(&neigh), Some(9));
+        assert!(debts.client_is_within_debt_limit(&neigh));
+
+        let summary = debts.summary();
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0].incoming_balance, 10);
+        assert_eq!(summary[0].outgoing_balance, 1);
+
+        let restored = Debts::from_bytes(debts.as_bytes().unwrap()).unwrap();
+        assert_eq!(restored.client_net_debt_est(&neigh), Some(9));
+    }
+}
@@ -1,62 +1,182 @@
 use async_event::Event;
-use core::hash::Hash;
-
 use parking_lot::Mutex;
-use priority_queue::priority_queue::PriorityQueue;
 use smol::future::FutureExt;
-use std::{cmp::Reverse, time::Instant};
+use std::{cmp::Ordering, collections::BinaryHeap, time::Instant};
 
-pub struct DelayQueue<T: Hash + Eq> {
-    priority_queue: Mutex<PriorityQueue<T, Reverse<Instant>>>,
+/// A queue that holds every item until its emit time, so that a single task can emit delayed items instead of each one getting its own timer.
+pub struct DelayQueue<T> {
+    inner: Mutex<Inner<T>>,
     event: Event,
+    max_depth: usize,
+}
+
+struct Inner<T> {
+    heap: BinaryHeap<Entry<T>>,
+    /// breaks ties between items with the same emit time, so that they come out in insertion order
+    next_seq: u64,
+    stats: DelayQueueStats,
+}
+
+/// Counters describing what a [DelayQueue] did with its items.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DelayQueueStats {
+    /// items handed out after their delay elapsed
+    pub emitted: u64,
+    /// items dropped on insertion because the queue was full
+    pub overflowed: u64,
 }
 
-impl<T: Hash + Eq> DelayQueue<T> {
-    pub fn new() -> Self {
+impl<T> DelayQueue<T> {
+    /// Creates a queue that holds at most `max_depth` items at once.
+    pub fn new(max_depth: usize) -> Self {
         Self {
-            priority_queue: Mutex::new(PriorityQueue::new()),
+            inner: Mutex::new(Inner {
+                heap: BinaryHeap::new(),
+                next_seq: 0,
+                stats: DelayQueueStats::default(),
+            }),
             event: Event::new(),
+            max_depth,
         }
     }
-    /// inserts `item` with delay
-    pub fn insert(&self, item: T, emit_time: Instant) {
-        let _ = self.priority_queue.lock().push(item, Reverse(emit_time));
-        // eprintln!("notifying!");
+
+    /// Inserts `item`, to be emitted at `emit_time`. Returns false, dropping the item, if the queue is full.
+    pub fn insert(&self, item: T, emit_time: Instant) -> bool {
+        let mut inner = self.inner.lock();
+        if inner.heap.len() >= self.max_depth {
+            inner.stats.overflowed += 1;
+            return false;
+        }
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.heap.push(Entry {
+            emit_time,
+            seq,
+            item,
+        });
+        drop(inner);
         self.event.notify(1);
+        true
     }
 
-    /// *blocks* until the item with the shortest delay is ready, then returns it
+    /// *blocks* until the item with the earliest emit time is ready, then returns it
     pub async fn pop(&self) -> T {
         loop {
-            // first, we try to pop out the first one
-            let (val, Reverse(earliest_pop)) = self
-                .event
-                .wait_until(|| self.priority_queue.lock().pop())
-                .await;
-            let now = Instant::now();
-            if earliest_pop <= now {
-                return val;
+            let earliest_pop = self.event.wait_until(|| self.earliest()).await;
+            if earliest_pop <= Instant::now() {
+                let mut inner = self.inner.lock();
+                // someone else might have popped it in the meantime
+                if inner
+                    .heap
+                    .peek()
+                    .is_some_and(|entry| entry.emit_time <= Instant::now())
+                {
+                    inner.stats.emitted += 1;
+                    return inner.heap.pop().unwrap().item;
+                }
+                continue;
             }
 
-            // otherwise, we put it back and wait until *at most* the correct time
-            self.priority_queue.lock().push(val, Reverse(earliest_pop));
+            // wait until *at most* the correct time, or until an earlier item comes in
             smol::Timer::at(earliest_pop)
                 .race(self.wait_till_earlier(earliest_pop))
                 .await;
         }
     }
 
+    /// The number of items currently waiting in the queue.
+    pub fn depth(&self) -> usize {
+        self.inner.lock().heap.len()
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn stats(&self) -> DelayQueueStats {
+        self.inner.lock().stats
+    }
+
+    fn earliest(&self) -> Option<Instant> {
+        self.inner.lock().heap.peek().map(|entry| entry.emit_time)
+    }
+
     async fn wait_till_earlier(&self, earliest_pop: Instant) -> Instant {
         self.event
-            .wait_until(|| {
-                self.priority_queue.lock().peek().and_then(|(_, until)| {
-                    if until.0 < earliest_pop {
-                        Some(until.0.clone())
-                    } else {
-                        None
-                    }
-                })
-            })
+            .wait_until(|| self.earliest().filter(|until| *until < earliest_pop))
             .await
     }
 }
+
+struct Entry<T> {
+    emit_time: Instant,
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    // reversed, since BinaryHeap is a max-heap and we want the earliest item on top
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.emit_time, other.seq).cmp(&(self.emit_time, self.seq))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn emits_in_time_order() {
+        let queue = DelayQueue::new(10);
+        let now = Instant::now();
+        assert!(queue.insert(3, now + Duration::from_millis(30)));
+        assert!(queue.insert(1, now + Duration::from_millis(10)));
+        assert!(queue.insert(2, now + Duration::from_millis(10)));
+        let popped: Vec<i32> =
+            smol::block_on(async { vec![queue.pop().await, queue.pop().await, queue.pop().await] });
+        assert_eq!(popped, vec![1, 2, 3]);
+        assert!(now.elapsed() >= Duration::from_millis(30));
+        assert_eq!(queue.stats().emitted, 3);
+    }
+
+    #[test]
+    fn earlier_item_overtakes_pending_pop() {
+        let queue = DelayQueue::new(10);
+        let now = Instant::now();
+        queue.insert("late", now + Duration::from_secs(10));
+        let first = smol::block_on(async { queue.pop().await }.race(async {
+            smol::Timer::after(Duration::from_millis(10)).await;
+            queue.insert("early", Instant::now());
+            smol::future::pending().await
+        }));
+        assert_eq!(first, "early");
+        assert_eq!(queue.depth(), 1);
+    }
+
+    #[test]
+    fn drops_when_full() {
+        let queue = DelayQueue::new(2);
+        let now = Instant::now();
+        assert!(queue.insert(1, now));
+        assert!(queue.insert(2, now));
+        assert!(!queue.insert(3, now));
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(queue.stats().overflowed, 1);
+    }
+}
@@ -23,8 +23,12 @@ static DHT_CACHE: CtxField<Cache<HavenFingerprint, HavenLocator>> = |_| {
         .build()
 };
 
-/// Insert a locator into the DHT.
-pub async fn dht_insert(ctx: &DaemonContext, locator: HavenLocator, n2r_skt: &N2rClientSocket) {
+/// Insert a locator into the DHT. Succeeds if at least one replica stored it.
+pub async fn dht_insert(
+    ctx: &DaemonContext,
+    locator: HavenLocator,
+    n2r_skt: &N2rClientSocket,
+) -> Result<(), DhtError> {
     let key = locator.identity_pk.fingerprint();
     let replicas = dht_key_to_fps(ctx, &key.to_string());
     let mut gatherer = FuturesUnordered::new();
@@ -43,12 +47,19 @@ pub async fn dht_insert(ctx: &DaemonContext, locator: HavenLocator, n2r_skt: &N2
             )
         })
     }
+    let mut retval = Err(DhtError::NetworkFailure("no DHT replicas known".into()));
     while let Some(res) = gatherer.next().await {
         match res {
-            Ok(_) => (),
-            Err(e) => tracing::debug!("DHT insert failed! {e}"),
+            Ok(_) => retval = Ok(()),
+            Err(e) => {
+                tracing::debug!("DHT insert failed! {e}");
+                if retval.is_err() {
+                    retval = Err(DhtError::NetworkFailure(format!("{e:#}")));
+                }
+            }
         }
     }
+    retval
 }
 
 /// Obtain a locator from the DHT.
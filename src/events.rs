@@ -0,0 +1,168 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+use async_event::Event;
+use parking_lot::Mutex;
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    context::CtxField,
+    control_protocol::{DaemonEvent, EventBatch, EventRecord},
+};
+
+/// How many events we remember for subscribers that fall behind.
+const EVENT_LOG_LEN: usize = 1000;
+
+pub static EVENTS: CtxField<EventLog> = |_| EventLog::new(EVENT_LOG_LEN);
+
+/// A bounded log of recent daemon events, numbered in the order they happened, that control clients can wait on.
+pub struct EventLog {
+    inner: Mutex<LogInner>,
+    max_len: usize,
+    new_event: Event,
+}
+
+struct LogInner {
+    next_seq: u64,
+    events: VecDeque<EventRecord>,
+}
+
+impl EventLog {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            inner: Mutex::new(LogInner {
+                next_seq: 0,
+                events: VecDeque::new(),
+            }),
+            max_len,
+            new_event: Event::new(),
+        }
+    }
+
+    /// Records an event and wakes up everybody waiting for one.
+    pub fn emit(&self, event: DaemonEvent) {
+        tracing::trace!(event = debug(&event), "emitting event");
+        let mut inner = self.inner.lock();
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        if inner.events.len() >= self.max_len {
+            inner.events.pop_front();
+        }
+        inner.events.push_back(EventRecord {
+            seq,
+            unix_timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            event,
+        });
+        drop(inner);
+        self.new_event.notify_all();
+    }
+
+    /// Returns the events numbered `from` onwards, waiting up to `timeout` for one to happen if there are none yet. Without `from`, only events that happen from now on are returned.
+    pub async fn wait_from(&self, from: Option<u64>, timeout: Duration) -> EventBatch {
+        let from = from.unwrap_or_else(|| self.inner.lock().next_seq);
+        let batch = self
+            .new_event
+            .wait_until(|| {
+                let batch = self.batch_from(from);
+                if batch.events.is_empty() && batch.missed == 0 {
+                    None
+                } else {
+                    Some(batch)
+                }
+            })
+            .timeout(timeout)
+            .await;
+        batch.unwrap_or_else(|| self.batch_from(from))
+    }
+
+    fn batch_from(&self, from: u64) -> EventBatch {
+        let inner = self.inner.lock();
+        let oldest = inner.next_seq - inner.events.len() as u64;
+        // a cursor from the future, such as one from before the daemon restarted, starts over from now
+        let from = if from > inner.next_seq {
+            inner.next_seq
+        } else {
+            from
+        };
+        EventBatch {
+            events: inner
+                .events
+                .iter()
+                .filter(|record| record.seq >= from)
+                .cloned()
+                .collect(),
+            next: inner.next_seq,
+            missed: oldest.saturating_sub(from),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(text: &str) -> DaemonEvent {
+        DaemonEvent::ChatReceived {
+            neighbor: "alice".into(),
+            text: text.into(),
+        }
+    }
+
+    #[test]
+    fn subscribers_see_each_event_once() {
+        smol::future::block_on(async {
+            let log = EventLog::new(3);
+            let batch = log.wait_from(None, Duration::from_millis(10)).await;
+            assert!(batch.events.is_empty());
+            assert_eq!(batch.next, 0);
+
+            log.emit(chat("one"));
+            log.emit(chat("two"));
+            let batch = log
+                .wait_from(Some(batch.next), Duration::from_secs(1))
+                .await;
+            assert_eq!(batch.events.len(), 2);
+            assert_eq!(batch.missed, 0);
+            let batch = log
+                .wait_from(Some(batch.next), Duration::from_millis(10))
+                .await;
+            assert!(batch.events.is_empty());
+
+            // falling behind the log loses the oldest events, but says how many
+            for text in ["three", "four", "five", "six"] {
+                log.emit(chat(text));
+            }
+            let batch = log
+                .wait_from(Some(batch.next), Duration::from_secs(1))
+                .await;
+            assert_eq!(batch.missed, 1);
+            assert_eq!(
+                batch
+                    .events
+                    .iter()
+                    .map(|record| record.seq)
+                    .collect::<Vec<_>>(),
+                vec![3, 4, 5]
+            );
+            assert_eq!(batch.next, 6);
+        })
+    }
+
+    #[test]
+    fn waiters_wake_up_on_new_events() {
+        smol::future::block_on(async {
+            let log = EventLog::new(10);
+            let (batch, _) = futures::join!(log.wait_from(None, Duration::from_secs(10)), async {
+                smol::Timer::after(Duration::from_millis(50)).await;
+                log.emit(chat("hello"));
+            });
+            assert_eq!(batch.events.len(), 1);
+            assert_eq!(batch.next, 1);
+        })
+    }
+}
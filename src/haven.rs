@@ -1,4 +1,5 @@
 mod listen;
+mod replay_window;
 mod visitor;
 mod vrh;
 
@@ -21,6 +22,7 @@ use earendil_packet::crypt::DhSecret;
 use earendil_packet::crypt::{AeadKey, DhPublic};
 
 use futures::TryFutureExt;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use smol::{
     channel::{Receiver, Sender},
@@ -31,8 +33,10 @@ use stdcode::StdcodeSerializeExt;
 use tap::Tap;
 use tracing::instrument;
 
+pub use self::replay_window::ReplayStats;
 use self::{
     listen::listen_loop,
+    replay_window::ReplayWindow,
     visitor::visitor_loop,
     vrh::{HavenMsg, V2rMessage, VisitorHandshake},
 };
@@ -187,6 +191,7 @@ pub struct HavenPacketConn {
     enc_key: AeadKey,
     enc_nonce: AtomicU64,
     dec_key: AeadKey,
+    dec_window: Mutex<ReplayWindow>,
 
     // some way of sending packets to the other side (e.g. the sending end of a channel, or a boxed closure)
     // some way of receiving packets from the other side (e.g. the receiving end of a channel, or a boxed closure)
@@ -273,6 +278,7 @@ impl HavenPacketConn {
             enc_key: up_key,
             enc_nonce: AtomicU64::new(0),
             dec_key: down_key,
+            dec_window: Default::default(),
 
             send_upstream,
             recv_downstream,
@@ -299,13 +305,30 @@ impl HavenPacketConn {
     }
 
     /// Receives a packet from the other side. We may not receive all the packets sent, since the connection is best-effort.
+    ///
+    /// Replayed packets, and packets too old to tell whether they are replays, are silently dropped.
     pub async fn recv_pkt(&self) -> anyhow::Result<Bytes> {
-        let ctext = self.recv_downstream.recv().await?;
-        let (nonce, ctext): (u64, Vec<u8>) = stdcode::deserialize(&ctext)?;
-        // TODO TODO replay protection by preventing the nonce from repeating
-        let nonce_bts = [0; 12].tap_mut(|b| b[..8].copy_from_slice(&nonce.to_le_bytes()));
-        let ptext = self.dec_key.open(&nonce_bts, &ctext)?;
-        Ok(ptext.into())
+        loop {
+            let ctext = self.recv_downstream.recv().await?;
+            let (nonce, ctext): (u64, Vec<u8>) = stdcode::deserialize(&ctext)?;
+            if !self.dec_window.lock().check(nonce) {
+                tracing::debug!(nonce, "dropping replayed or too old haven packet");
+                continue;
+            }
+            let nonce_bts = [0; 12].tap_mut(|b| b[..8].copy_from_slice(&nonce.to_le_bytes()));
+            let ptext = self.dec_key.open(&nonce_bts, &ctext)?;
+            // only authenticated nonces may move the window
+            if !self.dec_window.lock().update(nonce) {
+                tracing::debug!(nonce, "dropping replayed haven packet");
+                continue;
+            }
+            return Ok(ptext.into());
+        }
+    }
+
+    /// How many incoming packets were dropped as replays, or for being too old.
+    pub fn replay_stats(&self) -> ReplayStats {
+        self.dec_window.lock().stats()
     }
 }
 
@@ -363,3 +386,89 @@ pub async fn rendezvous_forward_loop(ctx: DaemonContext) -> anyhow::Result<()> {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds two ends of a connection, with the "wire" between them exposed so that tests can tamper with it.
+    fn conn_pair() -> (
+        HavenPacketConn,
+        HavenPacketConn,
+        Receiver<Bytes>,
+        Sender<Bytes>,
+    ) {
+        let key = [42u8; 32];
+        let (send_wire, recv_wire) = smol::channel::unbounded();
+        let (send_inject, recv_inject) = smol::channel::unbounded();
+        let (dummy_send, dummy_recv) = smol::channel::unbounded();
+        let sender = HavenPacketConn {
+            enc_key: AeadKey::from_bytes(&key),
+            enc_nonce: AtomicU64::new(0),
+            dec_key: AeadKey::from_bytes(&key),
+            dec_window: Default::default(),
+            send_upstream: send_wire,
+            recv_downstream: dummy_recv,
+            _task: smolscale::spawn(smol::future::pending()),
+        };
+        let receiver = HavenPacketConn {
+            enc_key: AeadKey::from_bytes(&key),
+            enc_nonce: AtomicU64::new(0),
+            dec_key: AeadKey::from_bytes(&key),
+            dec_window: Default::default(),
+            send_upstream: dummy_send,
+            recv_downstream: recv_inject,
+            _task: smolscale::spawn(smol::future::pending()),
+        };
+        (sender, receiver, recv_wire, send_inject)
+    }
+
+    #[test]
+    fn replayed_packets_dropped() {
+        smolscale::block_on(async {
+            let (sender, receiver, wire, inject) = conn_pair();
+            sender.send_pkt(b"hello").await.unwrap();
+            sender.send_pkt(b"world").await.unwrap();
+            let hello = wire.recv().await.unwrap();
+            let world = wire.recv().await.unwrap();
+
+            inject.send(hello.clone()).await.unwrap();
+            assert_eq!(receiver.recv_pkt().await.unwrap(), &b"hello"[..]);
+            inject.send(hello).await.unwrap();
+            inject.send(world).await.unwrap();
+            assert_eq!(receiver.recv_pkt().await.unwrap(), &b"world"[..]);
+            assert_eq!(receiver.replay_stats().replayed, 1);
+        })
+    }
+
+    #[test]
+    fn reordered_packets_accepted_once() {
+        smolscale::block_on(async {
+            let (sender, receiver, wire, inject) = conn_pair();
+            let mut pkts = vec![];
+            for i in 0u8..10 {
+                sender.send_pkt(&[i]).await.unwrap();
+                pkts.push(wire.recv().await.unwrap());
+            }
+            // deliver in reverse order, with every packet duplicated
+            for pkt in pkts.iter().rev() {
+                inject.send(pkt.clone()).await.unwrap();
+                inject.send(pkt.clone()).await.unwrap();
+            }
+            for i in (0u8..10).rev() {
+                assert_eq!(receiver.recv_pkt().await.unwrap(), &[i][..]);
+            }
+            // after the last duplicate, only a fresh packet gets through
+            sender.send_pkt(b"fresh").await.unwrap();
+            inject.send(wire.recv().await.unwrap()).await.unwrap();
+            assert_eq!(receiver.recv_pkt().await.unwrap(), &b"fresh"[..]);
+            assert_eq!(
+                receiver.replay_stats(),
+                ReplayStats {
+                    replayed: 10,
+                    too_old: 0
+                }
+            );
+        })
+    }
+}
@@ -12,8 +12,11 @@ use std::{collections::HashMap, sync::atomic::AtomicU64, time::Duration};
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
+    config::{AnonymityLevel, RoutePolicy},
     context::DaemonContext,
+    control_protocol::DaemonEvent,
     dht::dht_insert,
+    events::EVENTS,
     global_rpc::{transport::GlobalRpcTransport, GlobalRpcClient},
     haven::vrh::HavenHandshake,
     n2r_socket::{N2rClientSocket, RelayEndpoint},
@@ -72,7 +75,12 @@ async fn register_haven(
         N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random())?,
     ));
     loop {
-        let dht_socket = N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random())?;
+        // DHT writes aren't latency-sensitive, so they get the strongest mixing
+        let dht_socket = N2rClientSocket::bind(ctx.clone(), AnonEndpoint::random())?
+            .with_route_policy(RoutePolicy {
+                anonymity: AnonymityLevel::Strong,
+                ..ctx.init().route_policy.clone()
+            });
         match gclient
             .alloc_forward(forward_req.clone())
             .timeout(Duration::from_secs(10))
@@ -93,13 +101,23 @@ async fn register_haven(
             }
             _ => {
                 tracing::debug!("registering haven {}", identity.public().fingerprint());
-                dht_insert(
+                let result = dht_insert(
                     ctx,
                     HavenLocator::new(identity, epk, rendezvous),
                     &dht_socket,
                 )
                 .timeout(Duration::from_secs(30))
                 .await;
+                let error = match result {
+                    Some(Ok(())) => None,
+                    Some(Err(err)) => Some(err.to_string()),
+                    None => Some("timed out".to_string()),
+                };
+                ctx.get(EVENTS).emit(DaemonEvent::DhtPublish {
+                    haven: identity.public().fingerprint().to_string(),
+                    success: error.is_none(),
+                    error,
+                });
                 Timer::after(Duration::from_secs(5)).await;
             }
         }
@@ -173,6 +191,7 @@ async fn haven_demultiplex(
                                 enc_key: down_key,
                                 enc_nonce: AtomicU64::new(0),
                                 dec_key: up_key,
+                                dec_window: Default::default(),
 
                                 send_upstream,
                                 recv_downstream,
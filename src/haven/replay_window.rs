@@ -0,0 +1,131 @@
+const WORD_BITS: u64 = 64;
+const WINDOW_WORDS: usize = 32;
+
+/// How far behind the highest nonce seen so far a nonce can be and still be accepted. One word of the bitmap is always reserved for the word being rotated in, like in RFC 6479.
+pub const WINDOW_SIZE: u64 = WORD_BITS * (WINDOW_WORDS as u64 - 1);
+
+/// A sliding-window anti-replay filter over the nonces received in one direction of a connection, like the ones in IPsec and WireGuard.
+#[derive(Default)]
+pub struct ReplayWindow {
+    /// one more than the highest nonce accepted so far
+    next: u64,
+    bitmap: [u64; WINDOW_WORDS],
+    stats: ReplayStats,
+}
+
+/// Counts of nonces rejected by a [ReplayWindow].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// nonces that were already accepted before
+    pub replayed: u64,
+    /// nonces too far behind the window to tell whether they were already accepted
+    pub too_old: u64,
+}
+
+impl ReplayWindow {
+    /// Checks whether a nonce could be accepted, without accepting it. Call this before doing any expensive work, like decryption, on a packet.
+    pub fn check(&mut self, nonce: u64) -> bool {
+        if nonce >= self.next {
+            return true;
+        }
+        if self.next - nonce > WINDOW_SIZE {
+            self.stats.too_old += 1;
+            return false;
+        }
+        let (word, bit) = Self::position(nonce);
+        if self.bitmap[word] & bit != 0 {
+            self.stats.replayed += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Accepts a nonce, sliding the window forward if needed. Returns false if the nonce was already accepted or is too old. Only call this once the packet is authenticated, so that forged nonces can't move the window.
+    pub fn update(&mut self, nonce: u64) -> bool {
+        if !self.check(nonce) {
+            return false;
+        }
+        if nonce >= self.next {
+            if self.next > 0 {
+                // clear the words that rotate into the window
+                let top_word = (self.next - 1) / WORD_BITS;
+                let new_top_word = nonce / WORD_BITS;
+                let to_clear = (new_top_word - top_word).min(WINDOW_WORDS as u64);
+                for i in 1..=to_clear {
+                    self.bitmap[((top_word + i) % WINDOW_WORDS as u64) as usize] = 0;
+                }
+            }
+            self.next = nonce + 1;
+        }
+        let (word, bit) = Self::position(nonce);
+        self.bitmap[word] |= bit;
+        true
+    }
+
+    pub fn stats(&self) -> ReplayStats {
+        self.stats
+    }
+
+    fn position(nonce: u64) -> (usize, u64) {
+        let word = ((nonce / WORD_BITS) % WINDOW_WORDS as u64) as usize;
+        (word, 1 << (nonce % WORD_BITS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut window = ReplayWindow::default();
+        for nonce in 0..10_000 {
+            assert!(window.update(nonce));
+        }
+        assert_eq!(window.stats(), ReplayStats::default());
+    }
+
+    #[test]
+    fn duplicates_rejected() {
+        let mut window = ReplayWindow::default();
+        assert!(window.update(0));
+        assert!(!window.update(0));
+        assert!(window.update(5));
+        assert!(!window.check(5));
+        assert!(!window.update(5));
+        assert_eq!(window.stats().replayed, 3);
+    }
+
+    #[test]
+    fn reordering_within_window() {
+        let mut window = ReplayWindow::default();
+        assert!(window.update(WINDOW_SIZE));
+        for nonce in (1..WINDOW_SIZE).rev() {
+            assert!(window.update(nonce), "nonce {nonce}");
+        }
+        for nonce in 1..=WINDOW_SIZE {
+            assert!(!window.update(nonce), "nonce {nonce}");
+        }
+        assert_eq!(window.stats().replayed, WINDOW_SIZE);
+    }
+
+    #[test]
+    fn too_old_rejected() {
+        let mut window = ReplayWindow::default();
+        assert!(window.update(WINDOW_SIZE * 3));
+        assert!(!window.update(WINDOW_SIZE));
+        assert!(!window.update(0));
+        assert_eq!(window.stats().too_old, 2);
+    }
+
+    #[test]
+    fn big_jumps_clear_stale_bits() {
+        let mut window = ReplayWindow::default();
+        assert!(window.update(3));
+        // same bitmap position as 3, one full rotation later
+        let rotated = 3 + WORD_BITS * WINDOW_WORDS as u64;
+        assert!(window.update(rotated));
+        assert!(!window.update(rotated));
+        assert!(window.update(rotated - 1));
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+use colored::Colorize;
+use earendil_crypt::{HavenFingerprint, RelayFingerprint};
+use serde_yaml::{Mapping, Value};
+
+use crate::{
+    check_config::{check_config, Severity},
+    config::{ConfigFile, Identity, ObfsConfig, OutRouteConfig},
+};
+
+/// What the node that `earendil init` sets up is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InitRole {
+    /// Uses the network through a local SOCKS5 proxy, without relaying for others
+    Client,
+    /// Relays traffic for others, accepting links on a public port
+    Relay,
+    /// A client that also hosts a haven, forwarding its connections to a local service
+    Haven,
+}
+
+#[derive(Args)]
+pub struct InitArgs {
+    #[arg(value_enum)]
+    role: InitRole,
+    /// The directory to put the config file, identities and state cache in
+    #[arg(short, long, default_value = ".")]
+    dir: PathBuf,
+    /// Files of out-routes to link to, in the format that `earendil init relay` writes to `peer_route.yaml`. Clients and havens need at least one
+    #[arg(long)]
+    out_routes: Vec<PathBuf>,
+    /// Where relays accept links
+    #[arg(long, default_value = "0.0.0.0:19999")]
+    listen: SocketAddr,
+    /// The IP address that peers reach a relay at. Without it, peers have to fill it into the out-route themselves
+    #[arg(long)]
+    public_ip: Option<IpAddr>,
+    /// Where clients and havens run their SOCKS5 proxy
+    #[arg(long, default_value = "127.0.0.1:23456")]
+    socks5_listen: SocketAddr,
+    /// The port that a haven is reached at
+    #[arg(long, default_value_t = 80)]
+    haven_port: u16,
+    /// The local TCP service that a haven forwards its connections to
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    upstream: SocketAddr,
+    /// The relay that a haven is reached through. Defaults to the first out-route
+    #[arg(long)]
+    rendezvous: Option<RelayFingerprint>,
+    /// Overwrite an existing config file
+    #[arg(long)]
+    force: bool,
+}
+
+/// A config generated by `earendil init`, with its identities already created.
+struct Generated {
+    yaml: String,
+    config: ConfigFile,
+    /// The out-route that peers of a relay should add
+    peer_route: Option<String>,
+    haven: Option<HavenFingerprint>,
+}
+
+/// Writes a complete config for a new node of the chosen role, creating its identities, and explains how to use it.
+pub fn main_init(args: InitArgs) -> anyhow::Result<()> {
+    std::fs::create_dir_all(&args.dir).context("cannot create config directory")?;
+    let dir = args
+        .dir
+        .canonicalize()
+        .context("cannot find config directory")?;
+    let config_path = dir.join("earendil.yaml");
+    anyhow::ensure!(
+        args.force || !config_path.exists(),
+        "{} already exists. Pass --force to overwrite it",
+        config_path.display()
+    );
+
+    let generated = generate(&args, &dir)?;
+    std::fs::write(&config_path, &generated.yaml).context("cannot write config file")?;
+    println!("wrote {}", config_path.display());
+    for problem in check_config(&generated.config) {
+        println!("{} {problem}", "warning".yellow().bold());
+    }
+
+    if let Some(peer_route) = generated.peer_route {
+        let peer_route_path = dir.join("peer_route.yaml");
+        std::fs::write(&peer_route_path, &peer_route).context("cannot write peer route")?;
+        println!();
+        println!(
+            "Peers link to this relay by adding this to the out_routes of their configs, or by passing {} to `earendil init --out-routes`:",
+            peer_route_path.display()
+        );
+        println!();
+        print!("{peer_route}");
+        if args.public_ip.is_none() {
+            println!();
+            println!(
+                "Replace <YOUR_IP> with the public IP address of this relay, or pass --public-ip."
+            );
+        }
+    }
+    if let Some(haven) = generated.haven {
+        println!();
+        println!(
+            "The haven is reachable at {haven}:{}, forwarding to {}.",
+            args.haven_port, args.upstream
+        );
+    }
+    println!();
+    println!(
+        "Start the node with `earendil daemon --config {}`",
+        config_path.display()
+    );
+    Ok(())
+}
+
+fn generate(args: &InitArgs, dir: &Path) -> anyhow::Result<Generated> {
+    let mut out_routes: BTreeMap<String, OutRouteConfig> = BTreeMap::new();
+    for path in args.out_routes.iter() {
+        // through JSON, like config files, since YAML wants tags for enums
+        let json: serde_json::Value =
+            serde_yaml::from_slice(&std::fs::read(path).context("cannot read out-routes")?)
+                .context("syntax error in out-routes")?;
+        let routes: BTreeMap<String, OutRouteConfig> = serde_json::from_value(json)
+            .with_context(|| format!("{} is not a map of out-routes", path.display()))?;
+        out_routes.extend(routes);
+    }
+
+    let mut cfg = Mapping::new();
+    let mut peer_route = None;
+    let mut haven = None;
+    if args.role == InitRole::Relay {
+        let identity_file = dir.join("identity.asc");
+        let fingerprint = Identity::IdentityFile(identity_file.clone())
+            .actualize_relay()?
+            .public()
+            .fingerprint();
+        let obfs = ObfsConfig::Sosistab3(new_cookie());
+        cfg.insert("identity_file".into(), to_yaml(identity_file)?);
+        cfg.insert("state_cache".into(), to_yaml(dir.join("state_cache.db"))?);
+        cfg.insert(
+            "in_routes".into(),
+            to_yaml(serde_json::json!({
+                "main": { "listen": args.listen, "obfs": &obfs }
+            }))?,
+        );
+
+        let connect = match args.public_ip {
+            Some(ip) => SocketAddr::new(ip, args.listen.port()).to_string(),
+            None => format!("<YOUR_IP>:{}", args.listen.port()),
+        };
+        let name = format!("relay-{}", &fingerprint.to_string()[..8]);
+        peer_route = Some(serde_yaml::to_string(&serde_json::json!({
+            (name): { "connect": connect, "fingerprint": fingerprint.to_string(), "obfs": obfs }
+        }))?);
+    } else {
+        anyhow::ensure!(
+            !out_routes.is_empty(),
+            "clients and havens need at least one relay to link to. Pass the peer_route.yaml that `earendil init relay` wrote as --out-routes"
+        );
+        cfg.insert("state_cache".into(), to_yaml(dir.join("state_cache.db"))?);
+    }
+    if !out_routes.is_empty() {
+        cfg.insert("out_routes".into(), to_yaml(&out_routes)?);
+    }
+
+    if args.role != InitRole::Relay {
+        cfg.insert(
+            "socks5".into(),
+            to_yaml(serde_json::json!({ "listen": args.socks5_listen, "fallback": "block" }))?,
+        );
+    }
+    if args.role == InitRole::Haven {
+        let rendezvous = match args.rendezvous {
+            Some(fp) => fp,
+            None => out_routes
+                .values()
+                .next()
+                .map(|route| route.fingerprint)
+                .context("no relay to use as the rendezvous")?,
+        };
+        haven = Some(
+            Identity::IdentityFile(dir.join("haven.asc"))
+                .actualize_haven()?
+                .public()
+                .fingerprint(),
+        );
+        cfg.insert(
+            "havens".into(),
+            to_yaml(serde_json::json!([{
+                "identity_file": dir.join("haven.asc"),
+                "listen_port": args.haven_port,
+                "rendezvous": rendezvous.to_string(),
+                "handler": { "type": "tcp_service", "upstream": args.upstream },
+            }]))?,
+        );
+    }
+
+    let yaml = serde_yaml::to_string(&cfg)?;
+    // parse the config the same way the daemon does, so that we never write one it would refuse
+    let config: ConfigFile = serde_json::from_value(serde_json::to_value(&cfg)?)
+        .context("generated an unparseable config")?;
+    let errors: Vec<String> = check_config(&config)
+        .into_iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .map(|problem| problem.to_string())
+        .collect();
+    anyhow::ensure!(
+        errors.is_empty(),
+        "generated an invalid config: {}",
+        errors.join("; ")
+    );
+    Ok(Generated {
+        yaml,
+        config,
+        peer_route,
+        haven,
+    })
+}
+
+/// Converts to YAML through JSON, so that enums come out the way config files write them rather than as YAML tags.
+fn to_yaml(value: impl serde::Serialize) -> anyhow::Result<Value> {
+    Ok(serde_yaml::to_value(serde_json::to_value(value)?)?)
+}
+
+/// A random sosistab3 cookie, which peers must know to link to us.
+fn new_cookie() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        init: InitArgs,
+    }
+
+    fn init(args: &[&str], dir: &Path) -> Generated {
+        let cli = Cli::parse_from(["init"].iter().chain(args));
+        generate(&cli.init, dir).unwrap()
+    }
+
+    #[test]
+    fn every_role_gets_a_working_config() {
+        let dir = std::env::temp_dir().join(format!("earendil-init-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let relay = init(&["relay", "--public-ip", "1.2.3.4"], &dir);
+        let relay_fp = relay
+            .config
+            .identity
+            .as_ref()
+            .unwrap()
+            .actualize_relay()
+            .unwrap()
+            .public()
+            .fingerprint();
+        let peer_route = dir.join("peer_route.yaml");
+        std::fs::write(&peer_route, relay.peer_route.unwrap()).unwrap();
+
+        let client = init(
+            &["client", "--out-routes", peer_route.to_str().unwrap()],
+            &dir,
+        );
+        let route = client.config.out_routes.values().next().unwrap();
+        assert_eq!(route.fingerprint, relay_fp);
+        assert_eq!(route.connect, "1.2.3.4:19999");
+        assert!(client.config.is_client());
+
+        let haven = init(
+            &["haven", "--out-routes", peer_route.to_str().unwrap()],
+            &dir,
+        );
+        assert_eq!(haven.config.havens[0].rendezvous, relay_fp);
+        assert_eq!(
+            haven.haven,
+            Some(
+                haven.config.havens[0]
+                    .identity
+                    .actualize_haven()
+                    .unwrap()
+                    .public()
+                    .fingerprint()
+            )
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
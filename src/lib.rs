@@ -1,16 +1,22 @@
+mod check_config;
 mod commands;
 pub mod config;
 mod context;
+pub mod control_auth;
 pub mod control_protocol;
 pub mod daemon;
 mod db;
 mod debts;
+mod delay_queue;
 mod dht;
+mod events;
 mod global_rpc;
 mod haven;
+mod init;
 mod n2r;
 mod n2r_socket;
 mod network;
+mod onion_keys;
 mod settlement;
 
 mod pascal;
@@ -19,11 +25,13 @@ mod stream;
 
 // Create the public API here.
 
+pub use check_config::{check_config, config_schema, main_check_config, ConfigProblem, Severity};
 pub use commands::ControlCommand;
 pub use config::*;
-pub use control_protocol::main_control;
+pub use control_protocol::{main_control, ControlConnection};
 pub use daemon::Daemon;
-pub use haven::{HavenEndpoint, HavenListener, HavenPacketConn};
+pub use haven::{HavenEndpoint, HavenListener, HavenPacketConn, ReplayStats};
+pub use init::{main_init, InitArgs, InitRole};
 pub use n2r_socket::*;
 
 pub use pooled::*;
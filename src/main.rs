@@ -1,10 +1,15 @@
-use anyhow::Context;
 use bip39::Mnemonic;
 use clap::{Parser, Subcommand};
+use earendil::config_schema;
+use earendil::control_auth::read_cookie;
+use earendil::main_check_config;
 use earendil::main_control;
+use earendil::main_init;
 use earendil::ConfigFile;
 use earendil::ControlCommand;
+use earendil::ControlConnection;
 use earendil::Daemon;
+use earendil::InitArgs;
 use std::{net::SocketAddr, path::PathBuf};
 
 use tracing_subscriber::prelude::*;
@@ -30,11 +35,32 @@ enum Commands {
     Control {
         #[arg(short, long, default_value = "127.0.0.1:18964")]
         connect: SocketAddr,
+        /// Connect through this Unix domain socket instead
+        #[arg(long)]
+        unix: Option<PathBuf>,
+        /// The control token, for daemons that require one. Defaults to the one in the cookie file
+        #[arg(long)]
+        token: Option<String>,
+        /// Where the daemon wrote its control cookie. Defaults to ~/.earendil/control_cookie
+        #[arg(long)]
+        cookie_file: Option<PathBuf>,
         #[command(subcommand)]
         control_command: ControlCommand,
     },
 
     GenerateSeed,
+
+    /// Writes a config file and identities for a new client, relay or haven.
+    Init(InitArgs),
+
+    /// Checks a config file without starting a daemon, exiting with an error if the daemon would refuse it.
+    CheckConfig {
+        #[arg(short, long)]
+        config: PathBuf,
+    },
+
+    /// Prints a JSON Schema of the config file format.
+    ConfigSchema,
 }
 
 #[tracing::instrument]
@@ -51,16 +77,16 @@ fn main() -> anyhow::Result<()> {
 
     match Args::parse().command {
         Commands::Daemon { config } => {
-            let json: serde_json::Value =
-                serde_yaml::from_slice(&std::fs::read(config).context("cannot read config file")?)
-                    .context("syntax error in config file")?;
-            let config_parsed: ConfigFile = serde_json::from_value(json)?;
+            let config_parsed = ConfigFile::load(&config)?;
             tracing::debug!(
                 "parsed config file: {}",
                 serde_json::to_string_pretty(&config_parsed)?
             );
             tracing::info!("about to init daemon!");
             let daemon = Daemon::init(config_parsed)?;
+            daemon.set_config_path(config);
+            #[cfg(unix)]
+            let _reload_on_sighup = smolscale::spawn(reload_on_sighup(daemon.control_client()));
             match smol::future::block_on(daemon.wait_until_dead()) {
                 Ok(_) => anyhow::bail!("daemon is dead, with no error msg"),
                 Err(err) => anyhow::bail!(err),
@@ -69,13 +95,59 @@ fn main() -> anyhow::Result<()> {
         Commands::Control {
             control_command,
             connect,
-        } => smolscale::block_on(main_control(control_command, connect)),
+            unix,
+            token,
+            cookie_file,
+        } => {
+            let token = match token {
+                Some(token) => Some(token),
+                None => read_cookie(cookie_file.as_deref())?,
+            };
+            let connection = ControlConnection {
+                connect,
+                unix,
+                token,
+            };
+            smolscale::block_on(main_control(control_command, connection))
+        }
         Commands::GenerateSeed => {
             let seed_phrase = gen_seed()?;
             println!("{}", seed_phrase);
             Ok(())
         }
+        Commands::Init(args) => main_init(args),
+        Commands::CheckConfig { config } => main_check_config(&config),
+        Commands::ConfigSchema => {
+            println!("{}", config_schema());
+            Ok(())
+        }
+    }
+}
+
+/// Reloads the config file whenever we get a SIGHUP, like the `reload-config` control verb does.
+#[cfg(unix)]
+async fn reload_on_sighup(
+    control: earendil::control_protocol::ControlClient,
+) -> anyhow::Result<()> {
+    use async_signal::{Signal, Signals};
+    use futures_util::StreamExt;
+
+    let mut signals = Signals::new([Signal::Hup])?;
+    while signals.next().await.is_some() {
+        tracing::info!("got SIGHUP, reloading the config file");
+        match control.reload_config().await? {
+            Ok(report) => tracing::info!(
+                started = debug(report.started),
+                stopped = debug(report.stopped),
+                restarted = debug(report.restarted),
+                failed = debug(report.failed),
+                needs_restart = debug(report.needs_restart),
+                "reloaded the config file"
+            ),
+            Err(err) => tracing::warn!("could not reload the config file: {err}"),
+        }
     }
+    Ok(())
 }
 
 fn gen_seed() -> anyhow::Result<String> {
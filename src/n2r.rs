@@ -1,9 +1,13 @@
 mod anon_dest;
-mod delay_queue;
+mod cover;
 mod remote_rb;
+mod route;
 
+pub use cover::{cover_traffic_loop, RELAY_RELIABILITY};
 pub use remote_rb::replenish_remote_rb;
 
+use self::route::forward_route_to;
+
 use std::time::Instant;
 
 use anyhow::Context;
@@ -16,6 +20,7 @@ use earendil_packet::{
 use smol::channel::{Receiver, Sender};
 
 use crate::{
+    config::RoutePolicy,
     context::{CtxField, DaemonContext, MY_RELAY_IDENTITY, RELAY_GRAPH},
     n2r::anon_dest::ANON_DESTS,
     n2r_socket::RelayEndpoint,
@@ -61,6 +66,9 @@ pub async fn read_forward(ctx: &DaemonContext) -> anyhow::Result<(Bytes, AnonEnd
         let (inner, anon_remote) = ctx.get(INCOMING_FORWARDS).1.recv().await?;
 
         match inner {
+            InnerPacket::Message(msg) if msg.relay_dock == cover::COVER_DOCK => {
+                cover::cover_received(ctx, &msg.body);
+            }
             InnerPacket::Message(msg) => {
                 tracing::trace!("received InnerPacket::Message");
                 let anon_endpoint = anon_remote;
@@ -80,29 +88,37 @@ pub async fn read_forward(ctx: &DaemonContext) -> anyhow::Result<(Bytes, AnonEnd
 pub async fn read_backward(
     ctx: &DaemonContext,
 ) -> anyhow::Result<(Bytes, RelayEndpoint, AnonEndpoint)> {
-    let (mut reply, degarbler_id) = ctx.get(INCOMING_BACKWARDS).1.recv().await?;
-    let degarbler = ctx
-        .get(DEGARBLERS)
-        .remove(&degarbler_id)
-        .context("no degarbler for incoming reply")?
-        .1;
-    let (inner_pkt, relay_fp) = degarbler.degarble(&mut reply)?;
-    match inner_pkt {
-        InnerPacket::Message(msg) => {
-            let anon_endpoint = degarbler.my_anon_id();
-            let relay_endpoint = RelayEndpoint::new(relay_fp, msg.relay_dock);
-            // consume a reply block
-            remote_rb::consume_remote_rb(ctx, anon_endpoint, relay_endpoint.fingerprint).await;
-            Ok((msg.body, relay_endpoint, anon_endpoint))
+    loop {
+        let (mut reply, degarbler_id) = ctx.get(INCOMING_BACKWARDS).1.recv().await?;
+        let degarbler = ctx
+            .get(DEGARBLERS)
+            .remove(&degarbler_id)
+            .context("no degarbler for incoming reply")?
+            .1;
+        let (inner_pkt, relay_fp) = degarbler.degarble(&mut reply)?;
+        match inner_pkt {
+            InnerPacket::Message(msg) if msg.relay_dock == cover::COVER_DOCK => {
+                cover::cover_received(ctx, &msg.body);
+            }
+            InnerPacket::Message(msg) => {
+                let anon_endpoint = degarbler.my_anon_id();
+                let relay_endpoint = RelayEndpoint::new(relay_fp, msg.relay_dock);
+                // consume a reply block; the receiving socket replenishes them according to its route policy
+                remote_rb::consume_remote_rb(ctx, anon_endpoint, relay_endpoint.fingerprint);
+                return Ok((msg.body, relay_endpoint, anon_endpoint));
+            }
+            InnerPacket::ReplyBlocks(_) => {
+                anyhow::bail!("we shouldn't be getting reply blocks here")
+            }
         }
-        InnerPacket::ReplyBlocks(_) => anyhow::bail!("we shouldn't be getting reply blocks here"),
     }
 }
 
-/// Sends a raw N2R message with the given parameters.
-#[tracing::instrument(skip(ctx, content))]
+/// Sends a raw N2R message with the given parameters, along a route that follows the given policy.
+#[tracing::instrument(skip(ctx, policy, content))]
 pub async fn send_forward(
     ctx: &DaemonContext,
+    policy: &RoutePolicy,
     src: AnonEndpoint,
     dst_fp: RelayFingerprint,
     dst_dock: Dock,
@@ -115,7 +131,7 @@ pub async fn send_forward(
         tracing::trace!("send message took {:?}", send_msg_time);
     });
 
-    let route = forward_route_to(ctx, dst_fp).context("failed to create forward route")?;
+    let route = forward_route_to(ctx, policy, dst_fp).context("failed to create forward route")?;
     tracing::trace!("RRRRRRRRRRRRRRRRRRRRRR route: {:?}", route);
     let first_peeler = *route
         .first()
@@ -140,9 +156,10 @@ pub async fn send_forward(
         &dest_opk,
         InnerPacket::Message(Message::new(dst_dock, content.clone())),
         RemoteId::Anon(src),
+        policy.anonymity.delay_profile(),
     )?;
 
-    replenish_remote_rb(ctx, src, dst_fp)
+    replenish_remote_rb(ctx, policy, src, dst_fp)
         .await
         .context("failed to replenish remote reply blocks")?;
 
@@ -184,16 +201,6 @@ pub async fn send_backward(
     Ok(())
 }
 
-fn forward_route_to(
-    ctx: &DaemonContext,
-    dest_fp: RelayFingerprint,
-) -> anyhow::Result<Vec<RelayFingerprint>> {
-    let mut route = ctx.get(RELAY_GRAPH).read().rand_relays(2);
-    route.push(dest_fp);
-    tracing::trace!("forward route formed: {:?}", route);
-    Ok(route)
-}
-
 fn route_to_instructs(
     ctx: &DaemonContext,
     route: &[RelayFingerprint],
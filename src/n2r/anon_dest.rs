@@ -73,7 +73,7 @@ mod tests {
     use super::*;
     use earendil_crypt::{RelayFingerprint, RelayIdentitySecret};
     use earendil_packet::crypt::DhSecret;
-    use earendil_packet::ForwardInstruction;
+    use earendil_packet::{DelayProfile, ForwardInstruction};
 
     fn generate_forward_instructions(n: usize) -> Vec<(ForwardInstruction, DhSecret)> {
         (0..n)
@@ -105,8 +105,15 @@ mod tests {
         let alice_opk = alice_osk.public();
         let first_peeler = RelayFingerprint::from_bytes(&[10; 32]);
 
-        let (rb, _) = ReplyBlock::new(&route, first_peeler, &alice_opk, 0, alice_anon_id)
-            .expect("failed to create reply block");
+        let (rb, _) = ReplyBlock::new(
+            &route,
+            first_peeler,
+            &alice_opk,
+            0,
+            alice_anon_id,
+            DelayProfile::None,
+        )
+        .expect("failed to create reply block");
         rb
     }
 
@@ -0,0 +1,199 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Context;
+use bytes::Bytes;
+use dashmap::DashMap;
+use earendil_crypt::{AnonEndpoint, RelayFingerprint, RemoteId};
+use earendil_packet::{Dock, InnerPacket, Message, RawPacket, ReplyBlock};
+use moka::{notification::RemovalCause, sync::Cache};
+use rand::prelude::*;
+
+use crate::{
+    config::CoverTrafficConfig,
+    context::{
+        CtxField, DaemonContext, MY_CLIENT_ID, MY_RELAY_IDENTITY, MY_RELAY_ONION_KEYS, RELAY_GRAPH,
+    },
+    n2r::{
+        route::{forward_route_to, loop_route},
+        route_to_instructs, DEGARBLERS,
+    },
+    network::send_raw,
+};
+
+/// The dock that cover packets are addressed to. Whoever peels the last layer of a cover packet drops it.
+pub const COVER_DOCK: Dock = 100003;
+
+/// How long we wait for a loop packet to come back before counting it as lost.
+const LOOP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many loop packets went out through a relay, and what became of them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoopStats {
+    pub sent: u64,
+    pub returned: u64,
+    pub lost: u64,
+}
+
+/// Loop packet statistics for every relay we have routed loop packets through.
+pub static RELAY_RELIABILITY: CtxField<Arc<DashMap<RelayFingerprint, LoopStats>>> =
+    |_| Default::default();
+
+/// A loop packet still in flight.
+struct PendingLoop {
+    /// the relays on its route, which a timeout is counted against
+    relays: Vec<RelayFingerprint>,
+    /// the id of the degarbler for the reply block that clients send loop packets through
+    degarbler_id: Option<u64>,
+}
+
+/// The loop packets still in flight, by loop id. Loops that time out are counted against every relay on their route, and their degarblers are thrown away.
+static PENDING_LOOPS: CtxField<Cache<u64, Arc<PendingLoop>>> = |ctx| {
+    let ctx = ctx.clone();
+    Cache::builder()
+        .time_to_live(LOOP_TIMEOUT)
+        .eviction_listener(move |_, pending: Arc<PendingLoop>, cause| {
+            if cause == RemovalCause::Expired {
+                let reliability = ctx.get(RELAY_RELIABILITY);
+                for fp in pending.relays.iter() {
+                    reliability.entry(*fp).or_default().lost += 1;
+                }
+                if let Some(id) = pending.degarbler_id {
+                    ctx.get(DEGARBLERS).remove(&id);
+                }
+            }
+        })
+        .build()
+};
+
+/// Loop that sends cover packets at Poisson-distributed times, so that an observer of our links can't tell when we are actually active.
+pub async fn cover_traffic_loop(ctx: DaemonContext, cfg: CoverTrafficConfig) -> anyhow::Result<()> {
+    loop {
+        // exponentially distributed gaps between packets make for a Poisson process
+        let gap = -(1.0 - rand::random::<f64>()).ln() / cfg.rate;
+        smol::Timer::after(Duration::from_secs_f64(gap)).await;
+        ctx.get(PENDING_LOOPS).run_pending_tasks();
+
+        let res = if rand::random::<f64>() < cfg.loop_fraction {
+            send_loop(&ctx).await
+        } else {
+            send_drop(&ctx).await
+        };
+        if let Err(err) = res {
+            tracing::debug!(err = debug(err), "could not send cover packet");
+        }
+    }
+}
+
+/// Handles a message addressed to [COVER_DOCK], which is either one of our loop packets coming back, or a drop packet that we silently discard.
+pub fn cover_received(ctx: &DaemonContext, body: &[u8]) {
+    let Ok(loop_id) = <[u8; 8]>::try_from(body) else {
+        tracing::trace!("dropping a cover packet");
+        return;
+    };
+    if let Some(pending) = ctx.get(PENDING_LOOPS).remove(&u64::from_be_bytes(loop_id)) {
+        tracing::trace!(route = debug(&pending.relays), "loop packet came back");
+        let reliability = ctx.get(RELAY_RELIABILITY);
+        for fp in pending.relays.iter() {
+            reliability.entry(*fp).or_default().returned += 1;
+        }
+    }
+}
+
+fn cover_message(body: Bytes) -> InnerPacket {
+    InnerPacket::Message(Message::new(COVER_DOCK, body))
+}
+
+/// Sends a packet to a random relay, which drops it.
+async fn send_drop(ctx: &DaemonContext) -> anyhow::Result<()> {
+    let my_fp = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|id| id.public().fingerprint());
+    let dest_fp = ctx
+        .get(RELAY_GRAPH)
+        .read()
+        .all_nodes()
+        .filter(|fp| Some(*fp) != my_fp)
+        .choose(&mut rand::thread_rng())
+        .context("no relays to send drop packets to")?;
+
+    let policy = &ctx.init().route_policy;
+    let route = forward_route_to(ctx, policy, dest_fp)?;
+    let instructs = route_to_instructs(ctx, &route)?;
+    let dest_opk = ctx
+        .get(RELAY_GRAPH)
+        .read()
+        .identity(&dest_fp)
+        .context("no identity for the drop destination")?
+        .onion_pk;
+    let pkt = RawPacket::new_normal(
+        &instructs,
+        &dest_opk,
+        cover_message(Bytes::new()),
+        RemoteId::Anon(AnonEndpoint::random()),
+        policy.anonymity.delay_profile(),
+    )?;
+    send_raw(ctx, pkt, route[0]).await
+}
+
+/// Sends a packet through a random route that ends with us. Relays send themselves a normal packet, while clients, which can only receive replies, send themselves a reply through a reply block of their own.
+async fn send_loop(ctx: &DaemonContext) -> anyhow::Result<()> {
+    let policy = &ctx.init().route_policy;
+    let route = loop_route(ctx, policy)?;
+    let instructs = route_to_instructs(ctx, &route)?;
+    let loop_id: u64 = rand::random();
+    let payload = cover_message(Bytes::copy_from_slice(&loop_id.to_be_bytes()));
+    let end = *route.last().context("empty loop route")?;
+
+    let mut degarbler_id = None;
+    let pkt = if ctx.init().is_client() {
+        let end_opk = ctx
+            .get(RELAY_GRAPH)
+            .read()
+            .identity(&end)
+            .context("no identity for the end of the loop route")?
+            .onion_pk;
+        let (rb, (rb_id, degarbler)) = ReplyBlock::new(
+            &instructs,
+            route[0],
+            &end_opk,
+            *ctx.get(MY_CLIENT_ID),
+            AnonEndpoint::random(),
+            policy.anonymity.delay_profile(),
+        )?;
+        ctx.get(DEGARBLERS).insert(rb_id, degarbler);
+        degarbler_id = Some(rb_id);
+        RawPacket::new_reply(&rb, payload, &RemoteId::Relay(end))?
+    } else {
+        let my_opk = ctx.get(MY_RELAY_ONION_KEYS).read().current().public();
+        RawPacket::new_normal(
+            &instructs,
+            &my_opk,
+            payload,
+            RemoteId::Anon(AnonEndpoint::random()),
+            policy.anonymity.delay_profile(),
+        )?
+    };
+
+    // we only learn about the relays that we route through, not about ourselves
+    let my_fp = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|id| id.public().fingerprint());
+    let relays: Vec<RelayFingerprint> = route
+        .iter()
+        .copied()
+        .filter(|fp| Some(*fp) != my_fp)
+        .collect();
+    let reliability = ctx.get(RELAY_RELIABILITY);
+    for fp in relays.iter() {
+        reliability.entry(*fp).or_default().sent += 1;
+    }
+    ctx.get(PENDING_LOOPS).insert(
+        loop_id,
+        Arc::new(PendingLoop {
+            relays,
+            degarbler_id,
+        }),
+    );
+
+    send_raw(ctx, pkt, route[0]).await
+}
@@ -3,20 +3,24 @@ use earendil_crypt::{AnonEndpoint, RelayFingerprint, RemoteId};
 use earendil_packet::{InnerPacket, RawPacket, ReplyBlock};
 use moka::sync::Cache;
 use parking_lot::Mutex;
-use rand::prelude::*;
 use std::time::Duration;
 
 use crate::{
-    context::{CtxField, DaemonContext, MY_CLIENT_ID, MY_RELAY_IDENTITY, RELAY_GRAPH},
-    n2r::{forward_route_to, route_to_instructs, DEGARBLERS},
-    network::{all_relay_neighs, send_raw},
+    config::RoutePolicy,
+    context::{CtxField, DaemonContext, MY_CLIENT_ID, RELAY_GRAPH},
+    n2r::{
+        route::{forward_route_to, reply_route},
+        route_to_instructs, DEGARBLERS,
+    },
+    network::send_raw,
 };
 
 static LAWK: Mutex<()> = Mutex::new(());
 
-/// Call to replenish remote reply blocks as needed.
+/// Call to replenish remote reply blocks as needed. Both the reply blocks and the packets carrying them are routed according to the policy.
 pub async fn replenish_remote_rb(
     ctx: &DaemonContext,
+    policy: &RoutePolicy,
     my_anon_id: AnonEndpoint,
     dst_fp: RelayFingerprint,
 ) -> anyhow::Result<()> {
@@ -34,13 +38,13 @@ pub async fn replenish_remote_rb(
         }
     }
     for _ in 0..count {
-        send_reply_blocks(ctx, BATCH_SIZE, my_anon_id, dst_fp).await?;
+        send_reply_blocks(ctx, policy, BATCH_SIZE, my_anon_id, dst_fp).await?;
     }
     Ok(())
 }
 
-/// Decrements the estimate of how many reply blocks the other side has. If needed, replenishes too.
-pub async fn consume_remote_rb(
+/// Decrements the estimate of how many reply blocks the other side has.
+pub fn consume_remote_rb(
     ctx: &DaemonContext,
     my_anon_id: AnonEndpoint,
     reply_source: RelayFingerprint,
@@ -48,7 +52,6 @@ pub async fn consume_remote_rb(
     let new_balance = rb_balance(ctx, my_anon_id, reply_source);
     ctx.get(BALANCE_TABLE)
         .insert((my_anon_id, reply_source), new_balance - 1.0);
-    let _ = replenish_remote_rb(ctx, my_anon_id, reply_source).await;
 }
 
 fn rb_balance(
@@ -66,17 +69,18 @@ static BALANCE_TABLE: CtxField<Cache<(AnonEndpoint, RelayFingerprint), f64>> = |
         .build()
 };
 
-#[tracing::instrument(skip(ctx))]
+#[tracing::instrument(skip(ctx, policy))]
 /// Send a batch of reply blocks to the given N2R destination.
 async fn send_reply_blocks(
     ctx: &DaemonContext,
+    policy: &RoutePolicy,
     count: usize,
     my_anon_id: AnonEndpoint,
     dst_fp: RelayFingerprint,
 ) -> anyhow::Result<()> {
     tracing::trace!("sending a batch of {count} reply blocks for {my_anon_id} to {dst_fp}");
 
-    let route = forward_route_to(ctx, dst_fp).context("failed to form forward route")?;
+    let route = forward_route_to(ctx, policy, dst_fp).context("failed to form forward route")?;
     let first_peeler = route[0];
 
     let dest_opk = ctx
@@ -88,7 +92,7 @@ async fn send_reply_blocks(
 
     let instructs = route_to_instructs(ctx, &route).context("failed to translate forward route")?;
     // currently the path for every one of them is the same; will want to change this in the future
-    let reverse_route = reply_route(ctx).context("failed to form reply route")?;
+    let reverse_route = reply_route(ctx, policy, dst_fp).context("failed to form reply route")?;
     let rb_dest_opk = ctx
         .get(RELAY_GRAPH)
         .read()
@@ -107,6 +111,7 @@ async fn send_reply_blocks(
             &rb_dest_opk,
             *ctx.get(MY_CLIENT_ID),
             my_anon_id,
+            policy.anonymity.delay_profile(),
         )
         .context("cannot build reply block")?;
         rbs.push(rb);
@@ -117,6 +122,7 @@ async fn send_reply_blocks(
         &dest_opk,
         InnerPacket::ReplyBlocks(rbs),
         RemoteId::Anon(my_anon_id),
+        policy.anonymity.delay_profile(),
     )?;
 
     send_raw(ctx, wrapped_rb_onion, first_peeler)
@@ -127,20 +133,3 @@ async fn send_reply_blocks(
 
     Ok(())
 }
-
-fn reply_route(ctx: &DaemonContext) -> anyhow::Result<Vec<RelayFingerprint>> {
-    let mut route = ctx.get(RELAY_GRAPH).read().rand_relays(2);
-    let mut my_neighs: Vec<RelayFingerprint> = all_relay_neighs(ctx);
-    if let Some(myself) = ctx.get(MY_RELAY_IDENTITY) {
-        my_neighs.push(myself.public().fingerprint());
-    }
-    let rand_neigh = my_neighs.choose(&mut rand::thread_rng()).copied();
-
-    match rand_neigh {
-        Some(neigh) => route.push(neigh),
-        None => anyhow::bail!("we don't have any neighbors, so we cannot plot a reply route"),
-    }
-
-    tracing::trace!("reply route formed: {:?}", route);
-    Ok(route)
-}
@@ -0,0 +1,255 @@
+use anyhow::Context;
+use earendil_crypt::RelayFingerprint;
+use earendil_packet::MAX_HOPS;
+use earendil_topology::RelayGraph;
+use rand::prelude::*;
+
+use crate::{
+    config::RoutePolicy,
+    context::{DaemonContext, MY_RELAY_IDENTITY, RELAY_GRAPH},
+    network::all_relay_neighs,
+};
+
+/// Forms a route to the destination that follows the policy. The route ends with the destination itself.
+pub fn forward_route_to(
+    ctx: &DaemonContext,
+    policy: &RoutePolicy,
+    dest_fp: RelayFingerprint,
+) -> anyhow::Result<Vec<RelayFingerprint>> {
+    let my_fp = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|id| id.public().fingerprint());
+    // clients hand packets to one of their neighbors, so that's where their routes start
+    let starts = match my_fp {
+        Some(my_fp) => vec![my_fp],
+        None => all_relay_neighs(ctx),
+    };
+    let avoid: Vec<RelayFingerprint> = my_fp.into_iter().collect();
+
+    let mut route = pick_relays(
+        &ctx.get(RELAY_GRAPH).read(),
+        policy,
+        &starts,
+        dest_fp,
+        &avoid,
+    )?;
+    route.push(dest_fp);
+    tracing::trace!("forward route formed: {:?}", route);
+    Ok(route)
+}
+
+/// Forms a route that follows the policy, for reply blocks that the given relay uses to reach us. The route ends with either us or one of our neighbors.
+pub fn reply_route(
+    ctx: &DaemonContext,
+    policy: &RoutePolicy,
+    src_fp: RelayFingerprint,
+) -> anyhow::Result<Vec<RelayFingerprint>> {
+    let my_fp = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|id| id.public().fingerprint());
+    let mut ends = all_relay_neighs(ctx);
+    ends.extend(my_fp);
+    let end = *ends
+        .choose(&mut rand::thread_rng())
+        .context("we don't have any neighbors, so we cannot plot a reply route")?;
+    let mut avoid = vec![src_fp];
+    avoid.extend(my_fp);
+
+    let mut route = pick_relays(&ctx.get(RELAY_GRAPH).read(), policy, &[src_fp], end, &avoid)?;
+    route.push(end);
+    tracing::trace!("reply route formed: {:?}", route);
+    Ok(route)
+}
+
+/// Forms a route that follows the policy and leads back to us. For relays the route ends with us, while for clients it ends with one of our neighbors, which hands packets to us.
+pub fn loop_route(
+    ctx: &DaemonContext,
+    policy: &RoutePolicy,
+) -> anyhow::Result<Vec<RelayFingerprint>> {
+    let my_fp = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|id| id.public().fingerprint());
+    let (starts, end) = match my_fp {
+        Some(my_fp) => (vec![my_fp], my_fp),
+        None => {
+            let neighs = all_relay_neighs(ctx);
+            let end = *neighs
+                .choose(&mut rand::thread_rng())
+                .context("we don't have any neighbors, so we cannot plot a loop route")?;
+            (neighs, end)
+        }
+    };
+    let avoid: Vec<RelayFingerprint> = my_fp.into_iter().collect();
+
+    let mut route = pick_relays(&ctx.get(RELAY_GRAPH).read(), policy, &starts, end, &avoid)?;
+    route.push(end);
+    tracing::trace!("loop route formed: {:?}", route);
+    Ok(route)
+}
+
+/// Picks `policy.hops` distinct relays to route through, from somewhere in `starts` to `end`, or as many as there are if the graph has fewer usable relays, as long as that is at least `policy.min_hops`. The picked relays never include `end`, anything in `avoid`, or anything the policy excludes, and every one of them can reach the next.
+fn pick_relays(
+    graph: &RelayGraph,
+    policy: &RoutePolicy,
+    starts: &[RelayFingerprint],
+    end: RelayFingerprint,
+    avoid: &[RelayFingerprint],
+) -> anyhow::Result<Vec<RelayFingerprint>> {
+    anyhow::ensure!(
+        policy.hops < MAX_HOPS,
+        "route policy asks for {} hops, but packets can only go through {}",
+        policy.hops,
+        MAX_HOPS - 1
+    );
+    // links are bidirectional, so every relay reachable from the start can also reach every other one, including the end
+    let reachable = graph.distances_from(starts);
+    anyhow::ensure!(
+        reachable.contains_key(&end),
+        "{end} is not reachable in the relay graph"
+    );
+
+    let mut route: Vec<RelayFingerprint> = vec![];
+    for _ in 0..policy.hops {
+        let distances = match route.last() {
+            Some(prev) => graph.distances_from(&[*prev]),
+            None => reachable.clone(),
+        };
+        let candidates: Vec<(RelayFingerprint, usize)> = distances
+            .into_iter()
+            .filter(|(fp, _)| {
+                *fp != end
+                    && !avoid.contains(fp)
+                    && !policy.exclude.contains(fp)
+                    && !route.contains(fp)
+                    && graph.identity(fp).is_some()
+            })
+            .collect();
+        let mut rng = rand::thread_rng();
+        let next = if policy.latency_weighted {
+            // every link between two hops adds latency, so nearby relays are much more likely to be picked
+            candidates
+                .choose_weighted(&mut rng, |(_, distance)| {
+                    1.0 / ((distance + 1) * (distance + 1)) as f64
+                })
+                .ok()
+        } else {
+            candidates.choose(&mut rng)
+        };
+        let Some((next, _)) = next else {
+            // small networks just don't have enough relays, and unless the policy says otherwise, a shorter route beats none at all
+            anyhow::ensure!(
+                route.len() >= policy.min_hops,
+                "only {} usable relays on the way to {end}, but the route policy needs at least {}",
+                route.len(),
+                policy.min_hops
+            );
+            tracing::warn!(
+                hops = route.len(),
+                wanted = policy.hops,
+                "not enough usable relays, shortening route"
+            );
+            break;
+        };
+        route.push(*next);
+    }
+    Ok(route)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use bytes::Bytes;
+    use earendil_crypt::RelayIdentitySecret;
+    use earendil_packet::crypt::DhSecret;
+    use earendil_topology::{AdjacencyDescriptor, IdentityDescriptor};
+
+    use super::*;
+
+    /// Builds a graph of relays linked in a ring, plus one relay with no links at all.
+    fn ring_graph(size: usize) -> (RelayGraph, Vec<RelayFingerprint>, RelayFingerprint) {
+        let mut graph = RelayGraph::new();
+        let sks: Vec<RelayIdentitySecret> =
+            (0..size).map(|_| RelayIdentitySecret::generate()).collect();
+        let loner = RelayIdentitySecret::generate();
+        for sk in sks.iter().chain([&loner]) {
+            graph
+                .insert_identity(IdentityDescriptor::new(sk, &DhSecret::generate(), u64::MAX))
+                .unwrap();
+        }
+        for i in 0..size {
+            let (mut left, mut right) = (&sks[i], &sks[(i + 1) % size]);
+            if left.public().fingerprint() > right.public().fingerprint() {
+                std::mem::swap(&mut left, &mut right);
+            }
+            let mut adj = AdjacencyDescriptor {
+                left: left.public().fingerprint(),
+                right: right.public().fingerprint(),
+                left_sig: Bytes::new(),
+                right_sig: Bytes::new(),
+                unix_timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            };
+            adj.left_sig = left.sign(adj.to_sign().as_bytes());
+            adj.right_sig = right.sign(adj.to_sign().as_bytes());
+            graph.insert_adjacency(adj).unwrap();
+        }
+        let fps = sks.iter().map(|sk| sk.public().fingerprint()).collect();
+        (graph, fps, loner.public().fingerprint())
+    }
+
+    #[test]
+    fn routes_follow_policy() {
+        let (graph, fps, _) = ring_graph(8);
+        for latency_weighted in [false, true] {
+            let policy = RoutePolicy {
+                hops: 4,
+                exclude: vec![fps[2]],
+                latency_weighted,
+                ..Default::default()
+            };
+            for _ in 0..50 {
+                let route = pick_relays(&graph, &policy, &[fps[0]], fps[4], &[fps[0]]).unwrap();
+                assert_eq!(route.len(), 4);
+                for (i, fp) in route.iter().enumerate() {
+                    assert!(![fps[0], fps[2], fps[4]].contains(fp));
+                    assert!(!route[i + 1..].contains(fp));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn small_graphs_shorten_routes() {
+        let (graph, fps, _) = ring_graph(8);
+        // only 6 relays are left once the start and the end are taken out
+        let policy = RoutePolicy {
+            hops: 7,
+            ..Default::default()
+        };
+        let route = pick_relays(&graph, &policy, &[fps[0]], fps[4], &[fps[0]]).unwrap();
+        assert_eq!(route.len(), 6);
+
+        let strict = RoutePolicy {
+            hops: 7,
+            min_hops: 7,
+            ..Default::default()
+        };
+        assert!(pick_relays(&graph, &strict, &[fps[0]], fps[4], &[fps[0]]).is_err());
+    }
+
+    #[test]
+    fn rejects_impossible_policies() {
+        let (graph, fps, loner) = ring_graph(8);
+        let too_long = RoutePolicy {
+            hops: MAX_HOPS,
+            ..Default::default()
+        };
+        assert!(pick_relays(&graph, &too_long, &[fps[0]], fps[4], &[]).is_err());
+
+        let unreachable = pick_relays(&graph, &RoutePolicy::default(), &[fps[0]], loner, &[]);
+        assert!(unreachable.is_err());
+    }
+}
@@ -13,6 +13,7 @@ use serde::{Deserialize, Serialize};
 use smol::future::FutureExt as _;
 
 use crate::{
+    config::RoutePolicy,
     context::{DaemonContext, MY_RELAY_IDENTITY},
     n2r,
 };
@@ -109,6 +110,7 @@ impl N2rRelaySocket {
 pub struct N2rClientSocket {
     ctx: DaemonContext,
     endpoint: AnonEndpoint,
+    route_policy: RoutePolicy,
     recv_incoming: Arc<QueueReceiver<(Bytes, RelayEndpoint)>>, // relays can only ever receive communication from clients
 }
 
@@ -117,15 +119,23 @@ impl N2rClientSocket {
         let recv_incoming = new_client_queue(&ctx, my_anon_id)?;
 
         Ok(N2rClientSocket {
+            route_policy: ctx.init().route_policy.clone(),
             ctx,
             endpoint: my_anon_id,
             recv_incoming: Arc::new(recv_incoming),
         })
     }
 
+    /// Routes everything this socket sends, and the reply blocks it hands out, according to the given policy rather than the one in the config.
+    pub fn with_route_policy(mut self, policy: RoutePolicy) -> Self {
+        self.route_policy = policy;
+        self
+    }
+
     pub async fn send_to(&self, body: Bytes, endpoint: RelayEndpoint) -> anyhow::Result<()> {
         n2r::send_forward(
             &self.ctx,
+            &self.route_policy,
             self.endpoint,
             endpoint.fingerprint,
             endpoint.dock,
@@ -137,12 +147,16 @@ impl N2rClientSocket {
     }
 
     pub async fn supply_reply_blocks(&self, fingerprint: RelayFingerprint) -> anyhow::Result<()> {
-        n2r::replenish_remote_rb(&self.ctx, self.endpoint, fingerprint).await?;
+        n2r::replenish_remote_rb(&self.ctx, &self.route_policy, self.endpoint, fingerprint).await?;
         Ok(())
     }
 
     pub async fn recv_from(&self) -> anyhow::Result<(Bytes, RelayEndpoint)> {
         let (message, source) = self.recv_incoming.recv().await?;
+        // every message used up one of the reply blocks the source had
+        if let Err(err) = self.supply_reply_blocks(source.fingerprint).await {
+            tracing::debug!(err = debug(err), "could not replenish reply blocks");
+        }
 
         Ok((message, source))
     }
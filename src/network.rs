@@ -1,20 +1,34 @@
+mod replay_guard;
 mod spider;
 
-use std::time::{Duration, Instant};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Context;
 use async_recursion::async_recursion;
-use dashmap::DashSet;
 use earendil_crypt::{ClientId, RelayFingerprint};
 use earendil_packet::{PeeledPacket, RawBody, RawPacket};
+use earendil_topology::DEFAULT_LINK_WEIGHT;
+use parking_lot::Mutex;
+use rand::seq::SliceRandom;
 use smol::channel::Receiver;
 
 use crate::{
-    context::{CtxField, DaemonContext, MY_RELAY_IDENTITY, MY_RELAY_ONION_SK, RELAY_GRAPH},
+    context::{
+        CtxField, DaemonContext, DEBTS, MY_RELAY_IDENTITY, MY_RELAY_ONION_KEYS, RELAY_GRAPH,
+    },
+    db::{db_delete_range, db_read, db_write},
+    delay_queue::DelayQueue,
     n2r,
+    onion_keys::rotate_full_onion_key,
 };
 
-use self::spider::Spider;
+use self::{
+    replay_guard::{layer_key, ReplayCheck, ReplayGuard, MANIFEST_KEY},
+    spider::Spider,
+};
 
 /// Dumps a raw packet onto the network with its next peeler, trying our best to have it go in the right direction.
 pub async fn send_raw(
@@ -54,6 +68,72 @@ pub async fn send_raw(
     Ok(())
 }
 
+/// The most packets we hold back for their mix delays at once. Packets are about 20 KB each, so this caps the queue at roughly 200 MB.
+const MAX_MIX_QUEUE_DEPTH: usize = 10_000;
+
+/// Holds peeled packets until their mix delays elapse.
+pub static MIX_QUEUE: CtxField<DelayQueue<(RawPacket, RelayFingerprint)>> =
+    |_| DelayQueue::new(MAX_MIX_QUEUE_DEPTH);
+
+/// Counts delayed packets that could not be sent onwards once their delays elapsed.
+pub static MIX_DROPPED: CtxField<AtomicU64> = |_| AtomicU64::new(0);
+
+/// Loop that sends out peeled packets once their mix delays elapse.
+pub async fn mix_queue_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    let queue = ctx.get(MIX_QUEUE);
+    loop {
+        let (pkt, next_peeler) = queue.pop().await;
+        if let Err(err) = send_raw(&ctx, pkt, next_peeler).await {
+            ctx.get(MIX_DROPPED).fetch_add(1, Ordering::Relaxed);
+            tracing::debug!(
+                next_peeler = display(next_peeler),
+                err = debug(err),
+                "dropping delayed packet"
+            );
+        }
+    }
+}
+
+/// Remembers the hashes of raw packets seen while our onion keys are valid, so that replayed packets get dropped. Restored from the state cache if possible, in which case the flag is set.
+pub static PKT_REPLAY_GUARD: CtxField<(Mutex<ReplayGuard>, bool)> = |ctx| {
+    let restored = smol::future::block_on(async {
+        let manifest = db_read(ctx, MANIFEST_KEY).await.ok().flatten()?;
+        let read_layer = |key: String| async move { db_read(ctx, &key).await.ok().flatten() };
+        ReplayGuard::restore(&manifest, read_layer)
+            .await
+            .map_err(|err| tracing::warn!("cannot restore the replay filter: {err}"))
+            .ok()
+    });
+    let was_restored = restored.is_some();
+    (
+        Mutex::new(restored.unwrap_or_else(ReplayGuard::new)),
+        was_restored,
+    )
+};
+
+/// Persists what changed in the replay filter since the last time, or all of its manifest if `force` is set even though nothing changed. Only the changed layers are copied while the filter is locked, and they are serialized and written out once it is unlocked.
+pub async fn persist_replay_guard(ctx: &DaemonContext, force: bool) -> anyhow::Result<()> {
+    let snapshot = {
+        let mut guard = ctx.get(PKT_REPLAY_GUARD).0.lock();
+        if !force && !guard.is_dirty() {
+            return Ok(());
+        }
+        guard.snapshot()
+    };
+    // layers go first, so that the manifest never lists a layer that isn't there
+    for (key, bts) in snapshot.layer_entries() {
+        db_write(ctx, &key, bts).await?;
+    }
+    db_write(ctx, MANIFEST_KEY, snapshot.manifest_bytes()).await?;
+    // then clean up the layers of partitions that are gone
+    let first_kept = match snapshot.oldest_created() {
+        Some(created) => layer_key(created, 0),
+        None => layer_key(u64::MAX, usize::MAX),
+    };
+    db_delete_range(ctx, &format!("{MANIFEST_KEY}/"), &first_kept).await?;
+    Ok(())
+}
+
 #[tracing::instrument(skip(ctx, pkt), fields(packet_hash=debug(blake3::hash(bytemuck::bytes_of(&pkt)))))]
 #[async_recursion]
 pub async fn incoming_raw(
@@ -62,7 +142,6 @@ pub async fn incoming_raw(
     pkt: RawPacket,
 ) -> anyhow::Result<()> {
     tracing::trace!("incoming raw packet!");
-    static PKTS_SEEN: CtxField<DashSet<blake3::Hash>> = |_| DashSet::new();
 
     let my_fp = ctx
         .get(MY_RELAY_IDENTITY)
@@ -70,18 +149,15 @@ pub async fn incoming_raw(
         .public()
         .fingerprint();
 
-    let pkts_seen = ctx.get(PKTS_SEEN);
-    let packet_hash = blake3::hash(bytemuck::bytes_of(&pkt));
-    if !pkts_seen.insert(packet_hash) {
-        anyhow::bail!("received replayed pkt {packet_hash}");
-    }
-
     tracing::trace!(my_fp = my_fp.to_string(), "on raw packet");
 
     if next_peeler == my_fp {
+        // only packets that we peel can be replayed to us, since the others are checked by their own peelers
+        check_replay(ctx, &pkt)?;
+
         // I am the designated peeler, peel and forward towards next peeler
         let now = Instant::now();
-        let peeled: PeeledPacket = pkt.peel(ctx.get(MY_RELAY_ONION_SK))?;
+        let peeled: PeeledPacket = ctx.get(MY_RELAY_ONION_KEYS).read().peel(&pkt)?;
 
         scopeguard::defer!(tracing::trace!(
             "message peel forward took {:?}",
@@ -94,18 +170,12 @@ pub async fn incoming_raw(
                 pkt,
                 delay_ms,
             } => {
-                let emit_time = Instant::now() + Duration::from_millis(delay_ms as u64);
-                // TODO delay queue here rather than this inefficient approach
-                let ctx = ctx.clone();
-                smolscale::spawn(async move {
-                    smol::Timer::at(emit_time).await;
-                    if let Err(e) = send_raw(&ctx, pkt, next_peeler).await {
-                        println!("network.rs line 102 failed with next_peeler = {next_peeler}, err = {e}");
-                        anyhow::bail!(e)
-                    }
-                    anyhow::Ok(())
-                })
-                .detach();
+                // the sender picks the delay, but we never hold a packet for longer than we are willing to
+                let delay_ms = (delay_ms as u64).min(ctx.init().max_mix_delay_ms);
+                let emit_time = Instant::now() + Duration::from_millis(delay_ms);
+                if !ctx.get(MIX_QUEUE).insert((pkt, next_peeler), emit_time) {
+                    anyhow::bail!("mix queue full, dropping packet for {next_peeler}");
+                }
             }
             PeeledPacket::Received { from, pkt } => {
                 if let Err(e) = n2r::incoming_forward(ctx, pkt, from).await {
@@ -147,6 +217,41 @@ pub async fn incoming_raw(
     Ok(())
 }
 
+/// Fails if the packet is a replay, remembering it otherwise.
+fn check_replay(ctx: &DaemonContext, pkt: &RawPacket) -> anyhow::Result<()> {
+    let packet_hash = blake3::hash(bytemuck::bytes_of(pkt));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let check = || {
+        let (key_created, key_expiry) = {
+            let keys = ctx.get(MY_RELAY_ONION_KEYS).read();
+            (keys.current_created(), keys.current_expiry())
+        };
+        let check = ctx.get(PKT_REPLAY_GUARD).0.lock().check_and_insert(
+            &packet_hash,
+            key_created,
+            key_expiry,
+            now,
+        );
+        (check, key_created)
+    };
+    let check = match check() {
+        // rather than dropping every packet until the key expires, move on to a fresh key with room in the filter
+        (ReplayCheck::Full, key_created) => {
+            rotate_full_onion_key(ctx, key_created);
+            check().0
+        }
+        (check, _) => check,
+    };
+    match check {
+        ReplayCheck::Fresh => Ok(()),
+        ReplayCheck::Replayed => anyhow::bail!("received replayed pkt {packet_hash}"),
+        ReplayCheck::Full => anyhow::bail!("replay filter full, dropping pkt {packet_hash}"),
+    }
+}
+
 fn one_hop_closer(ctx: &DaemonContext, dest: RelayFingerprint) -> anyhow::Result<RelayFingerprint> {
     let my_neighs: Vec<RelayFingerprint> = ctx.get(RELAY_SPIDER).keys();
 
@@ -154,19 +259,27 @@ fn one_hop_closer(ctx: &DaemonContext, dest: RelayFingerprint) -> anyhow::Result
         anyhow::bail!("cannot route one hop closer since we don't have ANY neighbors!")
     }
 
-    let mut shortest_route_len = usize::MAX;
-    let mut next_hop = None;
-
-    for neigh in my_neighs.iter() {
-        if let Some(route) = ctx.get(RELAY_GRAPH).read().find_shortest_path(neigh, &dest) {
-            if route.len() < shortest_route_len {
-                shortest_route_len = route.len();
-                next_hop = Some(*neigh);
-            }
-        }
-    }
+    // reaching a neighbor costs a link, weighted by its measured RTT if we are a relay, plus whatever that neighbor charges us
+    let my_fp = ctx
+        .get(MY_RELAY_IDENTITY)
+        .map(|id| id.public().fingerprint());
+    let graph = ctx.get(RELAY_GRAPH).read();
+    let sources: Vec<(RelayFingerprint, u64)> = my_neighs
+        .iter()
+        .map(|neigh| {
+            let weight = my_fp.map_or(DEFAULT_LINK_WEIGHT, |my_fp| {
+                graph.link_weight(&my_fp, neigh)
+            });
+            let price = ctx.get(DEBTS).relay_outgoing_price(neigh).unwrap_or(0);
+            (*neigh, weight.saturating_add(price))
+        })
+        .collect();
+    let next_hops = graph.next_hops(&sources, &dest);
 
-    next_hop
+    // spread traffic across all the equally good neighbors
+    next_hops
+        .choose(&mut rand::thread_rng())
+        .copied()
         .context(format!("cannot route one hop closer to {:?} since none of our neighbors ({:?}) could find a route there", dest, my_neighs))
 }
 
@@ -186,6 +299,16 @@ pub fn all_client_neighs(ctx: &DaemonContext) -> Vec<ClientId> {
     ctx.get(CLIENT_SPIDER).keys()
 }
 
+/// How many messages are waiting to be sent to the given neighboring relay.
+pub fn relay_queue_depth(ctx: &DaemonContext, neigh: RelayFingerprint) -> usize {
+    ctx.get(RELAY_SPIDER).queue_len(&neigh)
+}
+
+/// How many messages are waiting to be sent to the given neighboring client.
+pub fn client_queue_depth(ctx: &DaemonContext, neigh: ClientId) -> usize {
+    ctx.get(CLIENT_SPIDER).queue_len(&neigh)
+}
+
 pub type RelayLinkMsg = (RawPacket, RelayFingerprint);
 static RELAY_SPIDER: CtxField<Spider<RelayFingerprint, RelayLinkMsg>> = |_| Spider::new();
 
@@ -0,0 +1,419 @@
+use std::{collections::BTreeMap, future::Future};
+
+use serde::{Deserialize, Serialize};
+
+/// How many packets each Bloom filter layer is sized for.
+const LAYER_CAPACITY: u64 = 500_000;
+
+/// The most layers kept for one onion key. At most two keys are valid at once, which bounds the filter to about 58 MiB. Once a key's layers are all full, the key has to be rotated out early instead of old packets being forgotten, since forgetting would let replays of them through.
+const MAX_LAYERS_PER_KEY: usize = 16;
+
+/// Bits per packet, for a false-positive rate of about 1e-6 per full layer, since the optimal Bloom filter takes `-ln(p) / ln(2)^2` bits per item and `-log2(p)` hash functions.
+const BITS_PER_PACKET: u64 = 29;
+const HASHES: usize = 20;
+
+/// What the replay guard made of a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayCheck {
+    Fresh,
+    /// (probably) seen before
+    Replayed,
+    /// the partition of the onion key is full, so the packet can't be remembered until a fresh key is rotated in
+    Full,
+}
+
+/// A bounded-memory filter that rejects replayed packets, made of Bloom filters partitioned by onion key.
+///
+/// A packet can only be replayed for as long as some onion key can still decrypt it, so each packet goes into the partition of the onion key that is current when it arrives, and a partition is only dropped when its key expires. Packets encrypted to the previous key also land in the current key's partition, which outlives the previous key.
+///
+/// The `replay_filter` crate isn't used here, since it filters sequence numbers within a sliding window, while packet hashes are random.
+///
+/// The filter is persisted as a manifest of its partitions plus one entry per layer. Only the last layer of each partition ever changes, so only the layers that changed since the last snapshot get copied and written out.
+pub struct ReplayGuard {
+    /// keyed by when the partition's onion key was created
+    partitions: BTreeMap<u64, Partition>,
+    max_layers_per_key: usize,
+    layer_capacity: u64,
+    rejected: u64,
+    filled: u64,
+    /// whether anything changed since the filter was last persisted
+    dirty: bool,
+}
+
+/// For each partition, keyed by when its onion key was created, its expiry and how many layers it has.
+type Manifest = BTreeMap<u64, (u64, usize)>;
+
+/// The state cache key under which the manifest of the filter is persisted.
+pub const MANIFEST_KEY: &str = "replay_guard";
+
+/// The state cache key under which a layer of the filter is persisted. Creation times are padded, so that the keys of older partitions sort first.
+pub fn layer_key(created: u64, index: usize) -> String {
+    format!("{MANIFEST_KEY}/{created:020}/{index}")
+}
+
+/// The parts of a filter that changed since it was last persisted, as taken by [ReplayGuard::snapshot].
+pub struct ReplayGuardSnapshot {
+    manifest: Manifest,
+    layers: Vec<(u64, usize, Layer)>,
+}
+
+impl ReplayGuardSnapshot {
+    /// Serializes the changed layers, to be persisted under their keys before the manifest.
+    pub fn layer_entries(&self) -> impl Iterator<Item = (String, Vec<u8>)> + '_ {
+        self.layers.iter().map(|(created, index, layer)| {
+            let bts = stdcode::serialize(layer).expect("layers always serialize");
+            (layer_key(*created, *index), bts)
+        })
+    }
+
+    /// Serializes the manifest, to be persisted under [MANIFEST_KEY].
+    pub fn manifest_bytes(&self) -> Vec<u8> {
+        stdcode::serialize(&self.manifest).expect("manifests always serialize")
+    }
+
+    /// When the onion key of the oldest partition was created, if there is any. The layers of older partitions are no longer needed.
+    pub fn oldest_created(&self) -> Option<u64> {
+        self.manifest.keys().next().copied()
+    }
+}
+
+struct Partition {
+    /// the UNIX timestamp at which the partition's onion key expires
+    expiry: u64,
+    layers: Vec<Layer>,
+}
+
+impl ReplayGuard {
+    pub fn new() -> Self {
+        Self::with_limits(LAYER_CAPACITY, MAX_LAYERS_PER_KEY)
+    }
+
+    fn with_limits(layer_capacity: u64, max_layers_per_key: usize) -> Self {
+        Self {
+            partitions: BTreeMap::new(),
+            max_layers_per_key,
+            layer_capacity,
+            rejected: 0,
+            filled: 0,
+            dirty: false,
+        }
+    }
+
+    /// Restores a filter persisted through [ReplayGuard::snapshot], given its manifest and a way to read the layers that the manifest lists.
+    pub async fn restore<F: Future<Output = Option<Vec<u8>>>>(
+        manifest: &[u8],
+        read_layer: impl Fn(String) -> F,
+    ) -> anyhow::Result<Self> {
+        let manifest: Manifest = stdcode::deserialize(manifest)?;
+        let mut guard = Self::new();
+        for (created, (expiry, layer_count)) in manifest {
+            let mut layers = vec![];
+            for index in 0..layer_count {
+                let key = layer_key(created, index);
+                let bts = read_layer(key.clone())
+                    .await
+                    .ok_or_else(|| anyhow::anyhow!("replay filter layer {key} is missing"))?;
+                layers.push(stdcode::deserialize(&bts)?);
+            }
+            guard
+                .partitions
+                .insert(created, Partition { expiry, layers });
+        }
+        Ok(guard)
+    }
+
+    /// Copies out what changed since the last snapshot, for persisting in the state cache, and marks it as persisted. This only copies the layers that changed, so it is quick enough to do while holding up packets.
+    pub fn snapshot(&mut self) -> ReplayGuardSnapshot {
+        self.dirty = false;
+        let manifest = self
+            .partitions
+            .iter()
+            .map(|(created, partition)| (*created, (partition.expiry, partition.layers.len())))
+            .collect();
+        let mut layers = vec![];
+        for (created, partition) in self.partitions.iter_mut() {
+            for (index, layer) in partition.layers.iter_mut().enumerate() {
+                if layer.dirty {
+                    layer.dirty = false;
+                    layers.push((*created, index, layer.clone()));
+                }
+            }
+        }
+        ReplayGuardSnapshot { manifest, layers }
+    }
+
+    /// Whether the filter changed since it was last serialized.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Records a packet hash that arrived while the onion key created at `key_created`, which expires at `key_expiry`, was current.
+    pub fn check_and_insert(
+        &mut self,
+        pkt_hash: &blake3::Hash,
+        key_created: u64,
+        key_expiry: u64,
+        now: u64,
+    ) -> ReplayCheck {
+        let before = self.partitions.len();
+        self.partitions
+            .retain(|_, partition| partition.expiry > now);
+        self.dirty |= self.partitions.len() != before;
+
+        let hashes = base_hashes(pkt_hash);
+        if self.layers().any(|layer| layer.contains(hashes)) {
+            self.rejected += 1;
+            return ReplayCheck::Replayed;
+        }
+
+        let partition = self
+            .partitions
+            .entry(key_created)
+            .or_insert_with(|| Partition {
+                expiry: key_expiry,
+                layers: vec![],
+            });
+        if partition
+            .layers
+            .last()
+            .is_none_or(|layer| layer.count >= layer.capacity)
+        {
+            if partition.layers.len() >= self.max_layers_per_key {
+                self.filled += 1;
+                return ReplayCheck::Full;
+            }
+            partition.layers.push(Layer::new(self.layer_capacity));
+        }
+        partition.layers.last_mut().unwrap().insert(hashes);
+        self.dirty = true;
+        ReplayCheck::Fresh
+    }
+
+    /// Drops the partitions of onion keys created before `oldest_created`, which we no longer hold and so can't be replayed to.
+    pub fn forget_keys_before(&mut self, oldest_created: u64) {
+        let before = self.partitions.len();
+        self.partitions = self.partitions.split_off(&oldest_created);
+        self.dirty |= self.partitions.len() != before;
+    }
+
+    fn layers(&self) -> impl Iterator<Item = &Layer> {
+        self.partitions
+            .values()
+            .flat_map(|partition| partition.layers.iter())
+    }
+
+    /// The number of packets rejected as replays.
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// The number of times a packet found its onion key's partition full, forcing an early key rotation.
+    pub fn filled(&self) -> u64 {
+        self.filled
+    }
+
+    /// The number of packets currently remembered.
+    pub fn remembered(&self) -> u64 {
+        self.layers().map(|layer| layer.count).sum()
+    }
+
+    /// The estimated probability that a fresh packet gets wrongly rejected as a replay right now.
+    pub fn false_positive_rate(&self) -> f64 {
+        1.0 - self
+            .layers()
+            .map(|layer| 1.0 - layer.false_positive_rate())
+            .product::<f64>()
+    }
+
+    /// The memory used by the filter, in bytes. This never exceeds what `MAX_LAYERS_PER_KEY` layers for each held onion key take.
+    pub fn memory_bytes(&self) -> usize {
+        self.layers().map(|layer| layer.bits.len() * 8).sum()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Layer {
+    bits: Vec<u64>,
+    count: u64,
+    capacity: u64,
+    /// whether the layer changed since it was last persisted
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl Layer {
+    fn new(capacity: u64) -> Self {
+        Self {
+            bits: vec![0; (capacity * BITS_PER_PACKET).div_ceil(64) as usize],
+            count: 0,
+            capacity,
+            dirty: true,
+        }
+    }
+
+    fn num_bits(&self) -> u64 {
+        self.bits.len() as u64 * 64
+    }
+
+    fn contains(&self, hashes: (u64, u64)) -> bool {
+        bit_indices(hashes, self.num_bits()).all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    fn insert(&mut self, hashes: (u64, u64)) {
+        for i in bit_indices(hashes, self.num_bits()) {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+        self.count += 1;
+        self.dirty = true;
+    }
+
+    fn false_positive_rate(&self) -> f64 {
+        let filled = 1.0 - (-(HASHES as f64) * self.count as f64 / self.num_bits() as f64).exp();
+        filled.powi(HASHES as i32)
+    }
+}
+
+/// Derives the bit positions of a packet in a layer by double hashing.
+fn bit_indices((h1, h2): (u64, u64), num_bits: u64) -> impl Iterator<Item = usize> {
+    (0..HASHES as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+}
+
+/// The packet hash is already uniformly random, so its halves serve as the two base hashes for double hashing.
+fn base_hashes(pkt_hash: &blake3::Hash) -> (u64, u64) {
+    let bts = pkt_hash.as_bytes();
+    let h1 = u64::from_le_bytes(bts[..8].try_into().unwrap());
+    let h2 = u64::from_le_bytes(bts[8..16].try_into().unwrap()) | 1;
+    (h1, h2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(i: u64) -> blake3::Hash {
+        blake3::hash(&i.to_le_bytes())
+    }
+
+    #[test]
+    fn rejects_replays() {
+        let mut guard = ReplayGuard::with_limits(10_000, 4);
+        for i in 0..1000 {
+            assert_eq!(
+                guard.check_and_insert(&hash(i), 0, 100, 0),
+                ReplayCheck::Fresh
+            );
+        }
+        for i in 0..1000 {
+            assert_eq!(
+                guard.check_and_insert(&hash(i), 0, 100, 0),
+                ReplayCheck::Replayed
+            );
+        }
+        assert_eq!(guard.rejected(), 1000);
+        assert_eq!(guard.remembered(), 1000);
+        assert!(guard.false_positive_rate() < 1e-12);
+    }
+
+    #[test]
+    fn remembers_until_the_key_expires() {
+        let mut guard = ReplayGuard::with_limits(1000, 4);
+        assert_eq!(
+            guard.check_and_insert(&hash(0), 0, 200, 0),
+            ReplayCheck::Fresh
+        );
+        // the next key's packets don't push out the old key's, even after rotation
+        for i in 1..=100 {
+            guard.check_and_insert(&hash(i), 100, 300, 150);
+        }
+        assert_eq!(
+            guard.check_and_insert(&hash(0), 100, 300, 199),
+            ReplayCheck::Replayed
+        );
+        assert_eq!(
+            guard.check_and_insert(&hash(0), 200, 400, 200),
+            ReplayCheck::Fresh
+        );
+    }
+
+    #[test]
+    fn full_partition_waits_for_the_next_key_instead_of_forgetting() {
+        let mut guard = ReplayGuard::with_limits(100, 2);
+        for i in 0..200 {
+            assert_eq!(
+                guard.check_and_insert(&hash(i), 0, 100, 0),
+                ReplayCheck::Fresh
+            );
+        }
+        assert_eq!(
+            guard.check_and_insert(&hash(1000), 0, 100, 0),
+            ReplayCheck::Full
+        );
+        assert_eq!(guard.filled(), 1);
+        assert_eq!(
+            guard.check_and_insert(&hash(0), 0, 100, 0),
+            ReplayCheck::Replayed
+        );
+        assert_eq!(
+            guard.memory_bytes(),
+            2 * (100 * BITS_PER_PACKET).div_ceil(64) as usize * 8
+        );
+
+        // a fresh key gets a fresh partition, while the full one keeps catching replays
+        assert_eq!(
+            guard.check_and_insert(&hash(1000), 10, 110, 10),
+            ReplayCheck::Fresh
+        );
+        assert_eq!(
+            guard.check_and_insert(&hash(0), 10, 110, 10),
+            ReplayCheck::Replayed
+        );
+
+        // until the key it belongs to is gone
+        guard.forget_keys_before(10);
+        assert_eq!(guard.remembered(), 1);
+        assert_eq!(
+            guard.check_and_insert(&hash(0), 10, 110, 10),
+            ReplayCheck::Fresh
+        );
+    }
+
+    #[test]
+    fn survives_restart() {
+        let mut guard = ReplayGuard::with_limits(10, 4);
+        let mut db = BTreeMap::new();
+        let mut persist = |guard: &mut ReplayGuard| {
+            let snapshot = guard.snapshot();
+            let changed: Vec<String> = snapshot.layer_entries().map(|(key, _)| key).collect();
+            db.extend(snapshot.layer_entries());
+            db.insert(MANIFEST_KEY.to_string(), snapshot.manifest_bytes());
+            changed
+        };
+
+        for i in 0..15 {
+            guard.check_and_insert(&hash(i), 0, 100, 0);
+        }
+        assert!(guard.is_dirty());
+        assert_eq!(persist(&mut guard).len(), 2);
+        assert!(!guard.is_dirty());
+        // the first layer is full, so only the second one changes
+        guard.check_and_insert(&hash(15), 0, 100, 0);
+        assert_eq!(persist(&mut guard), vec![layer_key(0, 1)]);
+
+        let read_layer = |key: String| std::future::ready(db.get(&key).cloned());
+        let mut restored =
+            smol::future::block_on(ReplayGuard::restore(&db[MANIFEST_KEY], read_layer)).unwrap();
+        for i in 0..16 {
+            assert_eq!(
+                restored.check_and_insert(&hash(i), 0, 100, 0),
+                ReplayCheck::Replayed
+            );
+        }
+
+        // a missing layer means the filter can't be trusted
+        db.remove(&layer_key(0, 0));
+        let read_layer = |key: String| std::future::ready(db.get(&key).cloned());
+        assert!(
+            smol::future::block_on(ReplayGuard::restore(&db[MANIFEST_KEY], read_layer)).is_err()
+        );
+    }
+}
@@ -38,6 +38,11 @@ impl<T: Eq + std::hash::Hash + Clone + std::fmt::Display, U> Spider<T, U> {
         self.inner.read().contains_key(val)
     }
 
+    /// How many messages are waiting to be sent to the given destination.
+    pub fn queue_len(&self, dest: &T) -> usize {
+        self.inner.read().get(dest).map_or(0, |chan| chan.0.len())
+    }
+
     pub fn keys(&self) -> Vec<T> {
         self.inner.read().keys().cloned().collect()
     }
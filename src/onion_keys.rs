@@ -0,0 +1,239 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use earendil_packet::{crypt::DhSecret, PacketPeelError, PeeledPacket, RawPacket};
+use stdcode::StdcodeSerializeExt;
+
+use crate::{
+    context::{DaemonContext, MY_RELAY_ONION_KEYS},
+    db::db_write,
+    network::{persist_replay_guard, PKT_REPLAY_GUARD},
+};
+
+/// How often the replay filter is persisted, if it changed. A crash loses the packets remembered since, which can be replayed after a restart if the onion keys survive it.
+const REPLAY_GUARD_PERSIST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A relay's onion keys. The current key is rotated out every `lifetime` seconds, after which it stays valid as the previous key for one more `lifetime`, so that packets and reply blocks built with it still work.
+pub struct OnionKeys {
+    current: OnionKey,
+    previous: Option<OnionKey>,
+    lifetime: u64,
+}
+
+/// The seed and creation time of an onion key, which is all that gets persisted.
+type PersistedKey = ([u8; 32], u64);
+
+#[derive(Clone)]
+struct OnionKey {
+    seed: [u8; 32],
+    created: u64,
+    secret: DhSecret,
+}
+
+impl OnionKey {
+    fn generate(created: u64) -> Self {
+        Self::from_seed(rand::random(), created)
+    }
+
+    fn from_seed(seed: [u8; 32], created: u64) -> Self {
+        Self {
+            seed,
+            created,
+            secret: DhSecret::from_seed(&seed),
+        }
+    }
+}
+
+impl OnionKeys {
+    /// Creates a fresh set of onion keys.
+    pub fn new(lifetime: u64) -> Self {
+        Self {
+            current: OnionKey::generate(unix_now()),
+            previous: None,
+            lifetime,
+        }
+    }
+
+    /// Restores onion keys persisted by [OnionKeys::to_bytes], rotating them if they became due while we were down.
+    pub fn from_bytes(bts: &[u8], lifetime: u64) -> anyhow::Result<Self> {
+        let (current, previous): (PersistedKey, Option<PersistedKey>) = stdcode::deserialize(bts)?;
+        let mut keys = Self {
+            current: OnionKey::from_seed(current.0, current.1),
+            previous: previous.map(|(seed, created)| OnionKey::from_seed(seed, created)),
+            lifetime,
+        };
+        keys.rotate_if_due(unix_now());
+        Ok(keys)
+    }
+
+    /// Serializes the seeds of the onion keys, for persisting in the state cache.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        (
+            (self.current.seed, self.current.created),
+            self.previous.as_ref().map(|key| (key.seed, key.created)),
+        )
+            .stdcode()
+    }
+
+    /// The key that should be published and used for all new packets.
+    pub fn current(&self) -> &DhSecret {
+        &self.current.secret
+    }
+
+    /// The UNIX timestamp at which the current key was created, which identifies it.
+    pub fn current_created(&self) -> u64 {
+        self.current.created
+    }
+
+    /// The UNIX timestamp after which we stop accepting the current key.
+    pub fn current_expiry(&self) -> u64 {
+        self.current.created + 2 * self.lifetime
+    }
+
+    /// The UNIX timestamp at which the current key gets rotated out.
+    pub fn next_rotation(&self) -> u64 {
+        self.current.created + self.lifetime
+    }
+
+    /// The UNIX timestamp at which the oldest key we still hold was created.
+    pub fn oldest_created(&self) -> u64 {
+        self.previous
+            .as_ref()
+            .map_or(self.current.created, |key| key.created)
+    }
+
+    /// Rotates the keys if the current key is due, returning whether it did.
+    pub fn rotate_if_due(&mut self, now: u64) -> bool {
+        if now < self.next_rotation() {
+            return false;
+        }
+        self.rotate(now);
+        true
+    }
+
+    /// Rotates the keys right away, even if the current key isn't due. The key before the current one is dropped, even if it would still be in its grace window.
+    pub fn rotate(&mut self, now: u64) {
+        // keys are identified by when they were created, so two can't share a second
+        let now = now.max(self.current.created + 1);
+        let old = std::mem::replace(&mut self.current, OnionKey::generate(now));
+        // a key that has been due for more than a lifetime already expired, so it can't even serve as the previous key
+        self.previous = (now < old.created + 2 * self.lifetime).then_some(old);
+    }
+
+    /// Peels a packet with the current key, falling back to the previous key during its grace window.
+    pub fn peel(&self, pkt: &RawPacket) -> Result<PeeledPacket, PacketPeelError> {
+        match pkt.peel(&self.current.secret) {
+            Err(PacketPeelError::DecryptionError) if self.previous.is_some() => {
+                pkt.peel(&self.previous.as_ref().unwrap().secret)
+            }
+            res => res,
+        }
+    }
+}
+
+/// Loop that rotates our onion keys on schedule, persisting them every time they change, along with the replay filter that guards them.
+pub async fn onion_key_rotation_loop(ctx: DaemonContext) -> anyhow::Result<()> {
+    let mut persisted_created = ctx.get(MY_RELAY_ONION_KEYS).read().current_created();
+    loop {
+        let next_rotation = ctx.get(MY_RELAY_ONION_KEYS).read().next_rotation();
+        let wait = Duration::from_secs(next_rotation.saturating_sub(unix_now()));
+        smol::Timer::after(wait.min(REPLAY_GUARD_PERSIST_INTERVAL)).await;
+
+        let (current_created, oldest_created) = {
+            let mut keys = ctx.get(MY_RELAY_ONION_KEYS).write();
+            if keys.rotate_if_due(unix_now()) {
+                tracing::debug!(
+                    onion_pk = debug(keys.current().public()),
+                    "rotated onion key"
+                );
+            }
+            (keys.current_created(), keys.oldest_created())
+        };
+        // the key may also have been rotated early, since the last time around
+        let rotated = current_created != persisted_created;
+        ctx.get(PKT_REPLAY_GUARD)
+            .0
+            .lock()
+            .forget_keys_before(oldest_created);
+        // the filter goes first, since keys are only restored along with a filter
+        persist_replay_guard(&ctx, rotated).await?;
+        if rotated {
+            let bts = ctx.get(MY_RELAY_ONION_KEYS).read().to_bytes();
+            db_write(&ctx, "onion_keys", bts).await?;
+            persisted_created = current_created;
+        }
+    }
+}
+
+/// Rotates our onion key ahead of schedule, because the replay filter can't remember any more packets for the key created at `full_key_created`. Does nothing if that key was already rotated out.
+pub fn rotate_full_onion_key(ctx: &DaemonContext, full_key_created: u64) {
+    let mut keys = ctx.get(MY_RELAY_ONION_KEYS).write();
+    if keys.current_created() != full_key_created {
+        return;
+    }
+    keys.rotate(unix_now());
+    tracing::warn!(
+        onion_pk = debug(keys.current().public()),
+        "rotated onion key early, since the replay filter filled up"
+    );
+    ctx.get(PKT_REPLAY_GUARD)
+        .0
+        .lock()
+        .forget_keys_before(keys.oldest_created());
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_keeps_previous_key() {
+        let mut keys = OnionKeys::new(100);
+        let first = keys.current().public();
+        let start = keys.current.created;
+        assert!(!keys.rotate_if_due(start + 99));
+        assert!(keys.rotate_if_due(start + 100));
+        assert_ne!(keys.current().public(), first);
+        assert_eq!(keys.previous.as_ref().unwrap().secret.public(), first);
+
+        // the first key falls out at the next rotation
+        assert!(keys.rotate_if_due(start + 200));
+        assert_ne!(keys.previous.as_ref().unwrap().secret.public(), first);
+    }
+
+    #[test]
+    fn long_downtime_drops_expired_keys() {
+        let mut keys = OnionKeys::new(100);
+        let start = keys.current.created;
+        assert!(keys.rotate_if_due(start + 500));
+        assert!(keys.previous.is_none());
+    }
+
+    #[test]
+    fn early_rotation_drops_the_oldest_key() {
+        let mut keys = OnionKeys::new(100);
+        let start = keys.current.created;
+        keys.rotate(start);
+        assert!(keys.current.created > start);
+        assert_eq!(keys.oldest_created(), start);
+
+        // the first key would still be in its grace window, but only two keys are ever kept
+        keys.rotate(start + 1);
+        assert!(keys.oldest_created() > start);
+        assert_eq!(keys.next_rotation(), keys.current.created + 100);
+    }
+
+    #[test]
+    fn persisted_keys_survive_restart() {
+        let keys = OnionKeys::new(1000);
+        let restored = OnionKeys::from_bytes(&keys.to_bytes(), 1000).unwrap();
+        assert_eq!(restored.current().public(), keys.current().public());
+        assert_eq!(restored.current_expiry(), keys.current_expiry());
+    }
+}
@@ -1,23 +1,39 @@
 use std::{
-    collections::HashSet,
+    collections::VecDeque,
     fmt,
     sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::Context;
 use blake3::Hash;
 use bytes::Bytes;
 use dashmap::DashMap;
-use earendil_crypt::{RelayFingerprint, RelayIdentityPublic, RelayIdentitySecret};
+use earendil_crypt::{ClientId, RelayFingerprint, RelayIdentityPublic, RelayIdentitySecret};
+use either::Either;
 use melpow::{HashFunction, SVec};
 use moka::sync::{Cache, CacheBuilder};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use smol::channel::{Receiver, Sender};
 use stdcode::StdcodeSerializeExt;
 
 use crate::config::AutoSettle;
 
-use crate::context::DaemonContext;
+use crate::context::{CtxField, DaemonContext, DEBTS, MY_RELAY_IDENTITY};
+use crate::db::db_read;
+
+pub static SETTLEMENTS: CtxField<Settlements> = |ctx| {
+    let history = smol::future::block_on(db_read(ctx, "settlement_history"))
+        .ok()
+        .flatten()
+        .and_then(|bts| stdcode::deserialize(&bts).ok())
+        .unwrap_or_default();
+    Settlements::new(ctx.init().auto_settle, history)
+};
+
+/// How many outstanding seeds each neighbor may have. Asking for more forgets the oldest ones.
+const MAX_SEEDS_PER_NEIGHBOR: usize = 16;
 
 pub struct Hasher;
 
@@ -36,9 +52,13 @@ pub fn onchain_multiplier() -> u64 {
     1
 }
 
-pub fn difficulty_to_micromel(difficulty: usize) -> u64 {
-    let work = (2u64).pow(difficulty as u32);
-    work.saturating_mul(onchain_multiplier())
+/// The largest MelPoW difficulty used in a single automatic settlement, both when paying and when being paid. Larger debts are paid off over several rounds.
+pub const MAX_AUTO_SETTLE_DIFFICULTY: usize = 16;
+
+/// What a MelPoW proof of the given difficulty is worth, or None if that doesn't fit in a u64.
+pub fn difficulty_to_micromel(difficulty: usize) -> Option<u64> {
+    let work = 1u64.checked_shl(u32::try_from(difficulty).ok()?)?;
+    work.checked_mul(onchain_multiplier())
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -47,7 +67,8 @@ pub struct SettlementRequest {
     decrease: u64,
     pub payment_proof: SettlementProof,
     signature: Bytes,
-    initiator_pk: Arc<RelayIdentityPublic>,
+    /// None if the initiator is a client. Clients have no long-term identity, so their requests are unsigned and only authenticated by the link they arrive on.
+    initiator_pk: Option<Arc<RelayIdentityPublic>>,
 }
 
 impl fmt::Display for SettlementRequest {
@@ -57,13 +78,20 @@ impl fmt::Display for SettlementRequest {
             self.timestamp_ms,
             self.decrease,
             self.payment_proof,
-            self.initiator_pk.fingerprint()
+            self.initiator_pk
+                .as_ref()
+                .map(|pk| pk.fingerprint().to_string())
+                .unwrap_or_else(|| "[client]".to_string())
         )
     }
 }
 
 impl SettlementRequest {
-    pub fn new(my_sk: RelayIdentitySecret, decrease: u64, payment_proof: SettlementProof) -> Self {
+    pub fn new(
+        my_sk: Option<RelayIdentitySecret>,
+        decrease: u64,
+        payment_proof: SettlementProof,
+    ) -> Self {
         let mut request = Self {
             timestamp_ms: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
@@ -72,11 +100,11 @@ impl SettlementRequest {
             decrease,
             payment_proof,
             signature: Bytes::new(),
-            initiator_pk: my_sk.public().into(),
+            initiator_pk: my_sk.map(|sk| sk.public().into()),
         };
-        let signature = my_sk.sign(request.to_sign().as_bytes());
-
-        request.signature = signature.clone();
+        if let Some(my_sk) = my_sk {
+            request.signature = my_sk.sign(request.to_sign().as_bytes());
+        }
 
         request
     }
@@ -87,6 +115,31 @@ impl SettlementRequest {
 
         blake3::keyed_hash(b"settlement-request--------------", &this.stdcode())
     }
+
+    /// The amount of debt, in micromel, that this request settles.
+    pub fn decrease(&self) -> u64 {
+        self.decrease
+    }
+
+    /// Checks that this request really comes from the given neighbor.
+    pub fn verify(&self, neigh: Either<ClientId, RelayFingerprint>) -> anyhow::Result<()> {
+        match (neigh, self.initiator_pk.as_ref()) {
+            (Either::Right(fp), Some(pk)) => {
+                if pk.fingerprint() != fp {
+                    anyhow::bail!("settlement request not initiated by {fp}");
+                }
+                pk.verify(self.to_sign().as_bytes(), &self.signature)?;
+            }
+            (Either::Right(fp), None) => {
+                anyhow::bail!("unsigned settlement request from relay {fp}")
+            }
+            (Either::Left(_), Some(pk)) => {
+                pk.verify(self.to_sign().as_bytes(), &self.signature)?;
+            }
+            (Either::Left(_), None) => {}
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -123,19 +176,32 @@ pub struct SettlementResponse {
 }
 
 impl SettlementResponse {
-    pub fn new(my_sk: RelayIdentitySecret, request: SettlementRequest, current_debt: i128) -> Self {
+    /// Creates a response to a settlement request. Clients have no identity to sign with, so their responses are unsigned.
+    pub fn new(
+        my_sk: Option<RelayIdentitySecret>,
+        request: SettlementRequest,
+        current_debt: i128,
+    ) -> Self {
         let mut response = Self {
             request,
             current_debt,
             signature: Bytes::new(),
         };
-        let signature = my_sk.sign(response.to_sign().as_bytes());
-
-        response.signature = signature;
+        if let Some(my_sk) = my_sk {
+            response.signature = my_sk.sign(response.to_sign().as_bytes());
+        }
 
         response
     }
 
+    /// Checks the signature of the neighbor that responded, if that neighbor is a relay.
+    pub fn verify(&self, their_pk: Option<&RelayIdentityPublic>) -> anyhow::Result<()> {
+        if let Some(their_pk) = their_pk {
+            their_pk.verify(self.to_sign().as_bytes(), &self.signature)?;
+        }
+        Ok(())
+    }
+
     pub fn to_sign(&self) -> Hash {
         let mut this = self.clone();
         this.signature = Bytes::new();
@@ -144,16 +210,35 @@ impl SettlementResponse {
     }
 }
 
+/// A completed settlement, in either direction, along with the neighbor's signed response.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SettlementRecord {
+    pub neighbor: Either<ClientId, RelayFingerprint>,
+    /// true if the neighbor paid us, false if we paid the neighbor
+    pub is_incoming: bool,
+    pub time: SystemTime,
+    pub response: SettlementResponse,
+}
+
+type OutgoingSettlement = (SettlementRequest, Sender<Option<SettlementResponse>>);
+
 pub struct Settlements {
-    pending: DashMap<RelayFingerprint, PendingSettlement>,
-    pub seed_cache: Cache<u64, HashSet<Seed>>,
+    pending: DashMap<Either<ClientId, RelayFingerprint>, PendingSettlement>,
+    outgoing: DashMap<
+        Either<ClientId, RelayFingerprint>,
+        (Sender<OutgoingSettlement>, Receiver<OutgoingSettlement>),
+    >,
+    history: Mutex<Vec<SettlementRecord>>,
+    pub seed_cache: Cache<Either<ClientId, RelayFingerprint>, VecDeque<Seed>>,
     pub auto_settle: Option<AutoSettle>,
 }
 
 impl Settlements {
-    pub fn new(auto_settle: Option<AutoSettle>) -> Self {
+    pub fn new(auto_settle: Option<AutoSettle>, history: Vec<SettlementRecord>) -> Self {
         Settlements {
             pending: DashMap::new(),
+            outgoing: DashMap::new(),
+            history: Mutex::new(history),
             seed_cache: CacheBuilder::default()
                 .time_to_live(Duration::from_secs(60))
                 .build(),
@@ -162,6 +247,30 @@ impl Settlements {
     }
 }
 
+/// Waits for us to accept or reject a pending manual settlement, which is forgotten once this is dropped.
+pub struct PendingResponse<'a> {
+    settlements: &'a Settlements,
+    neigh: Either<ClientId, RelayFingerprint>,
+    request: SettlementRequest,
+    recv_res: Receiver<Option<SettlementResponse>>,
+}
+
+impl PendingResponse<'_> {
+    /// Waits for the response, which is None if we rejected the settlement.
+    pub async fn recv(&self) -> anyhow::Result<Option<SettlementResponse>> {
+        Ok(self.recv_res.recv().await?)
+    }
+}
+
+impl Drop for PendingResponse<'_> {
+    fn drop(&mut self) {
+        // a newer request from the same neighbor may have replaced ours, and stays pending
+        self.settlements
+            .pending
+            .remove_if(&self.neigh, |_, pending| pending.request == self.request);
+    }
+}
+
 #[derive(Debug)]
 struct PendingSettlement {
     request: SettlementRequest,
@@ -169,13 +278,13 @@ struct PendingSettlement {
 }
 
 impl Settlements {
-    // handles manual settlements
+    /// Handles a manual settlement request, which waits for us to accept or reject it. The request stays pending until the returned handle is dropped.
     pub fn insert_pending(
         &self,
+        neigh: Either<ClientId, RelayFingerprint>,
         request: SettlementRequest,
-    ) -> anyhow::Result<Receiver<Option<SettlementResponse>>> {
-        let initiator_pk = request.clone().initiator_pk;
-        initiator_pk.verify(request.to_sign().as_bytes(), &request.signature)?;
+    ) -> anyhow::Result<PendingResponse<'_>> {
+        request.verify(neigh)?;
 
         match request.payment_proof {
             SettlementProof::Manual => (),
@@ -188,100 +297,329 @@ impl Settlements {
             send_res,
         };
 
-        self.pending
-            .insert(initiator_pk.fingerprint(), pending_settlement);
+        self.pending.insert(neigh, pending_settlement);
 
-        Ok(recv_res)
+        Ok(PendingResponse {
+            settlements: self,
+            neigh,
+            request,
+            recv_res,
+        })
+    }
+
+    /// Generates a fresh MelPoW seed for the given neighbor to build an automatic settlement proof with. Returns None if we don't accept automatic settlements.
+    pub fn new_seed(&self, neigh: Either<ClientId, RelayFingerprint>) -> Option<Seed> {
+        self.auto_settle?;
+        let seed: Seed = rand::random();
+        let mut seeds = self.seed_cache.get(&neigh).unwrap_or_default();
+        if seeds.len() >= MAX_SEEDS_PER_NEIGHBOR {
+            seeds.pop_front();
+        }
+        seeds.push_back(seed);
+        self.seed_cache.insert(neigh, seeds);
+        Some(seed)
     }
 
     // handles automatic settlements
     pub fn verify_auto_settle(
         &self,
-        _ctx: &DaemonContext,
-        _request: SettlementRequest,
+        ctx: &DaemonContext,
+        neigh: Either<ClientId, RelayFingerprint>,
+        request: SettlementRequest,
     ) -> anyhow::Result<Option<SettlementResponse>> {
-        todo!();
-        // let initiator_pk = request.clone().initiator_pk;
-        // initiator_pk.verify(request.to_sign().as_bytes(), &request.signature)?;
-
-        // match &request.payment_proof {
-        //     SettlementProof::Automatic(AutoSettleProof {
-        //         seed,
-        //         difficulty,
-        //         proof,
-        //     }) => {
-        //         let proof =
-        //             melpow::Proof::from_bytes(proof).context("unable to deserialize mel proof")?;
-        //         if let Some(mut seeds) = self.seed_cache.get(&initiator_pk.fingerprint()) {
-        //             if seeds.contains(seed) && proof.verify(seed, *difficulty, Hasher) {
-        //                 let debts = ctx.get(DEBTS);
-        //                 let amount = difficulty_to_micromel(*difficulty);
-
-        //                 debts.deduct_settlement(initiator_pk.fingerprint(), amount);
-        //                 seeds.remove(seed);
-
-        //                 if let Some(current_debt) = debts.net_debt_est(&initiator_pk.fingerprint())
-        //                 {
-        //                     tracing::debug!(
-        //                         "processed auto_settle debt with {}",
-        //                         &initiator_pk.fingerprint()
-        //                     );
-        //                     return Ok(Some(SettlementResponse::new(
-        //                         *ctx.get(GLOBAL_IDENTITY),
-        //                         request,
-        //                         current_debt,
-        //                     )));
-        //                 }
-        //             }
-        //         }
-        //     }
-        //     _ => return Err(anyhow::anyhow!("expected automatic settlement proof")),
-        // };
-        // Ok(None)
+        if self.auto_settle.is_none() {
+            anyhow::bail!("we do not accept automatic settlements");
+        }
+        request.verify(neigh)?;
+
+        match &request.payment_proof {
+            SettlementProof::Automatic(AutoSettleProof {
+                seed,
+                difficulty,
+                proof,
+            }) => {
+                // the difficulty comes from the neighbor, so bound it before trusting it with anything
+                anyhow::ensure!(
+                    *difficulty <= MAX_AUTO_SETTLE_DIFFICULTY,
+                    "settlement proof difficulty {difficulty} is above the maximum of {MAX_AUTO_SETTLE_DIFFICULTY}"
+                );
+                let amount = difficulty_to_micromel(*difficulty)
+                    .context("settlement proof difficulty is out of range")?;
+                if request.decrease != amount {
+                    anyhow::bail!(
+                        "settlement request claims {} micromel, but its proof is worth {amount}",
+                        request.decrease
+                    );
+                }
+                let proof =
+                    melpow::Proof::from_bytes(proof).context("unable to deserialize mel proof")?;
+                if let Some(mut seeds) = self.seed_cache.get(&neigh) {
+                    // seeds are single-use, so a proof can never be replayed
+                    if let Some(idx) = seeds.iter().position(|s| s == seed) {
+                        seeds.remove(idx);
+                        self.seed_cache.insert(neigh, seeds);
+                        if proof.verify(seed, *difficulty, Hasher) {
+                            let current_debt = deduct_settlement(ctx, neigh, amount);
+                            tracing::debug!(
+                                neigh = debug(neigh),
+                                amount,
+                                current_debt,
+                                "processed auto_settle debt"
+                            );
+                            let response = SettlementResponse::new(
+                                *ctx.get(MY_RELAY_IDENTITY),
+                                request,
+                                current_debt,
+                            );
+                            self.record(neigh, true, response.clone());
+                            return Ok(Some(response));
+                        }
+                    }
+                }
+            }
+            _ => return Err(anyhow::anyhow!("expected automatic settlement proof")),
+        };
+        Ok(None)
     }
 
+    /// Accepts the neighbor's pending settlement request, returning false if there is none. The request is taken out of the pending ones in one go, so that a newer request arriving meanwhile is never answered with this one's amount.
     pub async fn accept_response(
         &self,
-        _ctx: &DaemonContext,
-        _neighbor: RelayFingerprint,
-        _request: SettlementRequest,
-    ) -> anyhow::Result<()> {
-        todo!();
-        // let debts = ctx.get(DEBTS);
-        // let current_debt = debts
-        //     .net_debt_est(&neighbor)
-        //     .context("unable to retrieve net debt")?;
-        // let deduct_amount = request.decrease;
-        // let settled_debt = current_debt.saturating_sub(deduct_amount as i128);
-        // let my_sk = ctx.get(GLOBAL_IDENTITY);
-        // let response = SettlementResponse::new(*my_sk, request, settled_debt);
-
-        // if let Some(settlement) = self.pending.get(&neighbor) {
-        //     settlement.send_res.send(Some(response)).await?;
-        //     debts.deduct_settlement(neighbor, deduct_amount);
-        // }
-
-        // self.pending.remove(&neighbor);
-        // Ok(())
-    }
-
-    pub async fn reject_response(&self, neighbor: &RelayFingerprint) -> anyhow::Result<()> {
-        if let Some(settlement) = self.pending.get(neighbor) {
-            settlement.send_res.send(None).await?
+        ctx: &DaemonContext,
+        neighbor: Either<ClientId, RelayFingerprint>,
+    ) -> anyhow::Result<bool> {
+        let Some((_, settlement)) = self.pending.remove(&neighbor) else {
+            return Ok(false);
+        };
+        let request = settlement.request;
+        let amount = request.decrease;
+        let debts = ctx.get(DEBTS);
+        let current_debt = match neighbor {
+            Either::Left(id) => debts.client_net_debt_after_settlement(&id, amount),
+            Either::Right(fp) => debts.relay_net_debt_after_settlement(&fp, amount),
         }
-
-        self.pending.remove(neighbor);
-        Ok(())
+        .unwrap_or_default();
+        let response = SettlementResponse::new(*ctx.get(MY_RELAY_IDENTITY), request, current_debt);
+        // only forgive the debt once the neighbor is sure to hear about it
+        settlement
+            .send_res
+            .try_send(Some(response.clone()))
+            .ok()
+            .context("the settlement request is no longer waiting for a response")?;
+        deduct_settlement(ctx, neighbor, amount);
+        self.record(neighbor, true, response);
+        Ok(true)
     }
 
-    pub fn get_request(&self, neighbor: &RelayFingerprint) -> Option<SettlementRequest> {
-        self.pending.get(neighbor).map(|e| e.request.clone())
+    /// Rejects the neighbor's pending settlement request, returning false if there is none.
+    pub async fn reject_response(
+        &self,
+        neighbor: &Either<ClientId, RelayFingerprint>,
+    ) -> anyhow::Result<bool> {
+        let Some((_, settlement)) = self.pending.remove(neighbor) else {
+            return Ok(false);
+        };
+        settlement.send_res.send(None).await?;
+        Ok(true)
     }
 
-    pub fn list(&self) -> Vec<String> {
+    pub fn list(&self) -> Vec<(Either<ClientId, RelayFingerprint>, SettlementRequest)> {
         self.pending
             .iter()
-            .map(|entry| entry.request.to_string())
+            .map(|entry| (*entry.key(), entry.request.clone()))
             .collect()
     }
+
+    /// Sends a manual settlement request to the neighbor over any link to it, and waits for the neighbor's response. None means the neighbor rejected it.
+    pub async fn send_outgoing(
+        &self,
+        neigh: Either<ClientId, RelayFingerprint>,
+        request: SettlementRequest,
+    ) -> anyhow::Result<Option<SettlementResponse>> {
+        let (send_res, recv_res) = smol::channel::bounded(1);
+        let send_outgoing = self.outgoing_channel(neigh).0;
+        send_outgoing.send((request, send_res)).await?;
+        recv_res
+            .recv()
+            .await
+            .context("the settlement request could not be delivered to the neighbor")
+    }
+
+    /// Subscribes to the manual settlement requests that should be sent to the given neighbor.
+    pub fn subscribe_outgoing(
+        &self,
+        neigh: Either<ClientId, RelayFingerprint>,
+    ) -> Receiver<OutgoingSettlement> {
+        self.outgoing_channel(neigh).1
+    }
+
+    fn outgoing_channel(
+        &self,
+        neigh: Either<ClientId, RelayFingerprint>,
+    ) -> (Sender<OutgoingSettlement>, Receiver<OutgoingSettlement>) {
+        self.outgoing
+            .entry(neigh)
+            .or_insert_with(smol::channel::unbounded)
+            .clone()
+    }
+
+    /// Adds a completed settlement to the persistent history.
+    pub fn record(
+        &self,
+        neighbor: Either<ClientId, RelayFingerprint>,
+        is_incoming: bool,
+        response: SettlementResponse,
+    ) {
+        self.history.lock().push(SettlementRecord {
+            neighbor,
+            is_incoming,
+            time: SystemTime::now(),
+            response,
+        });
+    }
+
+    pub fn history(&self) -> Vec<SettlementRecord> {
+        self.history.lock().clone()
+    }
+}
+
+/// Deducts a settled amount from what we owe the neighbor.
+pub fn deduct_payment(ctx: &DaemonContext, neigh: Either<ClientId, RelayFingerprint>, amount: u64) {
+    let debts = ctx.get(DEBTS);
+    match neigh {
+        Either::Left(id) => debts.deduct_client_payment(id, amount),
+        Either::Right(fp) => debts.deduct_relay_payment(fp, amount),
+    }
+}
+
+/// Deducts a settled amount from what the neighbor owes us, returning our new estimate of their net debt.
+fn deduct_settlement(
+    ctx: &DaemonContext,
+    neigh: Either<ClientId, RelayFingerprint>,
+    amount: u64,
+) -> i128 {
+    let debts = ctx.get(DEBTS);
+    match neigh {
+        Either::Left(id) => {
+            debts.deduct_client_settlement(id, amount);
+            debts.client_net_debt_est(&id).unwrap_or_default()
+        }
+        Either::Right(fp) => {
+            debts.deduct_relay_settlement(fp, amount);
+            debts.relay_net_debt_est(&fp).unwrap_or_default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_settle_proof_verifies() {
+        let seed: Seed = rand::random();
+        let SettlementProof::Automatic(auto) = SettlementProof::new_auto(seed, 4) else {
+            panic!("expected an automatic proof")
+        };
+        let proof = melpow::Proof::from_bytes(&auto.proof).unwrap();
+        assert!(proof.verify(&seed, 4, Hasher));
+        assert!(!proof.verify(&rand::random::<Seed>(), 4, Hasher));
+    }
+
+    #[test]
+    fn difficulty_amounts_never_overflow() {
+        assert_eq!(difficulty_to_micromel(0), Some(1));
+        assert_eq!(
+            difficulty_to_micromel(MAX_AUTO_SETTLE_DIFFICULTY),
+            Some(1 << MAX_AUTO_SETTLE_DIFFICULTY)
+        );
+        assert_eq!(difficulty_to_micromel(63), Some(1 << 63));
+        assert_eq!(difficulty_to_micromel(64), None);
+        assert_eq!(difficulty_to_micromel(usize::MAX), None);
+    }
+
+    #[test]
+    fn seeds_per_neighbor_are_capped() {
+        let settlements = Settlements::new(Some(AutoSettle { interval: 1 }), vec![]);
+        let neigh = Either::Left(1);
+        let first = settlements.new_seed(neigh).unwrap();
+        for _ in 0..MAX_SEEDS_PER_NEIGHBOR * 4 {
+            settlements.new_seed(neigh);
+        }
+        let seeds = settlements.seed_cache.get(&neigh).unwrap();
+        assert_eq!(seeds.len(), MAX_SEEDS_PER_NEIGHBOR);
+        assert!(!seeds.contains(&first));
+    }
+
+    #[test]
+    fn dropped_pending_settlements_are_forgotten() {
+        let settlements = Settlements::new(None, vec![]);
+        let neigh = Either::Left(1);
+        let pending = settlements
+            .insert_pending(
+                neigh,
+                SettlementRequest::new(None, 10, SettlementProof::Manual),
+            )
+            .unwrap();
+        assert_eq!(settlements.list().len(), 1);
+
+        // a newer request replaces the old one, which must not take the newer one with it
+        let newer = settlements
+            .insert_pending(
+                neigh,
+                SettlementRequest::new(None, 20, SettlementProof::Manual),
+            )
+            .unwrap();
+        drop(pending);
+        assert_eq!(settlements.list()[0].1.decrease(), 20);
+        drop(newer);
+        assert!(settlements.list().is_empty());
+    }
+
+    #[test]
+    fn accepting_answers_the_request_it_removes() {
+        let ctx = DaemonContext::new(serde_yaml::from_str("out_routes: {}").unwrap());
+        let settlements = Settlements::new(None, vec![]);
+        let neigh = Either::Left(1);
+        let older = settlements
+            .insert_pending(
+                neigh,
+                SettlementRequest::new(None, 10, SettlementProof::Manual),
+            )
+            .unwrap();
+        let newer = settlements
+            .insert_pending(
+                neigh,
+                SettlementRequest::new(None, 20, SettlementProof::Manual),
+            )
+            .unwrap();
+
+        assert!(smol::future::block_on(settlements.accept_response(&ctx, neigh)).unwrap());
+        let response = smol::future::block_on(newer.recv()).unwrap().unwrap();
+        assert_eq!(response.request.decrease(), 20);
+        assert!(older.recv_res.is_empty());
+        assert_eq!(settlements.history()[0].response.request.decrease(), 20);
+        // nothing is left to accept
+        assert!(!smol::future::block_on(settlements.accept_response(&ctx, neigh)).unwrap());
+    }
+
+    #[test]
+    fn request_verifies_initiator() {
+        let sk = RelayIdentitySecret::generate();
+        let request = SettlementRequest::new(Some(sk), 10, SettlementProof::Manual);
+        assert!(request
+            .verify(Either::Right(sk.public().fingerprint()))
+            .is_ok());
+        assert!(request
+            .verify(Either::Right(
+                RelayIdentitySecret::generate().public().fingerprint()
+            ))
+            .is_err());
+
+        let client_request = SettlementRequest::new(None, 10, SettlementProof::Manual);
+        assert!(client_request.verify(Either::Left(1)).is_ok());
+        assert!(client_request
+            .verify(Either::Right(sk.public().fingerprint()))
+            .is_err());
+    }
 }
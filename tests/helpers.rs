@@ -9,7 +9,11 @@ use std::{
 };
 
 use earendil::{
-    Daemon, {ConfigFile, Identity, InRouteConfig, LinkPrice, ObfsConfig, OutRouteConfig},
+    Daemon,
+    {
+        ConfigFile, ControlAuthConfig, Identity, InRouteConfig, LinkPrice, ObfsConfig,
+        OutRouteConfig, RoutePolicy,
+    },
 };
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use smol::Timer;
@@ -69,12 +73,29 @@ pub fn new_cfg(
         identity,
         state_cache,
         control_listen,
+        control_unix: None,
+        // many daemons share one cookie path, so they can't all use cookie authentication
+        control_auth: ControlAuthConfig {
+            unauthenticated: true,
+            ..Default::default()
+        },
         in_routes,
         out_routes,
         udp_forwards,
         tcp_forwards,
         socks5,
         havens,
+        onion_key_rotation: 86400,
+        // the test networks can be too small to have any relay between the two ends of a route
+        route_policy: RoutePolicy {
+            min_hops: 0,
+            ..Default::default()
+        },
+        max_mix_delay_ms: 5000,
+        relay_graph: Default::default(),
+        gossip: Default::default(),
+        bootstrap_snapshots: vec![],
+        cover_traffic: None,
         auto_settle: None,
     }
 }
@@ -110,7 +131,7 @@ fn routes(
     num_outroutes_range: RangeInclusive<u8>,
 ) -> anyhow::Result<(InRoutes, OutRoutes)> {
     let _secret = "secret".to_string();
-    let _link_price = LinkPrice {
+    let link_price = LinkPrice {
         max_outgoing_price: 1,
         incoming_price: 0,
         incoming_debt_limit: 100,
@@ -123,6 +144,7 @@ fn routes(
             InRouteConfig {
                 listen: format!("0.0.0.0:{}", free_port(rng)).parse()?,
                 obfs: ObfsConfig::None,
+                link_price,
             },
         ))
     }
@@ -154,7 +176,9 @@ fn routes(
             }
         }
         let (connect, obfs) = match relay_cfg.in_routes.get("obfsudp").unwrap() {
-            InRouteConfig { mut listen, obfs } => {
+            InRouteConfig {
+                mut listen, obfs, ..
+            } => {
                 listen.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
                 (listen, obfs)
             }
@@ -176,6 +200,7 @@ fn routes(
                     .fingerprint(),
                 connect: connect.to_string(),
                 obfs: obfs.clone(),
+                link_price,
             },
         ));
     }
@@ -1,10 +1,14 @@
 use std::{
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
 
 use anyctx::AnyCtx;
 use chrono::{DateTime, Local};
+use earendil::control_protocol::DaemonEvent;
 use earendil_crypt::{ClientId, RelayFingerprint};
 use egui::{mutex::Mutex, Color32};
 use either::Either;
@@ -12,7 +16,10 @@ use smol::block_on;
 
 use crate::app::refresh_cell::RefreshCell;
 
-use super::{config::Prefs, App};
+use super::{config::Prefs, daemon_wrap::DaemonWrap, App};
+
+/// The generation of chat events and the neighbor being chatted with.
+type ChatVersion = (u64, Option<Either<ClientId, RelayFingerprint>>);
 
 pub fn render_chat(app: &App, ctx: &egui::Context, ui: &mut egui::Ui) {
     let mut daemon_cfg = app.daemon_cfg.lock();
@@ -30,6 +37,13 @@ pub fn render_chat(app: &App, ctx: &egui::Context, ui: &mut egui::Ui) {
     ui.separator();
 
     if let Some(Ok(daemon)) = app.daemon.as_ref().and_then(|d| d.ready()) {
+        static CHAT_WATCH: fn(&AnyCtx<()>) -> Mutex<Option<Arc<AtomicU64>>> = |_| Mutex::new(None);
+        let generation = app
+            .state
+            .get(CHAT_WATCH)
+            .lock()
+            .get_or_insert_with(|| watch_chats(daemon.clone(), ctx.clone()))
+            .clone();
         let control = Arc::new(async_std::sync::Mutex::new(daemon.control()));
         let control_clone = control.clone();
 
@@ -71,9 +85,18 @@ pub fn render_chat(app: &App, ctx: &egui::Context, ui: &mut egui::Ui) {
             )
                 -> Mutex<RefreshCell<anyhow::Result<Vec<(bool, String, SystemTime)>>>> =
                 |_| Mutex::new(RefreshCell::new());
+            // what the chat was last fetched for, so that it's only refetched when it changes
+            static CHAT_FETCHED: fn(&AnyCtx<()>) -> Mutex<Option<ChatVersion>> =
+                |_| Mutex::new(None);
             let mut chat = app.state.get(CHAT).lock();
             let chatting_with = daemon_cfg.gui_prefs.chatting_with;
-            let chat = chat.get_or_refresh(Duration::from_millis(100), move || {
+            let fetching = Some((generation.load(Ordering::Relaxed), chatting_with));
+            let mut fetched = app.state.get(CHAT_FETCHED).lock();
+            if *fetched != fetching {
+                *fetched = fetching;
+                chat.invalidate();
+            }
+            let chat = chat.get_or_refresh(Duration::from_secs(30), move || {
                 if let Some(neigh) = chatting_with {
                     Ok(block_on(async move {
                         let chat = control_clone
@@ -113,6 +136,7 @@ pub fn render_chat(app: &App, ctx: &egui::Context, ui: &mut egui::Ui) {
                                                 &mut cols[1],
                                                 &mut daemon_cfg.gui_prefs,
                                                 neigh,
+                                                generation.clone(),
                                             );
                                         }
                                     }
@@ -131,6 +155,38 @@ pub fn render_chat(app: &App, ctx: &egui::Context, ui: &mut egui::Ui) {
     }
 }
 
+/// Subscribes to the daemon's events in the background. The returned counter goes up whenever a chat message arrives.
+fn watch_chats(daemon: DaemonWrap, ctx: egui::Context) -> Arc<AtomicU64> {
+    let generation = Arc::new(AtomicU64::new(0));
+    let generation_clone = generation.clone();
+    std::thread::spawn(move || {
+        block_on(async move {
+            let control = daemon.control();
+            let mut from = None;
+            loop {
+                match control.next_events(from, 20).await {
+                    Ok(batch) => {
+                        from = Some(batch.next);
+                        if batch.missed > 0
+                            || batch.events.iter().any(|record| {
+                                matches!(record.event, DaemonEvent::ChatReceived { .. })
+                            })
+                        {
+                            generation_clone.fetch_add(1, Ordering::Relaxed);
+                            ctx.request_repaint();
+                        }
+                    }
+                    Err(err) => {
+                        tracing::warn!("waiting for events failed: {:?}", err);
+                        smol::Timer::after(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        })
+    });
+    generation
+}
+
 fn render_convo(
     ui: &mut egui::Ui,
     tuple_chat: Vec<(bool, String, SystemTime)>,
@@ -180,6 +236,7 @@ fn render_input(
     ui: &mut egui::Ui,
     prefs: &mut Prefs,
     dest: either::Either<ClientId, RelayFingerprint>,
+    generation: Arc<AtomicU64>,
 ) {
     ui.horizontal(|ui| {
         let response = ui.add(
@@ -192,8 +249,16 @@ fn render_input(
             if let Some(Ok(daemon)) = app.daemon.as_ref().and_then(|d| d.ready()) {
                 let msg = prefs.chat_msg.clone();
                 let daemon = daemon.clone();
+                let ctx = ctx.clone();
                 std::thread::spawn(move || {
-                    block_on(async move { daemon.control().send_chat(dest.to_string(), msg).await })
+                    let res =
+                        block_on(
+                            async move { daemon.control().send_chat(dest.to_string(), msg).await },
+                        );
+                    // show our own message, which doesn't produce an event
+                    generation.fetch_add(1, Ordering::Relaxed);
+                    ctx.request_repaint();
+                    res
                 });
                 prefs.chat_msg.clear();
                 response.request_focus();
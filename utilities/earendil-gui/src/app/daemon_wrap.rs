@@ -1,6 +1,10 @@
 use std::{net::SocketAddr, sync::Arc};
 
-use earendil::{control_protocol::ControlClient, daemon::Daemon};
+use earendil::{
+    control_auth::{read_cookie, AuthRpcTransport},
+    control_protocol::ControlClient,
+    daemon::Daemon,
+};
 use earendil_crypt::RelayFingerprint;
 use either::Either;
 
@@ -15,7 +19,12 @@ impl DaemonWrap {
     pub fn control(&self) -> ControlClient {
         match self {
             DaemonWrap::Remote(rem) => {
-                ControlClient::from(nanorpc_http::client::HttpRpcTransport::new(*rem))
+                // remote daemons that require a token write it to the default cookie file
+                let token = read_cookie(None).ok().flatten();
+                ControlClient::from(AuthRpcTransport::new(
+                    nanorpc_http::client::HttpRpcTransport::new(*rem),
+                    token,
+                ))
             }
             DaemonWrap::Embedded(emb) => emb.control_client(),
         }
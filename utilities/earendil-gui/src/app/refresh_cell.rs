@@ -4,6 +4,7 @@ use poll_promise::Promise;
 
 pub struct RefreshCell<T: Send + Sync + 'static> {
     last_updated: Instant,
+    invalidated: bool,
     stable: Option<T>,
     next: Option<Promise<T>>,
 }
@@ -13,19 +14,27 @@ impl<T: Send + Sync + 'static> RefreshCell<T> {
     pub fn new() -> Self {
         Self {
             last_updated: Instant::now(),
+            invalidated: false,
             stable: None,
             next: None,
         }
     }
 
+    /// Makes the next call refresh the value, however recently it was refreshed.
+    pub fn invalidate(&mut self) {
+        self.invalidated = true;
+    }
+
     /// Gets a value from the RefreshCell. If there's no value, starts refreshing it, but returns the stale value in the meantime.
     pub fn get_or_refresh(
         &mut self,
         timeout: Duration,
         refresh: impl FnOnce() -> T + Send + 'static,
     ) -> Option<&T> {
-        let must_refresh = self.next.is_none() || self.last_updated.elapsed() > timeout;
+        let must_refresh =
+            self.invalidated || self.next.is_none() || self.last_updated.elapsed() > timeout;
         if must_refresh {
+            self.invalidated = false;
             if let Some(Ok(res)) = self.next.take().map(|taken| taken.try_take()) {
                 self.stable = Some(res);
             }